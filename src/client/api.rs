@@ -0,0 +1,654 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use k8s_openapi::{
+   ClusterResourceScope, List, ListableResource, NamespaceResourceScope, Resource,
+   api::autoscaling::v1::Scale,
+};
+use futures::Stream;
+use reqwest::{Method, RequestBuilder, Response};
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{APIError, Base, FieldSelector, KubeErrorStatus, LabelSelector, RetryPolicy, response_into_error};
+
+/// Page size used by `list_all` and `list_pages` when `ListParams::limit` is unset, as kubectl does.
+const DEFAULT_PAGE_SIZE: u32 = 500;
+
+/// How many times a chunked list restarts from the first page after its continue token expired.
+const MAX_LIST_RESTARTS: u32 = 3;
+
+/// Query parameters shared by list and watch requests.
+#[derive(Debug, Clone, Default)]
+pub struct ListParams
+{
+   pub label_selector: Option<LabelSelector>,
+   pub field_selector: Option<FieldSelector>,
+   pub limit: Option<u32>,
+   pub continue_token: Option<Box<str>>,
+}
+
+impl ListParams
+{
+   pub fn labels(mut self, selector: LabelSelector) -> Self
+   {
+      self.label_selector = Some(selector);
+      self
+   }
+
+   pub fn fields(mut self, selector: FieldSelector) -> Self
+   {
+      self.field_selector = Some(selector);
+      self
+   }
+
+   pub fn limit(mut self, limit: u32) -> Self
+   {
+      self.limit = Some(limit);
+      self
+   }
+
+   pub fn continue_token(mut self, token: &str) -> Self
+   {
+      self.continue_token = Some(token.into());
+      self
+   }
+
+   fn paged(&self) -> Self
+   {
+      Self {
+         limit: Some(self.limit.unwrap_or(DEFAULT_PAGE_SIZE)),
+         continue_token: None,
+         ..self.clone()
+      }
+   }
+
+   fn query(&self) -> Vec<(&'static str, String)>
+   {
+      let mut query = vec![];
+
+      if let Some(limit) = self.limit {
+         query.push(("limit", limit.to_string()));
+      };
+
+      if let Some(token) = &self.continue_token {
+         query.push(("continue", token.to_string()));
+      };
+
+      if let Some(selector) = self.label_selector.as_ref().filter(|x| !x.is_empty()) {
+         query.push(("labelSelector", selector.to_string()));
+      };
+
+      if let Some(selector) = self.field_selector.as_ref().filter(|x| !x.is_empty()) {
+         query.push(("fieldSelector", selector.to_string()));
+      };
+
+      query
+   }
+}
+
+/// Body of a PATCH request, tagged with the patch strategy.
+#[derive(Debug, Clone)]
+pub enum Patch<T: Serialize>
+{
+   /// RFC 7386 JSON merge patch.
+   Merge(T),
+   /// Kubernetes strategic merge patch, only supported by built-in types.
+   Strategic(T),
+   /// RFC 6902 JSON patch, `T` being the list of operations.
+   Json(T),
+}
+
+impl<T: Serialize> Patch<T>
+{
+   fn content_type(&self) -> &'static str
+   {
+      match self {
+         Self::Merge(_) => "application/merge-patch+json",
+         Self::Strategic(_) => "application/strategic-merge-patch+json",
+         Self::Json(_) => "application/json-patch+json",
+      }
+   }
+
+   fn body(&self) -> Result<Vec<u8>, APIError>
+   {
+      let (Self::Merge(body) | Self::Strategic(body) | Self::Json(body)) = self;
+      Ok(serde_json::to_vec(body)?)
+   }
+}
+
+/// What a DELETE returned: the object while its finalizers run, or a `Status` once it is gone.
+#[derive(Debug)]
+pub enum Deleted<K>
+{
+   Object(K),
+   Status(Box<KubeErrorStatus>),
+}
+
+/// Typed access to one kind of resource, either in a namespace or across the cluster.
+#[derive(Debug)]
+pub struct Api<K>
+{
+   client: Base,
+   namespace: Option<Box<str>>,
+   phantom: PhantomData<K>,
+}
+
+impl<K> Clone for Api<K>
+{
+   fn clone(&self) -> Self
+   {
+      Self {
+         client: self.client.clone(),
+         namespace: self.namespace.clone(),
+         phantom: PhantomData,
+      }
+   }
+}
+
+impl<K> Api<K>
+where
+   K: Resource<Scope = NamespaceResourceScope>,
+{
+   pub fn namespaced(client: Base, namespace: &str) -> Self
+   {
+      Self {
+         client,
+         namespace: Some(namespace.into()),
+         phantom: PhantomData,
+      }
+   }
+
+   /// Uses the namespace of the client's context.
+   pub fn default_namespaced(client: Base) -> Self
+   {
+      let namespace = client.namespace.clone();
+      Self {
+         client,
+         namespace: Some(namespace),
+         phantom: PhantomData,
+      }
+   }
+}
+
+impl<K> Api<K>
+where
+   K: Resource<Scope = ClusterResourceScope>,
+{
+   pub fn cluster(client: Base) -> Self
+   {
+      Self::all(client)
+   }
+}
+
+impl<K> Api<K>
+where
+   K: Resource,
+{
+   /// Cluster-scoped resources, or namespaced ones across all namespaces.
+   pub fn all(client: Base) -> Self
+   {
+      Self {
+         client,
+         namespace: None,
+         phantom: PhantomData,
+      }
+   }
+
+   pub fn base(&self) -> &Base
+   {
+      &self.client
+   }
+
+   /// The same api, sending its requests with `retry` instead of the client's policy.
+   pub fn with_retry_policy(self, retry: RetryPolicy) -> Self
+   {
+      Self {
+         client: self.client.with_retry_policy(retry),
+         ..self
+      }
+   }
+
+   pub fn namespace(&self) -> Option<&str>
+   {
+      self.namespace.as_deref()
+   }
+
+   /// Path of the collection, or of the named object and optionally one of its subresources.
+   pub fn url_path(&self, name: Option<&str>, subresource: Option<&str>) -> String
+   {
+      let mut path = if K::GROUP.is_empty() {
+         format!("/api/{}", K::VERSION)
+      } else {
+         format!("/apis/{}/{}", K::GROUP, K::VERSION)
+      };
+
+      if let Some(namespace) = &self.namespace {
+         path.push_str("/namespaces/");
+         push_segment(&mut path, namespace);
+      };
+
+      path.push('/');
+      path.push_str(K::URL_PATH_SEGMENT);
+
+      for segment in [name, subresource].into_iter().flatten() {
+         path.push('/');
+         push_segment(&mut path, segment);
+      }
+
+      path
+   }
+
+   fn request(&self, method: Method, name: Option<&str>, subresource: Option<&str>) -> RequestBuilder
+   {
+      self.client.request(method, self.url_path(name, subresource))
+   }
+}
+
+/// Appends `segment` percent-encoded, so a `/`, `?` or `%` in a name cannot change the path.
+/// The `:` of proxy targets is allowed in a segment and kept as is.
+fn push_segment(path: &mut String, segment: &str)
+{
+   for byte in segment.bytes() {
+      match byte {
+         b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' | b'@' => path.push(byte as char),
+         _ => path.push_str(&format!("%{byte:02X}")),
+      };
+   }
+}
+
+impl<K> Api<K>
+where
+   K: Resource + DeserializeOwned,
+{
+   async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, APIError>
+   {
+      let response = self.client.send(request).await?;
+      let response = response_into_error(response).await?;
+      Ok(response.json::<T>().await?)
+   }
+
+   async fn send_patch<T, P>(&self, request: RequestBuilder, patch: &Patch<P>) -> Result<T, APIError>
+   where
+      T: DeserializeOwned,
+      P: Serialize,
+   {
+      let request = request
+         .header(reqwest::header::CONTENT_TYPE, patch.content_type())
+         .body(patch.body()?);
+      self.send(request).await
+   }
+
+   pub async fn get(&self, name: &str) -> Result<K, APIError>
+   {
+      self.send(self.request(Method::GET, Some(name), None)).await
+   }
+
+   /// Lists a single page; use `list_all` or `list_pages` to follow `continue` tokens.
+   pub async fn list(&self, params: &ListParams) -> Result<List<K>, APIError>
+   where
+      K: ListableResource,
+   {
+      let request = self.request(Method::GET, None, None).query(&params.query());
+      self.send(request).await
+   }
+
+   /// Lists every object in chunks of `params.limit`, collected into one list.
+   ///
+   /// The list keeps the resourceVersion of its first page, which every following page is
+   /// consistent with. If the continue token expires the list starts over.
+   pub async fn list_all(&self, params: &ListParams) -> Result<List<K>, APIError>
+   where
+      K: ListableResource,
+   {
+      let params = params.paged();
+      let mut restarts = 0;
+
+      'restart: loop {
+         let mut list = self.list(&params).await?;
+
+         while let Some(token) = list.metadata.continue_.take().filter(|token| !token.is_empty()) {
+            let page = match self.list(&params.clone().continue_token(&token)).await {
+               Ok(page) => page,
+               Err(e) if e.is_gone() && restarts < MAX_LIST_RESTARTS => {
+                  restarts += 1;
+                  continue 'restart;
+               },
+               Err(e) => return Err(e),
+            };
+
+            list.items.extend(page.items);
+            list.metadata.continue_ = page.metadata.continue_;
+            list.metadata.remaining_item_count = page.metadata.remaining_item_count;
+         }
+
+         return Ok(list);
+      }
+   }
+
+   /// Lists every object in chunks of `params.limit`, yielding each page as it arrives.
+   ///
+   /// If the continue token expires the list starts over from a fresh first page, which
+   /// consumers can tell apart by its different resourceVersion.
+   pub fn list_pages(&self, params: &ListParams) -> impl Stream<Item = Result<List<K>, APIError>> + use<K>
+   where
+      K: ListableResource,
+   {
+      let api = self.clone();
+      let params = params.paged();
+
+      futures::stream::try_unfold(Some((params, 0)), move |state| {
+         let api = api.clone();
+         async move {
+            let Some((mut params, mut restarts)) = state else {
+               return Ok(None);
+            };
+
+            let page = loop {
+               match api.list(&params).await {
+                  Ok(page) => break page,
+                  Err(e) if params.continue_token.is_some() && e.is_gone() && restarts < MAX_LIST_RESTARTS => {
+                     restarts += 1;
+                     params.continue_token = None;
+                  },
+                  Err(e) => return Err(e),
+               };
+            };
+
+            let next = page
+               .metadata
+               .continue_
+               .as_deref()
+               .filter(|token| !token.is_empty())
+               .map(|token| (params.clone().continue_token(token), restarts));
+
+            Ok(Some((page, next)))
+         }
+      })
+   }
+
+   /// Starts a watch from `version`; the response body streams newline-delimited `WatchEvent`s.
+   ///
+   /// BOOKMARK events are always asked for; they only carry a newer resourceVersion to
+   /// resume from.
+   pub async fn watch(&self, params: &ListParams, version: &str, timeout: Duration) -> Result<Response, APIError>
+   {
+      let seconds = timeout.as_secs().to_string();
+      let request = self.request(Method::GET, None, None).query(&params.query()).query(&[
+         ("watch", "true"),
+         ("resourceVersion", version),
+         ("timeoutSeconds", &seconds),
+         ("allowWatchBookmarks", "true"),
+      ]);
+
+      let response = self.client.send(request).await?;
+      response_into_error(response).await
+   }
+
+   pub async fn create(&self, object: &K) -> Result<K, APIError>
+   where
+      K: Serialize,
+   {
+      let request = self.request(Method::POST, None, None).json(object);
+      self.send(request).await
+   }
+
+   pub async fn replace(&self, name: &str, object: &K) -> Result<K, APIError>
+   where
+      K: Serialize,
+   {
+      let request = self.request(Method::PUT, Some(name), None).json(object);
+      self.send(request).await
+   }
+
+   pub async fn patch<P: Serialize>(&self, name: &str, patch: &Patch<P>) -> Result<K, APIError>
+   {
+      let request = self.request(Method::PATCH, Some(name), None);
+      self.send_patch(request, patch).await
+   }
+
+   pub async fn delete(&self, name: &str) -> Result<Deleted<K>, APIError>
+   {
+      let request = self.request(Method::DELETE, Some(name), None);
+      let value: serde_json::Value = self.send(request).await?;
+
+      if value.get("kind").and_then(|kind| kind.as_str()) == Some("Status") {
+         return Ok(Deleted::Status(Box::new(serde_json::from_value(value)?)));
+      };
+
+      Ok(Deleted::Object(serde_json::from_value(value)?))
+   }
+
+   pub async fn get_status(&self, name: &str) -> Result<K, APIError>
+   {
+      self.send(self.request(Method::GET, Some(name), Some("status"))).await
+   }
+
+   pub async fn replace_status(&self, name: &str, object: &K) -> Result<K, APIError>
+   where
+      K: Serialize,
+   {
+      let request = self.request(Method::PUT, Some(name), Some("status")).json(object);
+      self.send(request).await
+   }
+
+   pub async fn patch_status<P: Serialize>(&self, name: &str, patch: &Patch<P>) -> Result<K, APIError>
+   {
+      let request = self.request(Method::PATCH, Some(name), Some("status"));
+      self.send_patch(request, patch).await
+   }
+
+   pub async fn get_scale(&self, name: &str) -> Result<Scale, APIError>
+   {
+      self.send(self.request(Method::GET, Some(name), Some("scale"))).await
+   }
+
+   pub async fn replace_scale(&self, name: &str, scale: &Scale) -> Result<Scale, APIError>
+   {
+      let request = self.request(Method::PUT, Some(name), Some("scale")).json(scale);
+      self.send(request).await
+   }
+
+   pub async fn patch_scale<P: Serialize>(&self, name: &str, patch: &Patch<P>) -> Result<Scale, APIError>
+   {
+      let request = self.request(Method::PATCH, Some(name), Some("scale"));
+      self.send_patch(request, patch).await
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use std::sync::{Arc, Mutex};
+
+   use futures::TryStreamExt;
+   use k8s_openapi::api::core::v1::ConfigMap;
+
+   use super::*;
+
+   const ITEMS: usize = 5;
+
+   /// Serves `ITEMS` ConfigMaps in pages of the requested limit, recording the query of every
+   /// request. The continue token `expire` is rejected as expired the first time it is used;
+   /// every list started from the first page gets a newer resourceVersion.
+   async fn apiserver(expire: Option<&'static str>) -> (String, Arc<Mutex<Vec<String>>>)
+   {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+      const EXPIRED: &str = r#"{"kind":"Status","apiVersion":"v1","status":"Failure","reason":"Expired","code":410}"#;
+
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap();
+      let queries = Arc::new(Mutex::new(vec![]));
+      let recorded = queries.clone();
+
+      tokio::spawn(async move {
+         let mut expire = expire;
+         let mut version = 0;
+
+         loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            let mut request = vec![];
+            while !request.ends_with(b"\r\n\r\n") {
+               let mut byte = [0];
+               if socket.read(&mut byte).await.unwrap() == 0 {
+                  break;
+               };
+               request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let Some(target) = request.split(' ').nth(1) else {
+               continue;
+            };
+
+            let url = reqwest::Url::parse(&format!("http://apiserver{target}")).unwrap();
+            let query = url.query().unwrap_or_default().to_string();
+            recorded.lock().unwrap().push(query);
+
+            let mut limit = ITEMS;
+            let mut token = None;
+            for (key, value) in url.query_pairs() {
+               match &*key {
+                  "limit" => limit = value.parse().unwrap(),
+                  "continue" => token = Some(value.into_owned()),
+                  _ => (),
+               };
+            }
+
+            let (status, body) = match token {
+               Some(token) if expire == Some(&*token) => {
+                  expire = None;
+                  ("410 Gone", EXPIRED.to_string())
+               },
+               token => {
+                  let start = match token {
+                     Some(token) => token.parse().unwrap(),
+                     None => {
+                        version += 1;
+                        0
+                     },
+                  };
+                  let end = ITEMS.min(start + limit);
+
+                  let items: Vec<String> = (start..end)
+                     .map(|i| format!(r#"{{"apiVersion":"v1","kind":"ConfigMap","metadata":{{"name":"cm-{i}"}}}}"#))
+                     .collect();
+                  let next = match end < ITEMS {
+                     true => format!(r#","continue":"{end}","remainingItemCount":{}"#, ITEMS - end),
+                     false => String::new(),
+                  };
+
+                  let body = format!(
+                     r#"{{"apiVersion":"v1","kind":"ConfigMapList","metadata":{{"resourceVersion":"{version}"{next}}},"items":[{}]}}"#,
+                     items.join(",")
+                  );
+                  ("200 OK", body)
+               },
+            };
+
+            let response = format!("HTTP/1.1 {status}\r\ncontent-type: application/json\r\nconnection: close\r\n\r\n{body}");
+            socket.write_all(response.as_bytes()).await.unwrap();
+         }
+      });
+
+      (format!("http://{address}"), queries)
+   }
+
+   async fn config_maps(expire: Option<&'static str>) -> (Api<ConfigMap>, Arc<Mutex<Vec<String>>>)
+   {
+      let (server, queries) = apiserver(expire).await;
+      let config = crate::client::Config {
+         context: "test".into(),
+         namespace: "default".into(),
+         cluster: crate::client::Cluster {
+            server,
+            ..Default::default()
+         },
+         user: Default::default(),
+      };
+      let client = crate::client::KubeClient::from_config(config).unwrap();
+
+      (client.default_namespaced(), queries)
+   }
+
+   fn names(list: &List<ConfigMap>) -> Vec<String>
+   {
+      list.items.iter().map(|item| item.metadata.name.clone().unwrap()).collect()
+   }
+
+   #[tokio::test]
+   async fn list_all_follows_continue_tokens()
+   {
+      let (api, queries) = config_maps(None).await;
+      let list = api.list_all(&ListParams::default().limit(2)).await.unwrap();
+
+      assert_eq!(names(&list), ["cm-0", "cm-1", "cm-2", "cm-3", "cm-4"]);
+      assert_eq!(list.metadata.resource_version.as_deref(), Some("1"));
+      assert_eq!(list.metadata.continue_, None);
+      assert_eq!(*queries.lock().unwrap(), ["limit=2", "limit=2&continue=2", "limit=2&continue=4"]);
+   }
+
+   #[tokio::test]
+   async fn pages_default_to_the_default_page_size()
+   {
+      let (api, queries) = config_maps(None).await;
+
+      let list = api.list_all(&ListParams::default()).await.unwrap();
+      assert_eq!(list.items.len(), ITEMS);
+
+      let pages: Vec<_> = api.list_pages(&ListParams::default()).try_collect().await.unwrap();
+      assert_eq!(pages.len(), 1);
+
+      let page_size = format!("limit={DEFAULT_PAGE_SIZE}");
+      assert_eq!(*queries.lock().unwrap(), [page_size.as_str(); 2]);
+   }
+
+   #[tokio::test]
+   async fn list_all_restarts_after_the_continue_token_expired()
+   {
+      let (api, queries) = config_maps(Some("4")).await;
+      let list = api.list_all(&ListParams::default().limit(2)).await.unwrap();
+
+      // the pages from before the restart are not kept
+      assert_eq!(names(&list), ["cm-0", "cm-1", "cm-2", "cm-3", "cm-4"]);
+      assert_eq!(list.metadata.resource_version.as_deref(), Some("2"));
+      assert_eq!(
+         *queries.lock().unwrap(),
+         ["limit=2", "limit=2&continue=2", "limit=2&continue=4", "limit=2", "limit=2&continue=2", "limit=2&continue=4"]
+      );
+   }
+
+   #[tokio::test]
+   async fn list_pages_restarts_after_the_continue_token_expired()
+   {
+      let (api, _) = config_maps(Some("4")).await;
+      let pages: Vec<_> = api.list_pages(&ListParams::default().limit(2)).try_collect().await.unwrap();
+
+      let pages: Vec<_> = pages
+         .iter()
+         .map(|page| (page.metadata.resource_version.clone().unwrap(), names(page)))
+         .collect();
+
+      assert_eq!(
+         pages,
+         [
+            ("1".to_string(), vec!["cm-0".to_string(), "cm-1".to_string()]),
+            ("1".to_string(), vec!["cm-2".to_string(), "cm-3".to_string()]),
+            ("2".to_string(), vec!["cm-0".to_string(), "cm-1".to_string()]),
+            ("2".to_string(), vec!["cm-2".to_string(), "cm-3".to_string()]),
+            ("2".to_string(), vec!["cm-4".to_string()]),
+         ]
+      );
+   }
+
+   #[test]
+   fn segments_cannot_change_the_path()
+   {
+      let mut path = String::new();
+      push_segment(&mut path, "https:pod-1:8443");
+      push_segment(&mut path, "/");
+      push_segment(&mut path, "a/../b?watch=1%");
+
+      assert_eq!(path, "https:pod-1:8443%2Fa%2F..%2Fb%3Fwatch%3D1%25");
+   }
+}
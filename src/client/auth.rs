@@ -0,0 +1,402 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use k8s_openapi::chrono::{DateTime, Utc};
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use super::config::{AuthInfo, ExecConfig};
+
+/// Token files are re-read after this long so rotated tokens are picked up.
+const TOKEN_FILE_REFRESH: Duration = Duration::from_secs(60);
+
+/// Exec credentials are refreshed this long before they actually expire.
+const EXPIRY_SKEW: Duration = Duration::from_secs(10);
+
+const EXEC_API_VERSION: &str = "client.authentication.k8s.io/v1beta1";
+
+#[derive(Debug)]
+pub enum AuthError
+{
+   TokenFile
+   {
+      path: PathBuf,
+      error: std::io::Error,
+   },
+   ExecSpawn
+   {
+      command: Box<str>,
+      error: std::io::Error,
+      hint: Option<Box<str>>,
+   },
+   ExecFailed
+   {
+      command: Box<str>,
+      status: std::process::ExitStatus,
+      stderr: Box<str>,
+   },
+   ExecParse(serde_json::Error),
+   ExecNoToken,
+   ExecClientCertificate,
+}
+
+impl std::fmt::Display for AuthError
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::TokenFile { path, error } => write!(f, "failed to read token file {}: {error}", path.display()),
+         Self::ExecSpawn { command, error, hint } => {
+            write!(f, "failed to run credential plugin \"{command}\": {error}")?;
+            match hint {
+               Some(hint) => write!(f, "\n{hint}"),
+               None => Ok(()),
+            }
+         },
+         Self::ExecFailed { command, status, stderr } => {
+            write!(f, "credential plugin \"{command}\" exited with {status}: {stderr}")
+         },
+         Self::ExecParse(e) => write!(f, "credential plugin returned an invalid ExecCredential: {e}"),
+         Self::ExecNoToken => write!(f, "credential plugin returned no token"),
+         Self::ExecClientCertificate => {
+            write!(f, "credential plugin returned a client certificate, only tokens are supported")
+         },
+      }
+   }
+}
+
+impl std::error::Error for AuthError
+{
+   fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+   {
+      match self {
+         Self::TokenFile { error, .. } => Some(error),
+         Self::ExecSpawn { error, .. } => Some(error),
+         Self::ExecParse(e) => Some(e),
+         _ => None,
+      }
+   }
+}
+
+/// How requests made through `Base` are authenticated, on top of any TLS client certificate.
+#[derive(Debug, Clone, Default)]
+pub enum Auth
+{
+   #[default]
+   None,
+   Bearer(Box<str>),
+   Basic
+   {
+      username: Box<str>,
+      password: Box<str>,
+   },
+   TokenFile(Arc<TokenFile>),
+   Exec(Arc<ExecAuth>),
+}
+
+impl Auth
+{
+   /// Picks the kubeconfig user's token, token file, exec plugin or basic auth in that order.
+   pub fn from_auth_info(user: &AuthInfo) -> Self
+   {
+      if let Some(token) = &user.token {
+         return Self::Bearer(token.as_str().into());
+      };
+
+      if let Some(path) = &user.token_file {
+         return Self::TokenFile(Arc::new(TokenFile::new(path)));
+      };
+
+      if let Some(exec) = &user.exec {
+         return Self::Exec(Arc::new(ExecAuth::new(exec.clone())));
+      };
+
+      if let (Some(username), Some(password)) = (&user.username, &user.password) {
+         return Self::Basic {
+            username: username.as_str().into(),
+            password: password.as_str().into(),
+         };
+      };
+
+      Self::None
+   }
+
+   pub async fn apply(&self, request: RequestBuilder) -> Result<RequestBuilder, AuthError>
+   {
+      let request = match self {
+         Self::None => request,
+         Self::Bearer(token) => request.bearer_auth(token),
+         Self::Basic { username, password } => request.basic_auth(username, Some(password)),
+         Self::TokenFile(file) => request.bearer_auth(file.token().await?),
+         Self::Exec(exec) => request.bearer_auth(exec.token().await?),
+      };
+
+      Ok(request)
+   }
+
+   /// Drops any cached credential so the next request fetches a fresh one.
+   pub async fn invalidate(&self)
+   {
+      match self {
+         Self::TokenFile(file) => *file.cache.lock().await = None,
+         Self::Exec(exec) => *exec.cache.lock().await = None,
+         _ => (),
+      };
+   }
+}
+
+#[derive(Debug)]
+pub struct TokenFile
+{
+   path: PathBuf,
+   cache: Mutex<Option<(Box<str>, Instant)>>,
+}
+
+impl TokenFile
+{
+   pub fn new(path: impl Into<PathBuf>) -> Self
+   {
+      Self {
+         path: path.into(),
+         cache: Mutex::new(None),
+      }
+   }
+
+   pub async fn token(&self) -> Result<Box<str>, AuthError>
+   {
+      let mut cache = self.cache.lock().await;
+
+      if let Some((token, read_at)) = cache.as_ref()
+         && read_at.elapsed() < TOKEN_FILE_REFRESH
+      {
+         return Ok(token.clone());
+      };
+
+      let token = tokio::fs::read_to_string(&self.path)
+         .await
+         .map_err(|error| AuthError::TokenFile {
+            path: self.path.clone(),
+            error,
+         })?;
+
+      let token: Box<str> = token.trim().into();
+      *cache = Some((token.clone(), Instant::now()));
+      Ok(token)
+   }
+}
+
+fn expires_soon(expiry: &DateTime<Utc>, now: SystemTime) -> bool
+{
+   let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+   let deadline = now + EXPIRY_SKEW;
+   expiry.timestamp() <= deadline.as_secs() as i64
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredential
+{
+   status: Option<ExecCredentialStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredentialStatus
+{
+   token: Option<String>,
+   expiration_timestamp: Option<DateTime<Utc>>,
+   client_certificate_data: Option<String>,
+   client_key_data: Option<String>,
+}
+
+type ExecToken = (Box<str>, Option<DateTime<Utc>>);
+
+/// Runs a client-go credential plugin and caches its token until `expirationTimestamp`.
+#[derive(Debug)]
+pub struct ExecAuth
+{
+   config: ExecConfig,
+   cache: Mutex<Option<ExecToken>>,
+}
+
+impl ExecAuth
+{
+   pub fn new(config: ExecConfig) -> Self
+   {
+      Self {
+         config,
+         cache: Mutex::new(None),
+      }
+   }
+
+   pub async fn token(&self) -> Result<Box<str>, AuthError>
+   {
+      let mut cache = self.cache.lock().await;
+
+      if let Some((token, expiry)) = cache.as_ref() {
+         let fresh = match expiry {
+            None => true,
+            Some(expiry) => !expires_soon(expiry, SystemTime::now()),
+         };
+
+         if fresh {
+            return Ok(token.clone());
+         };
+      };
+
+      let (token, expiry) = self.run().await?;
+      *cache = Some((token.clone(), expiry));
+      Ok(token)
+   }
+
+   async fn run(&self) -> Result<ExecToken, AuthError>
+   {
+      let ExecConfig {
+         command,
+         args,
+         env,
+         api_version,
+         install_hint,
+      } = &self.config;
+
+      let api_version = api_version.as_deref().unwrap_or(EXEC_API_VERSION);
+      let exec_info = serde_json::json!({
+         "apiVersion": api_version,
+         "kind": "ExecCredential",
+         "spec": { "interactive": false },
+      });
+
+      let mut process = tokio::process::Command::new(command);
+      process
+         .args(args)
+         .envs(env.iter().map(|var| (&var.name, &var.value)))
+         .env("KUBERNETES_EXEC_INFO", exec_info.to_string())
+         .stdin(std::process::Stdio::null());
+
+      let output = process.output().await.map_err(|error| AuthError::ExecSpawn {
+         command: command.as_str().into(),
+         error,
+         hint: install_hint.as_deref().map(Into::into),
+      })?;
+
+      if !output.status.success() {
+         return Err(AuthError::ExecFailed {
+            command: command.as_str().into(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().into(),
+         });
+      };
+
+      let credential: ExecCredential = serde_json::from_slice(&output.stdout).map_err(AuthError::ExecParse)?;
+      let status = credential.status.ok_or(AuthError::ExecNoToken)?;
+
+      // the TLS identity is fixed when the http client is built, so it cannot come from here
+      if status.client_certificate_data.is_some() || status.client_key_data.is_some() {
+         return Err(AuthError::ExecClientCertificate);
+      };
+
+      let token = status.token.ok_or(AuthError::ExecNoToken)?;
+
+      Ok((token.into(), status.expiration_timestamp))
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use crate::client::config::ExecEnvVar;
+   use crate::client::temp_dir::TempDir;
+
+   /// Prints a credential whose token counts how often the plugin ran.
+   const PLUGIN: &str = r#"
+count=$(($(cat "$COUNT" 2>/dev/null || echo 0) + 1))
+echo "$count" > "$COUNT"
+printf '{"apiVersion":"client.authentication.k8s.io/v1beta1","kind":"ExecCredential","status":{"token":"token-%s","expirationTimestamp":"%s"%s}}' "$count" "$EXPIRY" "$EXTRA"
+"#;
+
+   fn expiring_in(duration: Duration) -> DateTime<Utc>
+   {
+      let expiry = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + duration;
+      DateTime::from_timestamp(expiry.as_secs() as i64, 0).unwrap()
+   }
+
+   /// The plugin keeps its files in the returned directory, which has to outlive it.
+   fn plugin(name: &str, expiry: DateTime<Utc>, extra: &str) -> (ExecAuth, TempDir)
+   {
+      let directory = TempDir::new(&format!("exec-auth-{name}"));
+      let script = directory.write("plugin.sh", PLUGIN);
+      let count = directory.path().join("count");
+
+      let var = |name: &str, value: String| ExecEnvVar {
+         name: name.into(),
+         value,
+      };
+
+      let exec = ExecAuth::new(ExecConfig {
+         command: "/bin/sh".into(),
+         args: vec![script.to_string_lossy().into()],
+         env: vec![
+            var("COUNT", count.to_string_lossy().into()),
+            var("EXPIRY", expiry.to_rfc3339()),
+            var("EXTRA", extra.into()),
+         ],
+         ..Default::default()
+      });
+
+      (exec, directory)
+   }
+
+   #[test]
+   fn tokens_expire_soon_within_the_skew()
+   {
+      let now = SystemTime::now();
+      let expiry = expiring_in(EXPIRY_SKEW + Duration::from_secs(2));
+
+      assert!(!expires_soon(&expiry, now));
+      assert!(expires_soon(&expiry, now + Duration::from_secs(3)));
+      assert!(expires_soon(&expiring_in(Duration::ZERO), now));
+   }
+
+   #[tokio::test]
+   async fn caches_the_token_until_it_expires()
+   {
+      let (exec, _directory) = plugin("cached", expiring_in(Duration::from_secs(3600)), "");
+
+      assert_eq!(exec.token().await.unwrap().as_ref(), "token-1");
+      assert_eq!(exec.token().await.unwrap().as_ref(), "token-1");
+   }
+
+   #[tokio::test]
+   async fn runs_again_once_the_token_expires_soon()
+   {
+      // already within EXPIRY_SKEW of expiring when it is handed out
+      let (exec, _directory) = plugin("expiring", expiring_in(EXPIRY_SKEW / 2), "");
+
+      assert_eq!(exec.token().await.unwrap().as_ref(), "token-1");
+      assert_eq!(exec.token().await.unwrap().as_ref(), "token-2");
+   }
+
+   #[tokio::test]
+   async fn runs_again_after_invalidate()
+   {
+      let (exec, _directory) = plugin("invalidate", expiring_in(Duration::from_secs(3600)), "");
+      let exec = Auth::Exec(Arc::new(exec));
+      let Auth::Exec(inner) = &exec else { unreachable!() };
+
+      assert_eq!(inner.token().await.unwrap().as_ref(), "token-1");
+      exec.invalidate().await;
+      assert_eq!(inner.token().await.unwrap().as_ref(), "token-2");
+   }
+
+   #[tokio::test]
+   async fn rejects_client_certificates()
+   {
+      let extra = r#","clientCertificateData":"cert","clientKeyData":"key""#;
+      let (exec, _directory) = plugin("certificate", expiring_in(Duration::from_secs(3600)), extra);
+
+      assert!(matches!(exec.token().await, Err(AuthError::ExecClientCertificate)));
+   }
+}
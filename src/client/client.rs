@@ -1,10 +1,17 @@
 use std::sync::Arc;
-use std::time::Duration;
 
-use super::Pod;
+use k8s_openapi::{
+   ClusterResourceScope, NamespaceResourceScope, Resource,
+   api::{apps::v1::ReplicaSet, core::v1::{Node, Pod as JsonPod, Service}},
+};
+
+use super::{Api, Exec, ListParams, LocalPortForward, Pod, PortForward, ProxyRequest, ProxyTarget, RateLimiter, ResourceWatcher, RetryPolicy};
+use super::auth::Auth;
+use super::config::{Config, ConfigError};
 
 use super::{
    Watcher,
+   WatcherConfig,
    DaemonSetEvent,
    CAdvisorDaemonSetMetadata,
    APIError, 
@@ -15,15 +22,71 @@ use super::{
 #[derive(Debug, Clone)]
 pub struct Base {
    pub host: Box<str>,
+   pub namespace: Box<str>,
    client: reqwest::Client,
+   /// Limited to HTTP/1.1, which connection upgrades such as WebSockets need.
+   http1: reqwest::Client,
+   auth: Auth,
+   limiter: Arc<RateLimiter>,
+   retry: RetryPolicy,
 }
 
 impl Base {
-   pub fn get(&self, endpoint: impl AsRef<str>) -> reqwest::RequestBuilder {
+   pub fn request(&self, method: reqwest::Method, endpoint: impl AsRef<str>) -> reqwest::RequestBuilder {
       let host = &self.host;
       let endpoint = endpoint.as_ref();
       let url = format!("{host}{endpoint}");
-      self.client.get(url)
+      self.client.request(method, url)
+   }
+
+   pub fn get(&self, endpoint: impl AsRef<str>) -> reqwest::RequestBuilder {
+      self.request(reqwest::Method::GET, endpoint)
+   }
+
+   /// A GET request for `endpoint` that can be upgraded to another protocol.
+   pub fn upgrade(&self, endpoint: impl AsRef<str>) -> reqwest::RequestBuilder {
+      let host = &self.host;
+      let endpoint = endpoint.as_ref();
+      self.http1.get(format!("{host}{endpoint}"))
+   }
+
+   pub fn retry_policy(&self) -> &RetryPolicy {
+      &self.retry
+   }
+
+   pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+      self.retry = retry;
+      self
+   }
+
+   /// Authenticates and sends a request built by `request` or `get`.
+   ///
+   /// Every attempt waits for the client's rate limiter, and 429/503 answers and connection
+   /// failures are retried following the retry policy. Requests with streaming bodies cannot
+   /// be cloned and are sent only once.
+   pub async fn send(&self, mut request: reqwest::RequestBuilder) -> Result<reqwest::Response, APIError> {
+      let mut attempt = 0;
+
+      loop {
+         let retry = request.try_clone();
+
+         self.limiter.acquire().await;
+         let result = self.auth.apply(request).await?.send().await;
+
+         if let Ok(response) = &result
+            && response.status() == reqwest::StatusCode::UNAUTHORIZED
+         {
+            self.auth.invalidate().await;
+         };
+
+         let (Some(retry), Some(delay)) = (retry, self.retry.retry_after(&result, attempt)) else {
+            return Ok(result?);
+         };
+
+         tokio::time::sleep(delay).await;
+         request = retry;
+         attempt += 1;
+      }
    }
 }
 
@@ -54,22 +117,32 @@ pub struct Proxy {
 
 
 
-use super::response_into_error;
-
 impl Proxy {
-   pub async fn pod(&self, pod: &Pod, endpoint: &str) -> Result<reqwest::Response, APIError> {
-      let client  = &*self.client;
+   /// A request to `path` on a pod, e.g. `ProxyTarget::new(name).port("8080")` and `metrics`.
+   pub fn pods(&self, namespace: &str, target: &ProxyTarget, path: &str) -> ProxyRequest {
+      let api = Api::<JsonPod>::namespaced((*self.client).clone(), namespace);
+      ProxyRequest::new(self.client.clone(), api.url_path(Some(&target.to_string()), Some("proxy")), path)
+   }
 
+   /// A request to `path` on a node's kubelet, e.g. `metrics/cadvisor` or `stats/summary`.
+   pub fn nodes(&self, target: &ProxyTarget, path: &str) -> ProxyRequest {
+      let api = Api::<Node>::cluster((*self.client).clone());
+      ProxyRequest::new(self.client.clone(), api.url_path(Some(&target.to_string()), Some("proxy")), path)
+   }
+
+   pub fn services(&self, namespace: &str, target: &ProxyTarget, path: &str) -> ProxyRequest {
+      let api = Api::<Service>::namespaced((*self.client).clone(), namespace);
+      ProxyRequest::new(self.client.clone(), api.url_path(Some(&target.to_string()), Some("proxy")), path)
+   }
+
+   pub async fn pod(&self, pod: &Pod, endpoint: &str) -> Result<reqwest::Response, APIError> {
       let Pod {
          namespace,
          name,
          ..
       } = pod;
 
-      let endpoint = format!("/api/v1/namespaces/{namespace}/pods/{name}/proxy/{endpoint}");
-      let response = client.get(endpoint).send().await?;
-      let response = response_into_error(response).await?;
-      Ok(response)
+      self.pods(namespace, &ProxyTarget::new(name), endpoint).send().await
    }
 }
 
@@ -78,9 +151,19 @@ impl Proxy {
 
 
 impl Watch {
-   pub fn daemon_set_pods(&self, daemon_set: CAdvisorDaemonSetMetadata, state: CAdvisorPods, duration: Duration) -> Watcher<CAdvisorDaemonSetMetadata, DaemonSetEvent> {
+   pub fn daemon_set_pods(&self, daemon_set: CAdvisorDaemonSetMetadata, state: CAdvisorPods, config: WatcherConfig) -> Watcher<CAdvisorDaemonSetMetadata, DaemonSetEvent> {
       let client = (*self.client).clone();
-      Watcher::new(client, daemon_set, state, duration)
+      Watcher::<CAdvisorDaemonSetMetadata, DaemonSetEvent>::new(client, daemon_set, state, config)
+   }
+
+   pub fn nodes(&self, params: ListParams, config: WatcherConfig) -> ResourceWatcher<Node> {
+      let api = Api::<Node>::cluster((*self.client).clone());
+      ResourceWatcher::new(api, params, config)
+   }
+
+   pub fn replica_sets(&self, namespace: &str, params: ListParams, config: WatcherConfig) -> ResourceWatcher<ReplicaSet> {
+      let api = Api::<ReplicaSet>::namespaced((*self.client).clone(), namespace);
+      ResourceWatcher::new(api, params, config)
    }
 }
 
@@ -95,52 +178,132 @@ unsafe impl Send for KubeClient {}
 
 impl Clone for KubeClient {
    fn clone(&self) -> Self {
-      let base= self.get.client.as_ref();
-      let base = Arc::new(base.clone());
-
-      let get = Get {
-         client: base.clone(),
-      };
-
-      let watch = Watch {
-         client: base.clone(),
-      };
-
-      let proxy = Proxy {
-         client: base.clone(),
-      };
-
-      KubeClient {
-         get,
-         proxy,
-         watch,
-      }
+      let base = self.get.client.as_ref();
+      Self::from_base(base.clone())
    }
 }
 
 impl KubeClient {
-   pub fn new() -> Result<Self, reqwest::Error> {
+   /// Builds a client for the current context of the kubeconfig.
+   pub fn new() -> Result<Self, ConfigError> {
+      let config = Config::kubeconfig()?;
+      Self::from_config(config)
+   }
+
+   /// Builds an in-cluster client when running in a Pod, otherwise falls back to the kubeconfig.
+   pub fn infer() -> Result<Self, ConfigError> {
+      let config = Config::infer()?;
+      Self::from_config(config)
+   }
 
-      use super::config::{read_config, generate_creds};
+   /// Builds a client from the service-account mount of the Pod it runs in.
+   pub fn incluster() -> Result<Self, ConfigError> {
+      let config = Config::incluster()?;
+      Self::from_config(config)
+   }
+
+   /// Builds a client for a named context of the kubeconfig.
+   pub fn from_context(context: &str) -> Result<Self, ConfigError> {
+      let config = Config::from_context(context)?;
+      Self::from_config(config)
+   }
 
-      let (host, ca_cert, client_cert, client_key) = read_config();
-      let (certificate, identity) = generate_creds(ca_cert, client_cert, client_key);
+   pub fn from_config(config: Config) -> Result<Self, ConfigError> {
+      use super::config::Tls;
 
+      let Config {
+         namespace,
+         cluster,
+         user,
+         ..
+      } = config;
 
-      let client = reqwest::Client::builder()
-         .use_rustls_tls()
-         // without this, it didnt use rustls and identity would fail
-         // because identity provided here is not compatible with
-         // native-tls
-         .add_root_certificate(certificate)
-         .identity(identity)
-         .build()?;
+      let auth = Auth::from_auth_info(&user);
+      let tls = Tls::load(&cluster, &user)?;
+      let client = tls.builder().build()?;
+      let http1 = tls.builder().http1_only().build()?;
 
       let base = Base {
          client,
-         host: host.into(),
+         http1,
+         host: tls.host().into(),
+         namespace,
+         auth,
+         limiter: Arc::new(RateLimiter::default()),
+         retry: RetryPolicy::default(),
       };
 
+      Ok(Self::from_base(base))
+   }
+
+   /// Replaces the rate limiter shared by this client and its later clones.
+   pub fn with_rate_limit(self, qps: f64, burst: u32) -> Self {
+      let mut base = self.base().clone();
+      base.limiter = Arc::new(RateLimiter::new(qps, burst));
+      Self::from_base(base)
+   }
+
+   pub fn with_retry_policy(self, retry: RetryPolicy) -> Self {
+      let base = self.base().clone().with_retry_policy(retry);
+      Self::from_base(base)
+   }
+
+   pub fn base(&self) -> &Base {
+      &self.get.client
+   }
+
+   /// Runs `command` in a container of `pod`, or its only container when `None`.
+   pub async fn exec(&self, pod: &Pod, container: Option<&str>, command: &[&str]) -> Result<Exec, APIError> {
+      super::exec::exec(self.base(), pod, container, command).await
+   }
+
+   /// Opens a port-forward session to `ports` of a pod, see `PortForward`.
+   pub async fn port_forward(&self, namespace: &str, name: &str, ports: &[u16]) -> Result<PortForward, APIError> {
+      super::portforward::port_forward(self.base(), namespace, name, ports).await
+   }
+
+   /// Forwards connections to `address`, e.g. `127.0.0.1:0`, to `port` of a pod.
+   pub async fn port_forward_local(
+      &self,
+      namespace: &str,
+      name: &str,
+      port: u16,
+      address: std::net::SocketAddr,
+   ) -> Result<LocalPortForward, APIError> {
+      super::portforward::port_forward_local(self.base(), namespace, name, port, address).await
+   }
+
+   pub fn namespaced<K>(&self, namespace: &str) -> Api<K>
+   where
+      K: Resource<Scope = NamespaceResourceScope>,
+   {
+      Api::namespaced(self.base().clone(), namespace)
+   }
+
+   /// Namespaced resources in the namespace of the client's context.
+   pub fn default_namespaced<K>(&self) -> Api<K>
+   where
+      K: Resource<Scope = NamespaceResourceScope>,
+   {
+      Api::default_namespaced(self.base().clone())
+   }
+
+   pub fn cluster<K>(&self) -> Api<K>
+   where
+      K: Resource<Scope = ClusterResourceScope>,
+   {
+      Api::cluster(self.base().clone())
+   }
+
+   /// Cluster-scoped resources, or namespaced ones across all namespaces.
+   pub fn all<K>(&self) -> Api<K>
+   where
+      K: Resource,
+   {
+      Api::all(self.base().clone())
+   }
+
+   fn from_base(base: Base) -> Self {
       let base = Arc::new(base);
 
       let get = Get {
@@ -154,10 +317,10 @@ impl KubeClient {
          client: base.clone(),
       };
 
-      Ok(Self {
+      Self {
          get,
          watch,
          proxy,
-      })
+      }
    }
 }
@@ -0,0 +1,58 @@
+use std::path::Path;
+
+use super::{AuthInfo, Cluster, Config, ConfigError};
+
+const SERVICE_HOST: &str = "KUBERNETES_SERVICE_HOST";
+const SERVICE_PORT: &str = "KUBERNETES_SERVICE_PORT";
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const TOKEN: &str = "token";
+const CA_CERT: &str = "ca.crt";
+const NAMESPACE: &str = "namespace";
+
+/// Builds the config of a Pod from its service-account mount.
+///
+/// The token is referenced as a token file rather than read here, so projected tokens that
+/// rotate are picked up by the periodic re-read in `Auth::TokenFile`.
+pub fn incluster() -> Result<Config, ConfigError>
+{
+   let host = std::env::var(SERVICE_HOST).map_err(|_| ConfigError::NotInCluster(SERVICE_HOST))?;
+   let port = std::env::var(SERVICE_PORT).map_err(|_| ConfigError::NotInCluster(SERVICE_PORT))?;
+
+   let server = if host.contains(':') {
+      format!("https://[{host}]:{port}")
+   } else {
+      format!("https://{host}:{port}")
+   };
+
+   let directory = Path::new(SERVICE_ACCOUNT);
+
+   let token = directory.join(TOKEN);
+   if !token.exists() {
+      return Err(ConfigError::NotInCluster(TOKEN));
+   };
+
+   let namespace = directory.join(NAMESPACE);
+   let namespace = std::fs::read_to_string(&namespace).map_err(|error| ConfigError::Read {
+      path: namespace,
+      error,
+   })?;
+
+   let cluster = Cluster {
+      server,
+      certificate_authority: Some(directory.join(CA_CERT).to_string_lossy().into()),
+      ..Default::default()
+   };
+
+   let user = AuthInfo {
+      token_file: Some(token.to_string_lossy().into()),
+      ..Default::default()
+   };
+
+   Ok(Config {
+      context: "in-cluster".into(),
+      namespace: namespace.trim().into(),
+      cluster,
+      user,
+   })
+}
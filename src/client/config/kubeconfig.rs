@@ -0,0 +1,391 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use super::ConfigError;
+
+/// On-disk kubeconfig, or several of them merged the way kubectl merges `KUBECONFIG`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Kubeconfig
+{
+   #[serde(default, deserialize_with = "null_as_default")]
+   pub clusters: Vec<NamedCluster>,
+   #[serde(default, deserialize_with = "null_as_default")]
+   pub users: Vec<NamedAuthInfo>,
+   #[serde(default, deserialize_with = "null_as_default")]
+   pub contexts: Vec<NamedContext>,
+   #[serde(default)]
+   pub current_context: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamedCluster
+{
+   pub name: String,
+   pub cluster: Cluster,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Cluster
+{
+   pub server: String,
+   pub certificate_authority: Option<String>,
+   pub certificate_authority_data: Option<String>,
+   pub insecure_skip_tls_verify: Option<bool>,
+   pub tls_server_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamedAuthInfo
+{
+   pub name: String,
+   #[serde(default)]
+   pub user: AuthInfo,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthInfo
+{
+   pub client_certificate: Option<String>,
+   pub client_certificate_data: Option<String>,
+   pub client_key: Option<String>,
+   pub client_key_data: Option<String>,
+   pub token: Option<String>,
+   #[serde(rename = "tokenFile")]
+   pub token_file: Option<String>,
+   pub username: Option<String>,
+   pub password: Option<String>,
+   pub exec: Option<ExecConfig>,
+}
+
+/// `users[].user.exec` section describing a client-go credential plugin.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecConfig
+{
+   pub command: String,
+   #[serde(default, deserialize_with = "null_as_default")]
+   pub args: Vec<String>,
+   #[serde(default, deserialize_with = "null_as_default")]
+   pub env: Vec<ExecEnvVar>,
+   pub api_version: Option<String>,
+   pub install_hint: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ExecEnvVar
+{
+   pub name: String,
+   pub value: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamedContext
+{
+   pub name: String,
+   pub context: Context,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Context
+{
+   pub cluster: String,
+   pub user: String,
+   pub namespace: Option<String>,
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+   D: Deserializer<'de>,
+   T: Default + Deserialize<'de>,
+{
+   let value = Option::<T>::deserialize(deserializer)?;
+   Ok(value.unwrap_or_default())
+}
+
+impl Kubeconfig
+{
+   pub fn read_from(path: impl AsRef<Path>) -> Result<Self, ConfigError>
+   {
+      let path = path.as_ref();
+
+      let string = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+         path: path.into(),
+         error,
+      })?;
+
+      let mut config: Self = serde_yaml::from_str(&string).map_err(|error| ConfigError::Parse {
+         path: path.into(),
+         error,
+      })?;
+
+      if let Some(directory) = path.parent() {
+         config.resolve_paths(directory);
+      };
+
+      Ok(config)
+   }
+
+   /// Makes file references relative to the kubeconfig absolute, as kubectl does before merging.
+   fn resolve_paths(&mut self, directory: &Path)
+   {
+      for NamedCluster { cluster, .. } in self.clusters.iter_mut() {
+         resolve(directory, &mut cluster.certificate_authority);
+      }
+
+      for NamedAuthInfo { user, .. } in self.users.iter_mut() {
+         resolve(directory, &mut user.client_certificate);
+         resolve(directory, &mut user.client_key);
+         resolve(directory, &mut user.token_file);
+
+         // a bare command name is looked up in PATH, only paths are relative to the file
+         if let Some(exec) = user.exec.as_mut()
+            && exec.command.contains(std::path::MAIN_SEPARATOR)
+         {
+            let mut command = Some(std::mem::take(&mut exec.command));
+            resolve(directory, &mut command);
+            exec.command = command.unwrap_or_default();
+         };
+      }
+   }
+
+   /// Loads `KUBECONFIG` if set, otherwise `$HOME/.kube/config`.
+   ///
+   /// Like kubectl, files listed in `KUBECONFIG` that do not exist are skipped, and for every
+   /// cluster, user and context name the first file defining it wins.
+   pub fn read() -> Result<Self, ConfigError>
+   {
+      let paths: Vec<PathBuf> = match std::env::var_os("KUBECONFIG") {
+         Some(value) if !value.is_empty() => std::env::split_paths(&value)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect(),
+         _ => vec![default_path()?],
+      };
+
+      Self::read_paths(&paths)
+   }
+
+   /// Reads and merges `paths` in order, as `read` does with the files of `KUBECONFIG`.
+   fn read_paths(paths: &[PathBuf]) -> Result<Self, ConfigError>
+   {
+      let mut merged: Option<Kubeconfig> = None;
+
+      for path in paths.iter() {
+         if paths.len() > 1 && !path.exists() {
+            continue;
+         };
+
+         let config = Self::read_from(path)?;
+         merged = Some(match merged {
+            None => config,
+            Some(merged) => merged.merge(config),
+         });
+      }
+
+      merged.ok_or(ConfigError::NoKubeconfig)
+   }
+
+   /// Merges `other` into `self`, keeping entries of `self` on name conflicts.
+   pub fn merge(mut self, other: Kubeconfig) -> Self
+   {
+      for cluster in other.clusters {
+         if !self.clusters.iter().any(|x| x.name == cluster.name) {
+            self.clusters.push(cluster);
+         };
+      }
+
+      for user in other.users {
+         if !self.users.iter().any(|x| x.name == user.name) {
+            self.users.push(user);
+         };
+      }
+
+      for context in other.contexts {
+         if !self.contexts.iter().any(|x| x.name == context.name) {
+            self.contexts.push(context);
+         };
+      }
+
+      let current_context = self.current_context.take().filter(|x| !x.is_empty());
+      self.current_context = current_context.or(other.current_context);
+      self
+   }
+
+   pub fn context(&self, name: &str) -> Option<&Context>
+   {
+      self.contexts.iter().find(|x| x.name == name).map(|x| &x.context)
+   }
+
+   pub fn cluster(&self, name: &str) -> Option<&Cluster>
+   {
+      self.clusters.iter().find(|x| x.name == name).map(|x| &x.cluster)
+   }
+
+   pub fn user(&self, name: &str) -> Option<&AuthInfo>
+   {
+      self.users.iter().find(|x| x.name == name).map(|x| &x.user)
+   }
+}
+
+fn resolve(directory: &Path, path: &mut Option<String>)
+{
+   let Some(relative) = path.as_deref().filter(|x| !x.is_empty() && Path::new(x).is_relative()) else {
+      return;
+   };
+
+   *path = Some(directory.join(relative).to_string_lossy().into());
+}
+
+fn default_path() -> Result<PathBuf, ConfigError>
+{
+   let home = std::env::var_os("HOME")
+      .filter(|home| !home.is_empty())
+      .map(PathBuf::from)
+      .or_else(std::env::home_dir)
+      .ok_or(ConfigError::NoHome)?;
+
+   Ok(home.join(".kube").join("config"))
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use crate::client::temp_dir::TempDir;
+
+   const FIRST: &str = "
+current-context: first
+clusters:
+- name: shared
+  cluster:
+    server: https://first
+    certificate-authority: ca.crt
+- name: only-first
+  cluster:
+    server: https://only-first
+users:
+- name: shared
+  user:
+    token: first
+contexts:
+- name: shared
+  context:
+    cluster: shared
+    user: shared
+    namespace: first
+";
+
+   const SECOND: &str = "
+current-context: second
+clusters:
+- name: shared
+  cluster:
+    server: https://second
+- name: only-second
+  cluster:
+    server: https://only-second
+users:
+- name: shared
+  user:
+    token: second
+- name: only-second
+  user:
+    client-certificate: certs/client.crt
+    client-key: /absolute/client.key
+    tokenFile: token
+    exec:
+      command: ./bin/plugin
+contexts:
+- name: shared
+  context:
+    cluster: only-second
+    user: only-second
+    namespace: second
+";
+
+   #[test]
+   fn first_file_wins_on_every_conflict()
+   {
+      let directory = TempDir::new("kubeconfig-merge");
+      let first = directory.write("first", FIRST);
+      let second = directory.write("second", SECOND);
+
+      let config = Kubeconfig::read_paths(&[first, second]).unwrap();
+
+      assert_eq!(config.current_context.as_deref(), Some("first"));
+      assert_eq!(config.cluster("shared").unwrap().server, "https://first");
+      assert_eq!(config.cluster("only-first").unwrap().server, "https://only-first");
+      assert_eq!(config.cluster("only-second").unwrap().server, "https://only-second");
+      assert_eq!(config.user("shared").unwrap().token.as_deref(), Some("first"));
+      assert!(config.user("only-second").is_some());
+      assert_eq!(config.context("shared").unwrap().namespace.as_deref(), Some("first"));
+      assert_eq!(config.clusters.len(), 3);
+      assert_eq!(config.contexts.len(), 1);
+   }
+
+   #[test]
+   fn current_context_comes_from_the_first_file_setting_it()
+   {
+      let directory = TempDir::new("kubeconfig-current-context");
+      let unset = directory.write("unset", "current-context: \"\"\n");
+      let second = directory.write("second", SECOND);
+
+      let config = Kubeconfig::read_paths(&[unset, second]).unwrap();
+
+      assert_eq!(config.current_context.as_deref(), Some("second"));
+   }
+
+   #[test]
+   fn missing_files_are_skipped()
+   {
+      let directory = TempDir::new("kubeconfig-missing");
+      let missing = directory.path().join("missing");
+      let second = directory.write("second", SECOND);
+
+      let config = Kubeconfig::read_paths(&[missing.clone(), second]).unwrap();
+      assert_eq!(config.current_context.as_deref(), Some("second"));
+
+      // unless it is the only one
+      assert!(matches!(Kubeconfig::read_paths(&[missing]), Err(ConfigError::Read { .. })));
+   }
+
+   #[test]
+   fn relative_paths_are_resolved_against_the_declaring_file()
+   {
+      let directory = TempDir::new("kubeconfig-paths");
+      std::fs::create_dir(directory.path().join("first")).unwrap();
+      std::fs::create_dir(directory.path().join("second")).unwrap();
+      let first = directory.write("first/config", FIRST);
+      let second = directory.write("second/config", SECOND);
+
+      let config = Kubeconfig::read_paths(&[first, second]).unwrap();
+      let path = |directory_name: &str, file: &str| -> Option<String> {
+         Some(directory.path().join(directory_name).join(file).to_string_lossy().into())
+      };
+
+      assert_eq!(config.cluster("shared").unwrap().certificate_authority, path("first", "ca.crt"));
+
+      let user = config.user("only-second").unwrap();
+      assert_eq!(user.client_certificate, path("second", "certs/client.crt"));
+      assert_eq!(user.client_key.as_deref(), Some("/absolute/client.key"));
+      assert_eq!(user.token_file, path("second", "token"));
+      assert_eq!(Some(user.exec.as_ref().unwrap().command.clone()), path("second", "./bin/plugin"));
+   }
+
+   #[test]
+   fn bare_exec_commands_are_left_to_path()
+   {
+      let directory = TempDir::new("kubeconfig-exec");
+      let config = directory.write(
+         "config",
+         "users:\n- name: plugin\n  user:\n    exec:\n      command: gke-gcloud-auth-plugin\n",
+      );
+
+      let config = Kubeconfig::read_paths(&[config]).unwrap();
+
+      assert_eq!(config.user("plugin").unwrap().exec.as_ref().unwrap().command, "gke-gcloud-auth-plugin");
+   }
+}
@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+
+mod incluster;
+mod kubeconfig;
+mod tls;
+
+pub use kubeconfig::{
+   AuthInfo, Cluster, Context, ExecConfig, ExecEnvVar, Kubeconfig, NamedAuthInfo, NamedCluster, NamedContext,
+};
+pub use tls::Tls;
+
+#[derive(Debug)]
+pub enum ConfigError
+{
+   NoHome,
+   NoKubeconfig,
+   Read
+   {
+      path: PathBuf,
+      error: std::io::Error,
+   },
+   Parse
+   {
+      path: PathBuf,
+      error: serde_yaml::Error,
+   },
+   NoCurrentContext,
+   ContextNotFound(Box<str>),
+   ClusterNotFound(Box<str>),
+   UserNotFound(Box<str>),
+   NotInCluster(&'static str),
+   MissingField(&'static str),
+   File
+   {
+      field: &'static str,
+      path: PathBuf,
+      error: std::io::Error,
+   },
+   Base64
+   {
+      field: &'static str,
+      error: base64::DecodeError,
+   },
+   Certificate
+   {
+      field: &'static str,
+      error: reqwest::Error,
+   },
+   InvalidServer
+   {
+      server: Box<str>,
+      reason: Box<str>,
+   },
+   Http(reqwest::Error),
+}
+
+impl From<reqwest::Error> for ConfigError
+{
+   fn from(value: reqwest::Error) -> Self
+   {
+      Self::Http(value)
+   }
+}
+
+impl std::fmt::Display for ConfigError
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::NoHome => write!(f, "could not determine the home directory to find the kubeconfig"),
+         Self::NoKubeconfig => write!(f, "none of the files in KUBECONFIG exist"),
+         Self::Read { path, error } => write!(f, "failed to read {}: {error}", path.display()),
+         Self::Parse { path, error } => write!(f, "failed to parse kubeconfig {}: {error}", path.display()),
+         Self::NoCurrentContext => write!(f, "kubeconfig has no current-context and no context was given"),
+         Self::ContextNotFound(name) => write!(f, "context \"{name}\" not found in kubeconfig"),
+         Self::ClusterNotFound(name) => write!(f, "cluster \"{name}\" not found in kubeconfig"),
+         Self::UserNotFound(name) => write!(f, "user \"{name}\" not found in kubeconfig"),
+         Self::NotInCluster(missing) => write!(f, "not running in a cluster: {missing} is missing"),
+         Self::MissingField(field) => write!(f, "kubeconfig is missing the field \"{field}\""),
+         Self::File { field, path, error } => write!(f, "failed to read {field} file {}: {error}", path.display()),
+         Self::Base64 { field, error } => write!(f, "{field} is not valid base64: {error}"),
+         Self::Certificate { field, error } => write!(f, "{field} is not a valid PEM certificate or key: {error}"),
+         Self::InvalidServer { server, reason } => write!(f, "invalid server \"{server}\": {reason}"),
+         Self::Http(e) => write!(f, "failed to build the http client: {e}"),
+      }
+   }
+}
+
+impl std::error::Error for ConfigError
+{
+   fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+   {
+      match self {
+         Self::Read { error, .. } => Some(error),
+         Self::Parse { error, .. } => Some(error),
+         Self::File { error, .. } => Some(error),
+         Self::Base64 { error, .. } => Some(error),
+         Self::Certificate { error, .. } => Some(error),
+         Self::Http(e) => Some(e),
+         _ => None,
+      }
+   }
+}
+
+/// A kubeconfig context resolved to the cluster, user and namespace it points at.
+#[derive(Debug, Clone)]
+pub struct Config
+{
+   pub context: Box<str>,
+   pub namespace: Box<str>,
+   pub cluster: Cluster,
+   pub user: AuthInfo,
+}
+
+impl Config
+{
+   /// Uses the in-cluster config when running in a Pod, otherwise the current kubeconfig context.
+   pub fn infer() -> Result<Self, ConfigError>
+   {
+      match Self::incluster() {
+         Err(ConfigError::NotInCluster(_)) => Self::kubeconfig(),
+         result => result,
+      }
+   }
+
+   /// Reads the service-account token, CA and namespace mounted into every Pod.
+   pub fn incluster() -> Result<Self, ConfigError>
+   {
+      incluster::incluster()
+   }
+
+   /// Resolves the current context of the merged kubeconfig.
+   pub fn kubeconfig() -> Result<Self, ConfigError>
+   {
+      let kubeconfig = Kubeconfig::read()?;
+      Self::from_kubeconfig(&kubeconfig, None)
+   }
+
+   /// Resolves the named context of the merged kubeconfig.
+   pub fn from_context(context: &str) -> Result<Self, ConfigError>
+   {
+      let kubeconfig = Kubeconfig::read()?;
+      Self::from_kubeconfig(&kubeconfig, Some(context))
+   }
+
+   /// Resolves `context`, or the kubeconfig's `current-context` when `None`.
+   pub fn from_kubeconfig(kubeconfig: &Kubeconfig, context: Option<&str>) -> Result<Self, ConfigError>
+   {
+      let name = context
+         .or(kubeconfig.current_context.as_deref())
+         .filter(|name| !name.is_empty())
+         .ok_or(ConfigError::NoCurrentContext)?;
+
+      let context = kubeconfig
+         .context(name)
+         .ok_or_else(|| ConfigError::ContextNotFound(name.into()))?;
+
+      let cluster = kubeconfig
+         .cluster(&context.cluster)
+         .ok_or_else(|| ConfigError::ClusterNotFound(context.cluster.as_str().into()))?;
+
+      let user = kubeconfig
+         .user(&context.user)
+         .ok_or_else(|| ConfigError::UserNotFound(context.user.as_str().into()))?;
+
+      let namespace = context.namespace.as_deref().unwrap_or("default");
+
+      Ok(Self {
+         context: name.into(),
+         namespace: namespace.into(),
+         cluster: cluster.clone(),
+         user: user.clone(),
+      })
+   }
+}
@@ -0,0 +1,245 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{HOST, HeaderMap, HeaderValue};
+use reqwest::{Certificate, ClientBuilder, Identity, Url};
+
+use super::{AuthInfo, Cluster, ConfigError};
+
+/// The cluster's CA, the user's certificate and the host requests are sent to, read from
+/// disk once so every client built from them uses the same files.
+///
+/// When `tls-server-name` is set the host uses that name, so rustls sends it as SNI and
+/// verifies the certificate against it. The name is resolved to the address of `server`
+/// when a connection is made, and requests keep the `Host` header of `server`; builders are
+/// limited to HTTP/1.1 because HTTP/2 would take `:authority` from the URL instead.
+pub struct Tls
+{
+   host: Box<str>,
+   insecure: bool,
+   root: Option<Certificate>,
+   identity: Option<Identity>,
+   server_name: Option<(Arc<ServerNameResolver>, HeaderValue)>,
+}
+
+impl Tls
+{
+   pub fn load(cluster: &Cluster, user: &AuthInfo) -> Result<Self, ConfigError>
+   {
+      let insecure = cluster.insecure_skip_tls_verify.unwrap_or(false);
+      let root = match insecure {
+         true => None,
+         false => root_certificate(cluster)?,
+      };
+
+      let (host, server_name) = match cluster.tls_server_name.as_deref().filter(|name| !name.is_empty()) {
+         None => (cluster.server.as_str().into(), None),
+         Some(server_name) => {
+            let (host, resolver, authority) = override_server_name(&cluster.server, server_name)?;
+            (host, Some((Arc::new(resolver), authority)))
+         },
+      };
+
+      Ok(Self {
+         host,
+         insecure,
+         root,
+         identity: identity(user)?,
+         server_name,
+      })
+   }
+
+   pub fn host(&self) -> &str
+   {
+      &self.host
+   }
+
+   /// A rustls client builder trusting the cluster's CA and presenting the user's certificate.
+   pub fn builder(&self) -> ClientBuilder
+   {
+      // without this, it didnt use rustls and identity would fail
+      // because identity provided here is not compatible with
+      // native-tls
+      let mut builder = reqwest::Client::builder().use_rustls_tls();
+
+      if self.insecure {
+         builder = builder.danger_accept_invalid_certs(true);
+      } else if let Some(certificate) = &self.root {
+         builder = builder.add_root_certificate(certificate.clone());
+      };
+
+      if let Some(identity) = &self.identity {
+         builder = builder.identity(identity.clone());
+      };
+
+      if let Some((resolver, authority)) = &self.server_name {
+         let mut headers = HeaderMap::new();
+         headers.insert(HOST, authority.clone());
+         builder = builder
+            .dns_resolver(resolver.clone())
+            .default_headers(headers)
+            .http1_only();
+      };
+
+      builder
+   }
+}
+
+fn override_server_name(
+   server: &str,
+   server_name: &str,
+) -> Result<(Box<str>, ServerNameResolver, HeaderValue), ConfigError>
+{
+   let invalid = |reason: String| ConfigError::InvalidServer {
+      server: server.into(),
+      reason: reason.into(),
+   };
+
+   let mut url = Url::parse(server).map_err(|e| invalid(e.to_string()))?;
+   let target = url.host_str().ok_or_else(|| invalid("missing host".into()))?;
+   let authority = match url.port() {
+      Some(port) => format!("{target}:{port}"),
+      None => target.to_owned(),
+   };
+   let authority = HeaderValue::from_str(&authority).map_err(|e| invalid(e.to_string()))?;
+
+   let resolver = ServerNameResolver {
+      server_name: server_name.into(),
+      target: target.trim_start_matches('[').trim_end_matches(']').into(),
+      port: url.port_or_known_default().unwrap_or(443),
+   };
+
+   url.set_host(Some(server_name))
+      .map_err(|e| invalid(format!("tls-server-name {server_name}: {e}")))?;
+
+   let host = url.as_str().trim_end_matches('/').into();
+   Ok((host, resolver, authority))
+}
+
+/// Resolves `tls-server-name` to the address of the configured server, and every other name
+/// normally, without blocking the runtime.
+struct ServerNameResolver
+{
+   server_name: Box<str>,
+   target: Box<str>,
+   port: u16,
+}
+
+impl Resolve for ServerNameResolver
+{
+   fn resolve(&self, name: Name) -> Resolving
+   {
+      let (host, port) = match name.as_str() == &*self.server_name {
+         true => (self.target.to_string(), self.port),
+         false => (name.as_str().to_owned(), 0),
+      };
+
+      Box::pin(async move {
+         let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port)).await?.collect();
+         let addresses: Addrs = Box::new(addresses.into_iter());
+         Ok(addresses)
+      })
+   }
+}
+
+fn root_certificate(cluster: &Cluster) -> Result<Option<Certificate>, ConfigError>
+{
+   let (field, pem) = match (&cluster.certificate_authority_data, &cluster.certificate_authority) {
+      (Some(data), _) => ("certificate-authority-data", decode("certificate-authority-data", data)?),
+      (None, Some(path)) => ("certificate-authority", read("certificate-authority", path)?),
+      (None, None) => return Ok(None),
+   };
+
+   let certificate = Certificate::from_pem(&pem).map_err(|error| ConfigError::Certificate { field, error })?;
+   Ok(Some(certificate))
+}
+
+fn identity(user: &AuthInfo) -> Result<Option<Identity>, ConfigError>
+{
+   let certificate = match (&user.client_certificate_data, &user.client_certificate) {
+      (Some(data), _) => Some(decode("client-certificate-data", data)?),
+      (None, Some(path)) => Some(read("client-certificate", path)?),
+      (None, None) => None,
+   };
+
+   let key = match (&user.client_key_data, &user.client_key) {
+      (Some(data), _) => Some(decode("client-key-data", data)?),
+      (None, Some(path)) => Some(read("client-key", path)?),
+      (None, None) => None,
+   };
+
+   let (certificate, key) = match (certificate, key) {
+      (Some(certificate), Some(key)) => (certificate, key),
+      (Some(_), None) => return Err(ConfigError::MissingField("client-key")),
+      (None, Some(_)) => return Err(ConfigError::MissingField("client-certificate")),
+      (None, None) => return Ok(None),
+   };
+
+   let mut pem = certificate;
+   pem.push(b'\n');
+   pem.extend_from_slice(&key);
+
+   let identity = Identity::from_pem(&pem).map_err(|error| ConfigError::Certificate {
+      field: "client-certificate/client-key",
+      error,
+   })?;
+
+   Ok(Some(identity))
+}
+
+fn decode(field: &'static str, data: &str) -> Result<Vec<u8>, ConfigError>
+{
+   STANDARD
+      .decode(data.trim())
+      .map_err(|error| ConfigError::Base64 { field, error })
+}
+
+fn read(field: &'static str, path: &str) -> Result<Vec<u8>, ConfigError>
+{
+   std::fs::read(path).map_err(|error| ConfigError::File {
+      field,
+      path: path.into(),
+      error,
+   })
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn server_name_replaces_the_url_host_but_not_the_host_header()
+   {
+      let (host, resolver, authority) =
+         override_server_name("https://10.0.0.1:6443", "kubernetes.default").unwrap();
+
+      assert_eq!(&*host, "https://kubernetes.default:6443");
+      assert_eq!(authority, "10.0.0.1:6443");
+      assert_eq!(&*resolver.target, "10.0.0.1");
+      assert_eq!(resolver.port, 6443);
+   }
+
+   #[test]
+   fn server_name_does_not_resolve_the_server_up_front()
+   {
+      let (host, resolver, authority) =
+         override_server_name("https://api.invalid", "kubernetes.default").unwrap();
+
+      assert_eq!(&*host, "https://kubernetes.default");
+      assert_eq!(authority, "api.invalid");
+      assert_eq!(resolver.port, 443);
+   }
+
+   #[tokio::test]
+   async fn resolves_the_server_name_to_the_server()
+   {
+      let (_, resolver, _) = override_server_name("https://127.0.0.1:6443", "kubernetes.default").unwrap();
+      let name: Name = "kubernetes.default".parse().unwrap();
+      let addresses: Vec<SocketAddr> = resolver.resolve(name).await.unwrap().collect();
+
+      assert_eq!(addresses, vec!["127.0.0.1:6443".parse().unwrap()]);
+   }
+}
@@ -1,8 +1,8 @@
-use k8s_openapi::{List, api::core::v1::Pod as JsonPod};
+use k8s_openapi::api::core::v1::Pod as JsonPod;
 
-use crate::client::CAdvisorDaemonSetMetadata;
+use crate::client::{Api, CAdvisorDaemonSetMetadata, ListParams};
 
-use super::{APIError, Base, CAdvisorPods, errors, response_into_error, parse_json_pod};
+use super::{APIError, Base, CAdvisorPods, errors, parse_json_pod};
 
 
 pub async fn get_daemon_set_pods(
@@ -11,19 +11,14 @@ pub async fn get_daemon_set_pods(
 ) -> Result<CAdvisorPods, APIError>
 {
    let CAdvisorDaemonSetMetadata {
-      value,
-      key,
+      selector,
       namespace,
+      ..
    } = daemon_set;
 
-   let endpoint = format!("/api/v1/namespaces/{namespace}/pods?labelSelector={key}={value}");
-
-   let response = {
-      let response = client.get(endpoint).send().await?;
-      response_into_error(response).await?
-   };
-
-   let pods = response.json::<List<JsonPod>>().await?;
+   let api = Api::<JsonPod>::namespaced(client.clone(), namespace);
+   let params = ListParams::default().labels(selector.clone());
+   let pods = api.list_all(&params).await?;
    let mut set = Vec::new();
 
    let version = pods
@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+
+use super::Uid;
+
+/// A controller or other object listed in a pod's `metadata.ownerReferences`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner
+{
+   pub kind: Box<str>,
+   pub name: Box<str>,
+   pub uid: Uid,
+   pub controller: bool,
+}
+
+/// The state of a container as last reported in `status.containerStatuses`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ContainerState
+{
+   #[default]
+   Unknown,
+   Waiting
+   {
+      reason: Option<Box<str>>,
+   },
+   Running,
+   Terminated
+   {
+      reason: Option<Box<str>>,
+      exit_code: i32,
+   },
+}
+
+impl std::fmt::Display for ContainerState
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Unknown => write!(f, "unknown"),
+         Self::Waiting { reason: None } => write!(f, "waiting"),
+         Self::Waiting { reason: Some(reason) } => write!(f, "waiting ({reason})"),
+         Self::Running => write!(f, "running"),
+         Self::Terminated { reason: None, exit_code } => write!(f, "terminated ({exit_code})"),
+         Self::Terminated {
+            reason: Some(reason),
+            exit_code,
+         } => write!(f, "terminated ({reason}, {exit_code})"),
+      }
+   }
+}
+
+/// CPU in cores and memory in bytes, as given in a container's requests or limits.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Resources
+{
+   pub cpu: Option<f64>,
+   pub memory: Option<f64>,
+}
+
+/// A container of the pod's spec, joined with its status when there is one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Container
+{
+   pub name: Box<str>,
+   pub ready: bool,
+   pub restart_count: i32,
+   pub state: ContainerState,
+   pub requests: Resources,
+   pub limits: Resources,
+}
+
+/// The parts of a `Pod` a `DaemonSetEvent` reports as changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PodField
+{
+   Ready,
+   NodeName,
+   HostIp,
+   Phase,
+   Labels,
+   Owners,
+   Containers,
+   RestartCount,
+   ContainerState,
+   Resources,
+}
+
+impl std::fmt::Display for PodField
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      let field = match self {
+         Self::Ready => "ready",
+         Self::NodeName => "node name",
+         Self::HostIp => "host ip",
+         Self::Phase => "phase",
+         Self::Labels => "labels",
+         Self::Owners => "owners",
+         Self::Containers => "containers",
+         Self::RestartCount => "restart count",
+         Self::ContainerState => "container state",
+         Self::Resources => "resources",
+      };
+
+      write!(f, "{field}")
+   }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pod
+{
+   pub uid: Uid,
+   pub namespace: Box<str>,
+   pub name: Box<str>,
+   pub status: bool,
+   pub node_name: Option<Box<str>>,
+   pub host_ip: Option<Box<str>>,
+   pub phase: Option<Box<str>>,
+   pub labels: BTreeMap<String, String>,
+   pub owners: Vec<Owner>,
+   pub containers: Vec<Container>,
+}
+
+impl Pod
+{
+   pub fn new(uid: Uid, namespace: Box<str>, name: Box<str>, status: bool) -> Self
+   {
+      Self {
+         uid,
+         namespace,
+         name,
+         status,
+         ..Default::default()
+      }
+   }
+
+   pub fn container(&self, name: &str) -> Option<&Container>
+   {
+      self.containers.iter().find(|container| container.name.as_ref() == name)
+   }
+
+   /// The owner that manages this pod, e.g. its DaemonSet or ReplicaSet.
+   pub fn controller(&self) -> Option<&Owner>
+   {
+      self.owners.iter().find(|owner| owner.controller)
+   }
+
+   pub fn restart_count(&self) -> i32
+   {
+      self.containers.iter().map(|container| container.restart_count).sum()
+   }
+
+   /// The fields that differ between `self` and a newer version of the same pod.
+   ///
+   /// Containers are matched by name; adding or removing one is reported as `Containers`
+   /// rather than as changes to the remaining ones.
+   pub fn changes(&self, new: &Pod) -> Vec<PodField>
+   {
+      let mut changes = Vec::new();
+
+      if self.status != new.status {
+         changes.push(PodField::Ready);
+      };
+      if self.node_name != new.node_name {
+         changes.push(PodField::NodeName);
+      };
+      if self.host_ip != new.host_ip {
+         changes.push(PodField::HostIp);
+      };
+      if self.phase != new.phase {
+         changes.push(PodField::Phase);
+      };
+      if self.labels != new.labels {
+         changes.push(PodField::Labels);
+      };
+      if self.owners != new.owners {
+         changes.push(PodField::Owners);
+      };
+
+      let same_containers = self.containers.len() == new.containers.len()
+         && new.containers.iter().all(|container| self.container(&container.name).is_some());
+
+      if !same_containers {
+         changes.push(PodField::Containers);
+         return changes;
+      };
+
+      let pairs = || {
+         new.containers
+            .iter()
+            .filter_map(|new| self.container(&new.name).map(|old| (old, new)))
+      };
+
+      if pairs().any(|(old, new)| old.restart_count != new.restart_count) {
+         changes.push(PodField::RestartCount);
+      };
+      if pairs().any(|(old, new)| old.state != new.state || old.ready != new.ready) {
+         changes.push(PodField::ContainerState);
+      };
+      if pairs().any(|(old, new)| old.requests != new.requests || old.limits != new.limits) {
+         changes.push(PodField::Resources);
+      };
+
+      changes
+   }
+}
+
+impl std::fmt::Display for Pod
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write!(f, "Pod (name: {}, uid: {}, status: {}", self.name, self.uid, self.status)?;
+
+      if let Some(node_name) = &self.node_name {
+         write!(f, ", node: {node_name}")?;
+      };
+      if let Some(phase) = &self.phase {
+         write!(f, ", phase: {phase}")?;
+      };
+
+      write!(f, ", restarts: {})", self.restart_count())
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn container(name: &str) -> Container
+   {
+      Container {
+         name: name.into(),
+         ready: true,
+         state: ContainerState::Running,
+         ..Default::default()
+      }
+   }
+
+   fn pod() -> Pod
+   {
+      let mut pod = Pod::new("uid".into(), "kube-system".into(), "cadvisor-x".into(), true);
+      pod.node_name = Some("node-0".into());
+      pod.host_ip = Some("10.0.0.1".into());
+      pod.phase = Some("Running".into());
+      pod.labels = [("app".to_string(), "cadvisor".to_string())].into();
+      pod.containers = vec![container("cadvisor"), container("sidecar")];
+      pod
+   }
+
+   #[test]
+   fn an_unchanged_pod_has_no_changes()
+   {
+      assert_eq!(pod().changes(&pod()), []);
+   }
+
+   #[test]
+   fn each_field_is_reported()
+   {
+      type Change = fn(&mut Pod);
+      let cases: [(Change, PodField); 11] = [
+         (|pod| pod.status = false, PodField::Ready),
+         (|pod| pod.node_name = Some("node-1".into()), PodField::NodeName),
+         (|pod| pod.host_ip = None, PodField::HostIp),
+         (|pod| pod.phase = Some("Failed".into()), PodField::Phase),
+         (|pod| drop(pod.labels.insert("tier".into(), "web".into())), PodField::Labels),
+         (
+            |pod| {
+               pod.owners.push(Owner {
+                  kind: "DaemonSet".into(),
+                  name: "cadvisor".into(),
+                  uid: "owner".into(),
+                  controller: true,
+               })
+            },
+            PodField::Owners,
+         ),
+         (|pod| pod.containers[1].restart_count = 1, PodField::RestartCount),
+         (
+            |pod| pod.containers[0].state = ContainerState::Waiting { reason: Some("CrashLoopBackOff".into()) },
+            PodField::ContainerState,
+         ),
+         (|pod| pod.containers[0].ready = false, PodField::ContainerState),
+         (|pod| pod.containers[0].requests.cpu = Some(0.1), PodField::Resources),
+         (|pod| pod.containers[1].limits.memory = Some(1e8), PodField::Resources),
+      ];
+
+      for (change, field) in cases {
+         let mut new = pod();
+         change(&mut new);
+         assert_eq!(pod().changes(&new), [field], "expected only {field}");
+      }
+   }
+
+   #[test]
+   fn containers_are_matched_by_name()
+   {
+      // the same containers in another order
+      let mut new = pod();
+      new.containers.reverse();
+      assert_eq!(pod().changes(&new), []);
+
+      // a restarted container changes both its count and its state
+      let mut new = pod();
+      new.containers[0].restart_count = 2;
+      new.containers[0].state = ContainerState::Terminated {
+         reason: Some("Error".into()),
+         exit_code: 1,
+      };
+      assert_eq!(pod().changes(&new), [PodField::RestartCount, PodField::ContainerState]);
+   }
+
+   #[test]
+   fn added_or_removed_containers_hide_their_other_changes()
+   {
+      let mut new = pod();
+      new.containers.push(container("debug"));
+      new.containers[0].restart_count = 1;
+      assert_eq!(pod().changes(&new), [PodField::Containers]);
+
+      let mut new = pod();
+      new.containers[1] = container("renamed");
+      new.phase = Some("Pending".into());
+      assert_eq!(pod().changes(&new), [PodField::Phase, PodField::Containers]);
+   }
+}
@@ -1,28 +1,29 @@
-use std::{collections::HashSet, time::Duration};
+use k8s_openapi::{api::core::v1::Pod as JsonPod, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use tokio_util::sync::CancellationToken;
 
-use super::{APIError, Base, CAdvisorDaemonSetMetadata, CAdvisorPods, response_into_error, Pod, get_daemon_set_pods, parse_json_pod};
+use super::{APIError, Base, CAdvisorDaemonSetMetadata, CAdvisorPods, Pod, PodField, parse_json_pod};
+use crate::client::watcher::Sender;
+use crate::client::{Api, ListParams, ResourceEvent, ResourceWatcher, Watcher, WatcherConfig, WatcherError, WatcherEvent};
 
-use futures::{StreamExt, future::FutureExt};
-use futures_core::stream::Stream;
 
-use bytes::Bytes;
-use k8s_openapi::{api::core::v1::Pod as JsonPod, apimachinery::pkg::apis::meta::v1::{WatchEvent}};
-
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventKind
 {
    Created,
    Deleted,
    Paused,
    Resumed,
+   /// Some field other than the Ready condition changed.
+   Updated,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DaemonSetEvent
 {
    pub pod: Pod,
    pub kind: EventKind,
+   /// What changed since the previous version of the pod; empty when created or deleted.
+   pub changed: Vec<PodField>,
 }
 
 
@@ -35,72 +36,21 @@ impl std::fmt::Display for DaemonSetEvent {
             writeln!(f, "created - {}", running)
          },
          EventKind::Deleted => writeln!(f, "deleted"),
-         EventKind::Paused => writeln!(f, "paused"),
-         EventKind::Resumed => writeln!(f, "resumed"),
+         EventKind::Paused => write_changes(f, "paused", &self.changed),
+         EventKind::Resumed => write_changes(f, "resumed", &self.changed),
+         EventKind::Updated => write_changes(f, "updated", &self.changed),
       }
    }
 }
 
-use tokio::sync::{mpsc, oneshot};
-
-#[derive(Debug)]
-pub struct Watcher<Resource, Data>
-{
-   terminator: oneshot::Sender<()>,
-   receiver: mpsc::Receiver<Result<Data, WatcherError>>,
-   handle: tokio::task::JoinHandle<()>,
-   phantom: std::marker::PhantomData<Resource>,
-}
-
-#[derive(Debug)]
-pub enum WatcherErrorKind
+fn write_changes(f: &mut std::fmt::Formatter<'_>, kind: &str, changed: &[PodField]) -> std::fmt::Result
 {
-   StreamRetrieveError,
-   ReconilationError,
-   StreamNextError,
-   Termination,
-}
-
-#[derive(Debug)]
-pub struct WatcherError
-{
-   cause: WatcherErrorKind,
-   error: APIError,
-}
-
-impl WatcherError
-{
-   fn recon(error: APIError) -> Self
-   {
-      Self {
-         cause: WatcherErrorKind::ReconilationError,
-         error,
-      }
-   }
-
-   fn stream(error: APIError) -> Self
-   {
-      Self {
-         cause: WatcherErrorKind::StreamRetrieveError,
-         error,
-      }
-   }
-
-   fn next(error: APIError) -> Self
-   {
-      Self {
-         cause: WatcherErrorKind::StreamNextError,
-         error,
-      }
-   }
-
-   fn terminate() -> Self
-   {
-      Self {
-         cause: WatcherErrorKind::Termination,
-         error: APIError::WatcherTermination,
-      }
+   write!(f, "{kind}")?;
+   for (i, field) in changed.iter().enumerate() {
+      let separator = if i == 0 { " - " } else { ", " };
+      write!(f, "{separator}{field}")?;
    }
+   writeln!(f)
 }
 
 impl Watcher<CAdvisorDaemonSetMetadata, DaemonSetEvent>
@@ -109,381 +59,126 @@ impl Watcher<CAdvisorDaemonSetMetadata, DaemonSetEvent>
       client: Base,
       daemon_set: CAdvisorDaemonSetMetadata,
       state: CAdvisorPods,
-      duration: Duration,
+      config: WatcherConfig,
    ) -> Self
    {
+      let api = Api::<JsonPod>::namespaced(client, &daemon_set.namespace);
+      let params = ListParams::default().labels(daemon_set.selector.clone());
+      let known = state.pods.iter().map(known_pod).collect();
+      let pods = Watcher::resume(api, params, known, &state.version, config);
 
-      let (terminator, kill_signal) = oneshot::channel::<()>();
-      let (sender, receiver) = mpsc::channel(100);
-      let handle = tokio::spawn(watch_daemon_set_pods(client, state, daemon_set, duration, sender, kill_signal));
-
-      Self {
-         terminator,
-         receiver,
-         handle,
-         phantom: std::marker::PhantomData,
-      }
-   }
-
-   pub async fn next(&mut self) -> Result<DaemonSetEvent, WatcherError> {
-      match self.receiver.recv().await {
-         Some(Ok(v)) => Ok(v),
-         Some(Err(e)) => Err(e),
-         None => Err(WatcherError::next(APIError::ChannelSenderDropped)),
-      }
-   }
-
-   pub async fn kill(self) -> Result<(), WatcherError> {
-      match self.terminator.send(()) {
-         Ok(_) => Ok(()),
-         Err(_) => Err(WatcherError::terminate()),
-      }
+      Self::spawn(|sender, kill_signal| watch_daemon_set_pods(pods, state, sender, kill_signal))
    }
 }
 
-
-pub async fn reconcile(
-   client: &Base,
-   daemon_set: &CAdvisorDaemonSetMetadata,
-   old_state: &mut CAdvisorPods,
-) -> Result<(CAdvisorPods, Vec<DaemonSetEvent>), WatcherError>
+/// The part of a pod the watcher needs to recognise it in a relist.
+///
+/// Without a resourceVersion every listed pod reads as modified, which `apply_event`
+/// only reports when one of the fields `Pod` keeps actually changed.
+fn known_pod(pod: &Pod) -> JsonPod
 {
-   println!("reconcilation running");
-
-
-   let mut events: Vec<DaemonSetEvent> = vec![];
-
-   let new_state = get_daemon_set_pods(client, daemon_set)
-      .await
-      .map_err(|e| WatcherError::recon(e))?;
-
-   let uids: HashSet<_> = new_state.uids().chain(old_state.uids()).collect();
-
-   for uid in uids {
-      let event = match (new_state.get_by_uid(uid), old_state.get_by_uid(uid)) {
-         (Some(new), Some(old)) => {
-            if new.status == old.status {
-               continue;
-            };
-
-            let kind = if new.status {
-               EventKind::Resumed
-            } else {
-               EventKind::Paused
-            };
-
-            DaemonSetEvent {
-               pod: new.clone(),
-               kind,
-            }
-         }
-         (None, Some(old)) => DaemonSetEvent {
-            pod: old.clone(),
-            kind: EventKind::Deleted,
-         },
-         (Some(new), None) => DaemonSetEvent {
-            pod: new.clone(),
-            kind: EventKind::Created,
-         },
-         _ => unreachable!(),
-      };
-
-      events.push(event);
+   JsonPod {
+      metadata: ObjectMeta {
+         uid: Some(pod.uid.to_string()),
+         name: Some(pod.name.to_string()),
+         namespace: Some(pod.namespace.to_string()),
+         ..Default::default()
+      },
+      ..Default::default()
    }
-
-   Ok((new_state, events))
 }
 
-
-async fn get_stream(
-   client: &Base,
-   endpoint: &str,
-) -> Result<impl Stream<Item = Result<Bytes, reqwest::Error>>, WatcherError>
+/// The event for a pod that may have changed, if any of the fields `Pod` keeps did.
+///
+/// A change of the Ready condition is reported as paused or resumed, along with whatever
+/// else changed in the same update.
+fn modification(old: &Pod, new: Pod) -> Option<DaemonSetEvent>
 {
-   let mut retry_allowance = 5;
-
-   let stream = loop {
-      // first make the request
-      let response = client
-         .get(endpoint)
-         .send()
-         .await
-         .map_err(|e| WatcherError::stream(e.into()));
-      let response = match response {
-         Err(e) => {
-            if retry_allowance == 0 {
-               return Err(e);
-            };
-
-            retry_allowance -= 1;
-            continue;
-         }
-         Ok(response) => response,
-      };
-
-      // check if the response was error
-      let response = response_into_error(response)
-         .await
-         .map_err(|e| WatcherError::stream(e.into()));
-      let error = match response {
-         Ok(response) => break response.bytes_stream(),
-         Err(e) => e,
-      };
+   let changed = old.changes(&new);
 
-      if retry_allowance == 0 {
-         return Err(error);
-      };
-
-      retry_allowance -= 1;
+   let kind = match (changed.contains(&PodField::Ready), new.status) {
+      _ if changed.is_empty() => return None,
+      (true, true) => EventKind::Resumed,
+      (true, false) => EventKind::Paused,
+      (false, _) => EventKind::Updated,
    };
 
-   Ok(stream)
+   Some(DaemonSetEvent { pod: new, kind, changed })
 }
 
-
-
-// fn parse_pod_object(pod: JsonPod) -> Result<Pod, APIError> {
-//    let JsonPod {
-//       metadata, status, ..
-//    } = pod;
-//
-//
-//    let ObjectMeta {
-//       uid, name, ..
-//    } = metadata;
-//
-//    let uid = match uid {
-//       Some(uid) => uid,
-//       None => return Err(errors::UID),
-//    };
-//
-//    let name = match name {
-//       Some(name) => name,
-//       None => return Err(errors::NAME),
-//    };
-//
-//
-//
-//    let status = match status
-//       .ok_or(errors::STATUS)
-//       .and_then( |x| x.conditions.ok_or(errors::CONDITION))
-//       .and_then(|x|
-//           x.iter()
-//          .find_map(|status| 
-//             if status.type_ != "Ready" {
-//                None
-//             } else {
-//                if status.status == "True" {
-//                   Some(true)
-//                } else {
-//                   Some(false)
-//                }
-//             }
-//          ).ok_or(errors::READY_CONDITION)
-//       ) {
-//          Ok(status) => status,
-//          Err(e) => {
-//             println!("got {e:?} in parse but fell back to false");
-//             false
-//          },
-//
-//       };
-//
-//
-//    Ok(Pod::new(uid.into(), name.into(), status))
-// }
-       
-
-
-async fn get_events(
-   stream: &mut (impl Stream<Item = Result<Bytes, reqwest::Error>> + Unpin),
-   event_builder: &mut Vec<u8>,
-) -> Result<Option<Vec<WatchEvent<JsonPod>>>, WatcherError>
+/// Applies a pod event to `state`, returning what changed.
+///
+/// Events that do not match `state`, as can happen around a relist, are turned into the
+/// change they imply rather than being trusted blindly.
+fn apply_event(state: &mut CAdvisorPods, event: ResourceEvent<JsonPod>) -> Result<Option<DaemonSetEvent>, APIError>
 {
-   let mut events = vec![];
+   let event = match event {
+      ResourceEvent::Added(pod) | ResourceEvent::Modified(pod) => {
+         let new = parse_json_pod(pod, "next")?;
+
+         match state.insert(new.clone()) {
+            Some(old) => modification(&old, new),
+            None => Some(DaemonSetEvent {
+               pod: new,
+               kind: EventKind::Created,
+               changed: Vec::new(),
+            }),
+         }
+      },
+      ResourceEvent::Deleted(pod) => {
+         let pod = parse_json_pod(pod, "next")?;
 
-   let bytes = match stream.next().await {
-      None => return Ok(None),
-      Some(Err(e)) => return Err(WatcherError::next(e.into())),
-      Some(Ok(bytes)) => bytes,
+         state.remove(&pod).map(|_| DaemonSetEvent {
+            pod,
+            kind: EventKind::Deleted,
+            changed: Vec::new(),
+         })
+      },
+      ResourceEvent::Bookmark(version) => {
+         state.version = version;
+         None
+      },
    };
 
-   for byte in bytes {
-      if byte == b'\n' {
-         let bytes = std::mem::take(event_builder);
-         let event: WatchEvent<JsonPod> = serde_json::from_slice(&bytes).map_err(|e| WatcherError::next(e.into()))?;
-         event_builder.clear();
-         events.push(event);
-         continue;
-      };
-
-      event_builder.push(byte);
-   }
-
-   Ok(Some(events))
+   Ok(event)
 }
 
-
-pub async fn watch_daemon_set_pods(
-   client: Base,
+/// Turns the pod watcher's events into cadvisor pod events until killed.
+///
+/// Like the pod watcher's own errors, a pod that cannot be parsed ends the watch with its
+/// error.
+async fn watch_daemon_set_pods(
+   mut pods: ResourceWatcher<JsonPod>,
    mut state: CAdvisorPods,
-   daemon_set: CAdvisorDaemonSetMetadata,
-   duration: Duration,
-   sender: mpsc::Sender<Result<DaemonSetEvent, WatcherError>>,
-   kill_signal: oneshot::Receiver<()>,
+   sender: Sender<DaemonSetEvent>,
+   kill_signal: CancellationToken,
 )
 {
-   let seconds = duration.as_secs();
-   let kill_signal = kill_signal.shared();
-
-   let mut event_builder = vec![];
-
-   'reconnection: loop {
-      let endpoint = format!(
-         "/api/v1/namespaces/{}/pods?labelSelector={}={}&watch=true&resourceVersion={}&timeoutSeconds={}",
-         daemon_set.namespace,
-         daemon_set.key,
-         daemon_set.value,
-         state.version,
-         seconds,
-      );
-
-
-      let stream = tokio::select! {
-            _ = kill_signal.clone() => return,
-            stream = get_stream(&client, &endpoint) => stream,
+   loop {
+      let event = tokio::select! {
+         _ = kill_signal.cancelled() => return,
+         event = pods.next_event() => event,
       };
 
-      let mut stream = match stream {
-         Ok(stream) => stream,
+      let event = match event {
+         Ok(WatcherEvent::Health(health)) => WatcherEvent::Health(health),
+         Ok(WatcherEvent::Resynced(resync)) => WatcherEvent::Resynced(resync),
+         Ok(WatcherEvent::Event(event)) => match apply_event(&mut state, event) {
+            Ok(Some(event)) => WatcherEvent::Event(event),
+            Ok(None) => continue,
+            Err(e) => {
+               let _ = sender.send(Err(WatcherError::next(e))).await;
+               return;
+            },
+         },
          Err(e) => {
             let _ = sender.send(Err(e)).await;
             return;
          },
       };
 
-      
-      loop {
-
-         let events = tokio::select! {
-            _ = kill_signal.clone() => return,
-            events = get_events(&mut stream, &mut event_builder) => events,
-         };
-
-         let events = match events {
-            Err(e) => {
-               let _ = sender.send(Err(e)).await;
-               return;
-            },
-            Ok(None) => {
-
-               let (new_state, events) = match reconcile(&client, &daemon_set, &mut state).await {
-                  Err(e) => {
-                     let _ = sender.send(Err(e)).await;
-                     return;
-                  },
-                  Ok(output) => output,
-               };
-
-               state = new_state;
-
-               for event in events {
-                  println!("event sending: reconcilation");
-                  if sender.send(Ok(event)).await.is_err() {
-                     return
-                  };
-               };
-
-               continue 'reconnection;
-            },
-            Ok(Some(events)) => events,
-         };
-
-         
-         for event in events {
-            let event = match event {
-               WatchEvent::Added(pod) => {
-                  let pod = match parse_json_pod(pod, "next") {
-                     Ok(v) => v,
-                     Err(e) => {
-                        let _ = sender.send(Err(WatcherError::next(e.into()))).await;
-                        return;
-                     }
-                  };
-
-                  state.insert(pod.clone());
-
-                  DaemonSetEvent {
-                     pod: pod,
-                     kind: EventKind::Created,
-                  }
-               },
-
-               WatchEvent::Deleted(pod) => {
-                  let pod = match parse_json_pod(pod, "next") {
-                     Ok(v) => v,
-                     Err(e) => {
-                        let _ = sender.send(Err(WatcherError::next(e.into()))).await;
-                        return;
-                     },
-                  };
-
-                  state.remove(&pod);
-
-                  DaemonSetEvent {
-                     pod: pod,
-                     kind: EventKind::Deleted
-                  }
-               },
-
-               WatchEvent::Modified(pod) => {
-                  let new = match parse_json_pod(pod, "next") {
-                     Ok(v) => v,
-                     Err(e) => {
-                        let _ = sender.send(Err(WatcherError::next(e.into()))).await;
-                        return
-                     },
-                  };
-
-                  let old = match state.get_mut(&new) {
-                     Some(old) => old,
-                     _ => unreachable!(),
-                  };
-
-                  if old.status == new.status {
-                     continue;
-                  };
-
-                  old.status = new.status;
-                      
-                  let kind = if new.status {
-                     EventKind::Resumed
-                  } else {
-                     EventKind::Paused
-                  };
-
-
-                  DaemonSetEvent {
-                     pod: new,
-                     kind,
-                  }
-
-               },
-               _ => continue,
-            };
-
-
-            if sender.send(Ok(event)).await.is_err() {
-               return;
-            };
-
-         };
-
-
-
+      if sender.send(Ok(event)).await.is_err() {
+         return;
       };
-
-   };
-
-
+   }
 }
@@ -1,5 +1,7 @@
 use reqwest::Error;
+use tokio_tungstenite::tungstenite;
 use super::KubeErrorStatus;
+use super::auth::AuthError;
 
 #[derive(Debug)]
 pub enum JsonQuery
@@ -23,6 +25,45 @@ pub enum Resource
 #[derive(Debug)]
 pub enum ReceiverError {}
 
+impl std::fmt::Display for JsonQuery
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      let field = match self {
+         Self::NoUid => "metadata.uid",
+         Self::NoName => "metadata.name",
+         Self::NoNamespace => "metadata.namespace",
+         Self::NoMetaData => "metadata",
+         Self::NoResourceVersion => "metadata.resourceVersion",
+         Self::NoStatus => "status",
+         Self::NoCondition => "status.conditions",
+         Self::NoReadyCondition => "Ready condition",
+      };
+
+      write!(f, "object has no {field}")
+   }
+}
+
+impl std::error::Error for JsonQuery {}
+
+impl std::fmt::Display for Resource
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::DaemonSet => write!(f, "DaemonSet"),
+      }
+   }
+}
+
+impl std::fmt::Display for ReceiverError
+{
+   fn fmt(&self, _: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match *self {}
+   }
+}
+
 
 use prom_text_format_parser::ScrapeParseError;
 
@@ -30,12 +71,13 @@ use prom_text_format_parser::ScrapeParseError;
 pub enum APIError
 {
    Http(Error),
-   Response(KubeErrorStatus),
+   Auth(AuthError),
+   Response(Box<KubeErrorStatus>),
    JsonParse(serde_json::Error),
    JsonQuery(JsonQuery),
 
    Prometheus(ScrapeParseError),
-   CPUMetricNotFound,
+   MetricNotFound(Box<str>),
    NodeTopLevelContainerMetricNotFound,
    NodeTopLevelContainerMetricNoTimeStamp,
 
@@ -47,8 +89,126 @@ pub enum APIError
    ChannelReceiverDropped,
    ChannelSenderDropped,
    WatcherTermination,
+
+   Upgrade(Box<str>),
+   Protocol(Box<str>),
+   WebSocket(tungstenite::Error),
+   Io(std::io::Error),
+   PortForward
+   {
+      port: u16,
+      message: Box<str>,
+   },
+}
+
+impl APIError
+{
+   /// The `Status` the apiserver answered with, if the request got that far.
+   pub fn status(&self) -> Option<&KubeErrorStatus>
+   {
+      match self {
+         Self::Response(status) => Some(status),
+         _ => None,
+      }
+   }
+
+   fn has_status(&self, code: u16, reasons: &[&str]) -> bool
+   {
+      self.status().is_some_and(|status| {
+         status.code == Some(code as i32) || status.reason.as_deref().is_some_and(|reason| reasons.contains(&reason))
+      })
+   }
+
+   pub fn is_not_found(&self) -> bool
+   {
+      self.has_status(404, &["NotFound"])
+   }
+
+   /// The requested resourceVersion or continue token is older than the etcd compaction
+   /// window; the caller has to list again.
+   pub fn is_gone(&self) -> bool
+   {
+      self.has_status(410, &["Gone", "Expired"])
+   }
+
+   pub fn is_forbidden(&self) -> bool
+   {
+      self.has_status(403, &["Forbidden"])
+   }
+
+   pub fn is_unauthorized(&self) -> bool
+   {
+      self.has_status(401, &["Unauthorized"])
+   }
+
+   /// Transient failures where sending the same request again later may succeed:
+   /// throttling, apiserver timeouts and unavailability, and dropped connections.
+   pub fn is_retryable(&self) -> bool
+   {
+      match self {
+         Self::Http(e) => e.is_connect() || e.is_timeout() || e.is_body() || e.is_request(),
+         Self::Response(status) => {
+            let reason = status.reason.as_deref().unwrap_or_default();
+            matches!(status.code, Some(429 | 500 | 502 | 503 | 504))
+               || matches!(reason, "TooManyRequests" | "ServerTimeout" | "Timeout" | "ServiceUnavailable" | "InternalError")
+         },
+         _ => false,
+      }
+   }
+}
+
+impl std::fmt::Display for APIError
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Http(e) => write!(f, "request failed: {e}"),
+         Self::Auth(e) => write!(f, "authentication failed: {e}"),
+         Self::Response(status) => {
+            let message = status.message.as_deref().unwrap_or("request rejected");
+            write!(f, "apiserver: {message}")?;
+            match (&status.reason, status.code) {
+               (Some(reason), Some(code)) => write!(f, " ({reason}, {code})"),
+               (Some(reason), None) => write!(f, " ({reason})"),
+               (None, Some(code)) => write!(f, " ({code})"),
+               (None, None) => Ok(()),
+            }
+         },
+         Self::JsonParse(e) => write!(f, "invalid json: {e}"),
+         Self::JsonQuery(e) => write!(f, "{e}"),
+         Self::Prometheus(ScrapeParseError::Parse(e)) => write!(f, "invalid metrics scrape: {e}"),
+         Self::Prometheus(ScrapeParseError::Collect(e)) => write!(f, "invalid metrics scrape: {e:?}"),
+         Self::MetricNotFound(query) => write!(f, "scrape has no samples for {query}"),
+         Self::NodeTopLevelContainerMetricNotFound => write!(f, "scrape has no sample for the node's top level container"),
+         Self::NodeTopLevelContainerMetricNoTimeStamp => write!(f, "top level container sample has no timestamp"),
+         Self::WatcherEventReceiver { resource, issue } => write!(f, "{resource} watcher receiver: {issue}"),
+         Self::ChannelReceiverDropped => write!(f, "channel receiver dropped"),
+         Self::ChannelSenderDropped => write!(f, "channel sender dropped"),
+         Self::WatcherTermination => write!(f, "watcher already terminated"),
+         Self::Upgrade(reason) => write!(f, "websocket upgrade failed: {reason}"),
+         Self::Protocol(reason) => write!(f, "streaming protocol: {reason}"),
+         Self::WebSocket(e) => write!(f, "websocket: {e}"),
+         Self::Io(e) => write!(f, "io: {e}"),
+         Self::PortForward { port, message } => write!(f, "port-forward to port {port} failed: {message}"),
+      }
+   }
 }
 
+impl std::error::Error for APIError
+{
+   fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+   {
+      match self {
+         Self::Http(e) => Some(e),
+         Self::Auth(e) => Some(e),
+         Self::JsonParse(e) => Some(e),
+         Self::JsonQuery(e) => Some(e),
+         Self::WebSocket(e) => Some(e),
+         Self::Io(e) => Some(e),
+         _ => None,
+      }
+   }
+}
 
 impl From<ScrapeParseError> for APIError {
    fn from(value: ScrapeParseError) -> Self {
@@ -64,6 +224,14 @@ impl From<Error> for APIError
    }
 }
 
+impl From<AuthError> for APIError
+{
+   fn from(value: AuthError) -> Self
+   {
+      Self::Auth(value)
+   }
+}
+
 impl From<serde_json::Error> for APIError
 {
    fn from(value: serde_json::Error) -> Self
@@ -72,28 +240,70 @@ impl From<serde_json::Error> for APIError
    }
 }
 
+impl From<tungstenite::Error> for APIError
+{
+   fn from(value: tungstenite::Error) -> Self
+   {
+      Self::WebSocket(value)
+   }
+}
+
+impl From<std::io::Error> for APIError
+{
+   fn from(value: std::io::Error) -> Self
+   {
+      Self::Io(value)
+   }
+}
+
 impl From<KubeErrorStatus> for APIError
 {
    fn from(value: KubeErrorStatus) -> Self
    {
-      Self::Response(value)
+      Self::Response(Box::new(value))
    }
 }
 
 
 
+/// Turns an unsuccessful response into an error carrying its `Status`.
+///
+/// Bodies that are not a `Status`, such as the plain text of a proxy or load balancer in
+/// front of the apiserver, become one built from the HTTP status with the body as message.
 pub async fn response_into_error(response: reqwest::Response) -> Result<reqwest::Response, APIError>
 {
-   if response.status() == reqwest::StatusCode::OK {
+   if response.status().is_success() {
       return Ok(response);
    };
 
-   let error = match response.json::<KubeErrorStatus>().await {
-         Err(e) => e.into(),
-         Ok(status) => status.into(),
+   let code = response.status();
+   let body = response.bytes().await?;
+
+   let status = match serde_json::from_slice::<KubeErrorStatus>(&body) {
+      Ok(status) => status,
+      Err(_) => status_from_body(code, &body),
+   };
+
+   Err(status.into())
+}
+
+fn status_from_body(code: reqwest::StatusCode, body: &[u8]) -> KubeErrorStatus
+{
+   let text = String::from_utf8_lossy(body);
+   let text = text.trim();
+   let reason = code.canonical_reason().map(|reason| reason.replace(' ', ""));
+   let message = match text.is_empty() {
+      true => code.to_string(),
+      false => text.to_owned(),
    };
 
-   Err(error)
+   KubeErrorStatus {
+      code: Some(code.as_u16().into()),
+      message: Some(message),
+      reason,
+      status: Some("Failure".into()),
+      ..Default::default()
+   }
 }
 
 
@@ -108,3 +318,46 @@ pub mod errors {
    pub const READY_CONDITION: APIError = APIError::JsonQuery(JsonQuery::NoReadyCondition);
 }
 
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use tokio_tungstenite::tungstenite::http;
+
+   async fn error(status: u16, body: &'static str) -> APIError
+   {
+      let response = http::Response::builder().status(status).body(body).unwrap();
+      response_into_error(response.into()).await.unwrap_err()
+   }
+
+   #[tokio::test]
+   async fn status_bodies_are_kept()
+   {
+      let error = error(404, r#"{"kind":"Status","code":404,"reason":"NotFound","message":"pods \"a\" not found"}"#).await;
+
+      assert!(error.is_not_found());
+      assert_eq!(error.status().unwrap().message.as_deref(), Some("pods \"a\" not found"));
+   }
+
+   #[tokio::test]
+   async fn other_bodies_keep_the_http_status()
+   {
+      let error = error(503, "upstream connect error\n").await;
+      let status = error.status().unwrap();
+
+      assert!(error.is_retryable());
+      assert_eq!(status.code, Some(503));
+      assert_eq!(status.reason.as_deref(), Some("ServiceUnavailable"));
+      assert_eq!(status.message.as_deref(), Some("upstream connect error"));
+   }
+
+   #[tokio::test]
+   async fn empty_bodies_fall_back_to_the_status_line()
+   {
+      let error = error(410, "").await;
+
+      assert!(error.is_gone());
+      assert_eq!(error.status().unwrap().message.as_deref(), Some("410 Gone"));
+   }
+}
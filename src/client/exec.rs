@@ -0,0 +1,396 @@
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use k8s_openapi::api::core::v1::Pod as JsonPod;
+use tokio::io::{AsyncReadExt, DuplexStream};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use super::ws::{self, V4_CHANNEL, V5_CHANNEL};
+use super::{APIError, Api, Base, KubeErrorStatus, Pod};
+
+/// Bytes buffered in each of stdin, stdout and stderr before the other side has to read.
+const BUFFER: usize = 64 * 1024;
+
+/// Messages queued for stdout or stderr before the session waits for it to be read.
+const QUEUE: usize = 256;
+
+const STDIN: u8 = 0;
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+const ERROR: u8 = 3;
+/// v5 only: a message on it closes the channel named by its payload.
+const CLOSE: u8 = 255;
+
+/// A command running in a container, with its stdin, stdout and stderr.
+///
+/// Stdin is closed when it is dropped, which includes when `join` or `output` is called
+/// without taking it first. Apiservers that only speak v4 cannot be told, so there the
+/// command has to end without waiting for it. Dropping the `Exec` closes the session.
+#[derive(Debug)]
+pub struct Exec
+{
+   stdin: Option<DuplexStream>,
+   stdout: Option<DuplexStream>,
+   stderr: Option<DuplexStream>,
+   task: tokio::task::JoinHandle<Result<i32, APIError>>,
+   guard: DropGuard,
+}
+
+/// Everything a command wrote, and the code it exited with.
+#[derive(Debug, Clone)]
+pub struct ExecOutput
+{
+   pub stdout: Vec<u8>,
+   pub stderr: Vec<u8>,
+   pub code: i32,
+}
+
+impl Exec
+{
+   /// Runs the session over an already upgraded socket speaking `protocol`.
+   pub(crate) fn new<S>(socket: S, protocol: &str) -> Self
+   where
+      S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+   {
+      let (stdin, stdin_reader) = tokio::io::duplex(BUFFER);
+      let (stdout, stdout_writer) = tokio::io::duplex(BUFFER);
+      let (stderr, stderr_writer) = tokio::io::duplex(BUFFER);
+
+      let kill_signal = CancellationToken::new();
+      let guard = kill_signal.clone().drop_guard();
+      let close_stdin = protocol == V5_CHANNEL;
+      let task = tokio::spawn(run(socket, close_stdin, stdin_reader, stdout_writer, stderr_writer, kill_signal));
+
+      Self {
+         stdin: Some(stdin),
+         stdout: Some(stdout),
+         stderr: Some(stderr),
+         task,
+         guard,
+      }
+   }
+
+   pub fn take_stdin(&mut self) -> Option<DuplexStream>
+   {
+      self.stdin.take()
+   }
+
+   pub fn take_stdout(&mut self) -> Option<DuplexStream>
+   {
+      self.stdout.take()
+   }
+
+   pub fn take_stderr(&mut self) -> Option<DuplexStream>
+   {
+      self.stderr.take()
+   }
+
+   /// Waits for the command to finish, returning its exit code.
+   ///
+   /// Output that was not taken is discarded; a failure to run the command at all is
+   /// returned as the apiserver's `Status`.
+   pub async fn join(self) -> Result<i32, APIError>
+   {
+      let Self {
+         stdin,
+         stdout,
+         stderr,
+         task,
+         guard,
+      } = self;
+
+      drop((stdin, stdout, stderr));
+      let result = task.await;
+      drop(guard);
+
+      result.map_err(|e| APIError::Io(std::io::Error::other(e)))?
+   }
+
+   /// Closes stdin and collects whatever is left of stdout and stderr until the command
+   /// finishes, e.g. to read the result of `nproc`.
+   pub async fn output(mut self) -> Result<ExecOutput, APIError>
+   {
+      self.stdin = None;
+
+      let read = |stream: Option<DuplexStream>| async move {
+         let mut output = vec![];
+         if let Some(mut stream) = stream {
+            stream.read_to_end(&mut output).await?;
+         };
+         Ok::<_, APIError>(output)
+      };
+
+      let (stdout, stderr) = tokio::try_join!(read(self.stdout.take()), read(self.stderr.take()))?;
+      let code = self.join().await?;
+
+      Ok(ExecOutput { stdout, stderr, code })
+   }
+}
+
+/// Runs `command` in `container` of a pod, or its only container when `None`.
+pub async fn exec(base: &Base, pod: &Pod, container: Option<&str>, command: &[&str]) -> Result<Exec, APIError>
+{
+   let api = Api::<JsonPod>::namespaced(base.clone(), &pod.namespace);
+   let path = api.url_path(Some(&pod.name), Some("exec"));
+
+   let mut query: Vec<_> = command.iter().map(|&arg| ("command", arg.to_string())).collect();
+   if let Some(container) = container {
+      query.push(("container", container.to_string()));
+   };
+   for stream in ["stdin", "stdout", "stderr"] {
+      query.push((stream, "true".to_string()));
+   }
+
+   let (socket, protocol) = ws::connect(base, &path, &query, &[V5_CHANNEL, V4_CHANNEL]).await?;
+   Ok(Exec::new(socket, &protocol))
+}
+
+/// The exit code in the `Status` sent on the error channel, or the status itself as the
+/// error when the command could not be run.
+fn exit_code(status: KubeErrorStatus) -> Result<i32, APIError>
+{
+   if status.status.as_deref() == Some("Success") {
+      return Ok(0);
+   };
+
+   let code = (status.reason.as_deref() == Some("NonZeroExitCode"))
+      .then_some(status.details.as_ref())
+      .flatten()
+      .and_then(|details| details.causes.as_ref())
+      .and_then(|causes| causes.iter().find(|cause| cause.reason.as_deref() == Some("ExitCode")))
+      .and_then(|cause| cause.message.as_deref()?.parse().ok());
+
+   match code {
+      Some(code) => Ok(code),
+      None => Err(status.into()),
+   }
+}
+
+/// Moves stdin, stdout and stderr between the socket and the local streams until the
+/// socket closes, then reports the exit status the apiserver sent.
+///
+/// Stdout and stderr are written by tasks of their own, so one that is read slowly does
+/// not hold back the other until it falls `QUEUE` messages behind, when the session waits
+/// for it rather than losing output. One that was dropped stops receiving.
+async fn run<S>(
+   mut socket: S,
+   close_stdin: bool,
+   stdin: DuplexStream,
+   stdout: DuplexStream,
+   stderr: DuplexStream,
+   kill_signal: CancellationToken,
+) -> Result<i32, APIError>
+where
+   S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+   let mut stdin = ReaderStream::new(stdin);
+   let mut stdout = Some(ws::queue_writes(stdout, QUEUE));
+   let mut stderr = Some(ws::queue_writes(stderr, QUEUE));
+
+   let mut stdin_open = true;
+   let mut status = None;
+
+   loop {
+      tokio::select! {
+         _ = kill_signal.cancelled() => return Err(APIError::Protocol("exec session closed".into())),
+         message = socket.next() => {
+            let message = match message {
+               None | Some(Ok(Message::Close(_))) => break,
+               Some(message) => message?,
+            };
+
+            let Some((channel, data)) = ws::unframe(message) else {
+               continue;
+            };
+
+            let output = match channel {
+               STDOUT => &mut stdout,
+               STDERR => &mut stderr,
+               ERROR => {
+                  status = Some(serde_json::from_slice::<KubeErrorStatus>(&data)?);
+                  continue;
+               },
+               _ => continue,
+            };
+
+            tokio::select! {
+               _ = kill_signal.cancelled() => return Err(APIError::Protocol("exec session closed".into())),
+               _ = ws::write_queued(output, data) => (),
+            };
+         },
+         chunk = stdin.next(), if stdin_open => {
+            let sent = match chunk {
+               Some(Ok(data)) => socket.send(ws::frame(STDIN, &data)).await,
+               Some(Err(e)) => return Err(e.into()),
+               None => {
+                  stdin_open = false;
+                  match close_stdin {
+                     true => socket.send(ws::frame(CLOSE, &[STDIN])).await,
+                     false => Ok(()),
+                  }
+               },
+            };
+
+            // a command may exit without reading all of its stdin
+            if let Err(e) = sent {
+               if status.is_none() {
+                  return Err(e.into());
+               };
+               break;
+            };
+         },
+      }
+   }
+
+   match status {
+      Some(status) => exit_code(status),
+      None => Err(APIError::Protocol("exec ended without an exit status".into())),
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use std::time::Duration;
+
+   use bytes::Bytes;
+   use k8s_openapi::apimachinery::pkg::apis::meta::v1::{StatusCause, StatusDetails};
+   use tokio::io::AsyncWriteExt;
+
+   use super::*;
+   use crate::client::ws::stand_in::{self, StandIn, receive};
+
+   /// A session speaking `protocol` over an in-memory socket, and the apiserver's end of it.
+   async fn session(protocol: &str) -> (Exec, StandIn)
+   {
+      let (client, server) = stand_in::pair(BUFFER).await;
+      (Exec::new(client, protocol), server)
+   }
+
+   fn exited(code: &str) -> KubeErrorStatus
+   {
+      KubeErrorStatus {
+         status: Some("Failure".into()),
+         reason: Some("NonZeroExitCode".into()),
+         message: Some(format!("command terminated with non-zero exit code: {code}")),
+         details: Some(StatusDetails {
+            causes: Some(vec![StatusCause {
+               reason: Some("ExitCode".into()),
+               message: Some(code.into()),
+               ..Default::default()
+            }]),
+            ..Default::default()
+         }),
+         ..Default::default()
+      }
+   }
+
+   async fn finish(server: &mut StandIn, status: &KubeErrorStatus)
+   {
+      let status = serde_json::to_vec(status).unwrap();
+      server.send(ws::frame(ERROR, &status)).await.unwrap();
+      server.close(None).await.unwrap();
+   }
+
+   #[tokio::test]
+   async fn demultiplexes_stdout_and_stderr()
+   {
+      let (exec, mut server) = session(V5_CHANNEL).await;
+      server.send(ws::frame(STDOUT, b"out ")).await.unwrap();
+      server.send(ws::frame(STDERR, b"err")).await.unwrap();
+      server.send(ws::frame(STDOUT, b"put")).await.unwrap();
+      finish(&mut server, &exited("3")).await;
+
+      let output = tokio::time::timeout(Duration::from_secs(1), exec.output())
+         .await
+         .expect("output hung")
+         .unwrap();
+
+      assert_eq!(output.stdout, b"out put");
+      assert_eq!(output.stderr, b"err");
+      assert_eq!(output.code, 3);
+   }
+
+   #[tokio::test]
+   async fn v5_closes_stdin_on_its_channel()
+   {
+      let (mut exec, mut server) = session(V5_CHANNEL).await;
+      let mut stdin = exec.take_stdin().unwrap();
+      stdin.write_all(b"input").await.unwrap();
+      drop(stdin);
+
+      assert_eq!(receive(&mut server).await, Some((STDIN, Bytes::from_static(b"input"))));
+      assert_eq!(receive(&mut server).await, Some((CLOSE, Bytes::from_static(&[STDIN]))));
+
+      finish(&mut server, &KubeErrorStatus {
+         status: Some("Success".into()),
+         ..Default::default()
+      })
+      .await;
+      assert_eq!(exec.join().await.unwrap(), 0);
+   }
+
+   #[tokio::test]
+   async fn v4_cannot_close_stdin()
+   {
+      let (exec, mut server) = session(V4_CHANNEL).await;
+      let join = tokio::spawn(exec.join());
+
+      // stdin was dropped by join, which v4 has no message for
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      finish(&mut server, &exited("1")).await;
+      assert_eq!(receive(&mut server).await, None);
+
+      assert_eq!(join.await.unwrap().unwrap(), 1);
+   }
+
+   #[tokio::test]
+   async fn slow_reader_gets_all_of_stdout()
+   {
+      let (mut exec, mut server) = session(V5_CHANNEL).await;
+      let mut stdout = exec.take_stdout().unwrap();
+
+      // more than the stream and its queue hold together
+      let messages = QUEUE + 2 * BUFFER / 1024;
+      let sender = tokio::spawn(async move {
+         for _ in 0..messages {
+            server.send(ws::frame(STDOUT, &[7; 1024])).await.unwrap();
+         }
+         finish(&mut server, &exited("0")).await;
+         server
+      });
+
+      tokio::time::sleep(Duration::from_millis(100)).await;
+      let mut output = vec![];
+      tokio::time::timeout(Duration::from_secs(5), stdout.read_to_end(&mut output))
+         .await
+         .expect("stdout hung")
+         .unwrap();
+
+      assert_eq!(output.len(), messages * 1024);
+      assert_eq!(exec.join().await.unwrap(), 0);
+      drop(sender.await.unwrap());
+   }
+
+   #[test]
+   fn exit_code_comes_from_the_status()
+   {
+      let success = KubeErrorStatus {
+         status: Some("Success".into()),
+         ..Default::default()
+      };
+      assert_eq!(exit_code(success).unwrap(), 0);
+      assert_eq!(exit_code(exited("137")).unwrap(), 137);
+
+      let failed = KubeErrorStatus {
+         status: Some("Failure".into()),
+         reason: Some("InternalError".into()),
+         message: Some("container not found".into()),
+         ..Default::default()
+      };
+      match exit_code(failed) {
+         Err(APIError::Response(status)) => assert_eq!(status.message.as_deref(), Some("container not found")),
+         other => panic!("expected the status as error, got {other:?}"),
+      };
+   }
+}
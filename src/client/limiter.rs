@@ -0,0 +1,177 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Requests per second allowed by a client unless configured otherwise, as in kubectl;
+/// client-go itself defaults to 5.
+pub const DEFAULT_QPS: f64 = 50.0;
+
+/// Requests a client may fire at once before being held to `DEFAULT_QPS`, as in kubectl;
+/// client-go itself defaults to 10.
+pub const DEFAULT_BURST: u32 = 300;
+
+#[derive(Debug)]
+struct Bucket
+{
+   tokens: f64,
+   refilled: Instant,
+}
+
+/// Token bucket shared by every clone of a client so that all of its tasks together stay
+/// within `qps`, with up to `burst` requests let through back to back.
+#[derive(Debug)]
+pub struct RateLimiter
+{
+   qps: f64,
+   burst: f64,
+   bucket: Mutex<Bucket>,
+}
+
+impl Default for RateLimiter
+{
+   fn default() -> Self
+   {
+      Self::new(DEFAULT_QPS, DEFAULT_BURST)
+   }
+}
+
+impl RateLimiter
+{
+   pub fn new(qps: f64, burst: u32) -> Self
+   {
+      let burst = burst.max(1) as f64;
+
+      Self {
+         qps,
+         burst,
+         bucket: Mutex::new(Bucket {
+            tokens: burst,
+            refilled: Instant::now(),
+         }),
+      }
+   }
+
+   pub fn qps(&self) -> f64
+   {
+      self.qps
+   }
+
+   pub fn burst(&self) -> u32
+   {
+      self.burst as u32
+   }
+
+   /// Waits until a request may be sent.
+   ///
+   /// Callers take their token up front and the bucket may go negative, so waiters are
+   /// served in the order they arrived instead of racing for each refill.
+   pub async fn acquire(&self)
+   {
+      if self.qps <= 0.0 {
+         return;
+      };
+
+      let wait = {
+         let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+
+         let now = Instant::now();
+         let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+         bucket.tokens = (bucket.tokens + elapsed * self.qps).min(self.burst);
+         bucket.refilled = now;
+         bucket.tokens -= 1.0;
+
+         if bucket.tokens >= 0.0 {
+            return;
+         };
+
+         Duration::from_secs_f64(-bucket.tokens / self.qps)
+      };
+
+      tokio::time::sleep(wait).await;
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   /// How long `acquire` waited, in milliseconds of paused time.
+   async fn waited(limiter: &RateLimiter) -> u128
+   {
+      let start = Instant::now();
+      limiter.acquire().await;
+      start.elapsed().as_millis()
+   }
+
+   #[tokio::test(start_paused = true)]
+   async fn bursts_then_holds_to_qps()
+   {
+      let limiter = RateLimiter::new(10.0, 3);
+
+      for _ in 0..3 {
+         assert_eq!(waited(&limiter).await, 0);
+      }
+
+      assert_eq!(waited(&limiter).await, 100);
+      assert_eq!(waited(&limiter).await, 100);
+   }
+
+   #[tokio::test(start_paused = true)]
+   async fn refills_up_to_the_burst()
+   {
+      let limiter = RateLimiter::new(10.0, 3);
+      for _ in 0..3 {
+         limiter.acquire().await;
+      }
+
+      // a partial refill lets one request through
+      tokio::time::advance(Duration::from_millis(150)).await;
+      assert_eq!(waited(&limiter).await, 0);
+      assert_eq!(waited(&limiter).await, 50);
+
+      // idling for long refills no more than the burst
+      tokio::time::advance(Duration::from_secs(10)).await;
+      for _ in 0..3 {
+         assert_eq!(waited(&limiter).await, 0);
+      }
+      assert_eq!(waited(&limiter).await, 100);
+   }
+
+   #[tokio::test(start_paused = true)]
+   async fn waiters_queue_up_behind_each_other()
+   {
+      let limiter = std::sync::Arc::new(RateLimiter::new(10.0, 1));
+      limiter.acquire().await;
+
+      let start = Instant::now();
+      let waiters: Vec<_> = (0..3)
+         .map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+               limiter.acquire().await;
+               start.elapsed().as_millis()
+            })
+         })
+         .collect();
+
+      let mut done = vec![];
+      for waiter in waiters {
+         done.push(waiter.await.unwrap());
+      }
+      done.sort();
+
+      assert_eq!(done, [100, 200, 300]);
+   }
+
+   #[tokio::test(start_paused = true)]
+   async fn zero_qps_is_unlimited()
+   {
+      let limiter = RateLimiter::new(0.0, 1);
+
+      for _ in 0..10 {
+         assert_eq!(waited(&limiter).await, 0);
+      }
+   }
+}
@@ -1,17 +1,52 @@
+mod api;
+mod auth;
+#[allow(clippy::module_inception)]
 mod client;
 mod config;
 
 mod daemon_set;
 mod error;
+mod exec;
+mod limiter;
+mod retry;
 
 mod parse_json_pod;
+mod portforward;
+mod proxy;
+mod reflector;
+mod selector;
+mod watcher;
+mod ws;
+
+#[cfg(test)]
+mod temp_dir;
 
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
 
+pub use api::{Api, Deleted, ListParams, Patch};
+pub use auth::{Auth, AuthError};
 pub use client::{Base, KubeClient};
-pub use daemon_set::{CAdvisorDaemonSetMetadata, CAdvisorPods, get_daemon_set_pods, Watcher, WatcherError, DaemonSetEvent, EventKind, Pod};
+pub use config::{
+   AuthInfo, Cluster, Config, ConfigError, Context, ExecConfig, ExecEnvVar, Kubeconfig, NamedAuthInfo, NamedCluster,
+   NamedContext,
+};
+pub use daemon_set::{
+   CAdvisorDaemonSetMetadata, CAdvisorPods, Container, ContainerState, DaemonSetEvent, EventKind, Owner, Pod, PodField,
+   Resources, get_daemon_set_pods,
+};
 pub use error::{APIError, JsonQuery, response_into_error, errors};
+pub use exec::{Exec, ExecOutput};
+pub use limiter::RateLimiter;
 pub use parse_json_pod::parse_json_pod;
+pub use portforward::{LocalPortForward, PortForward};
+pub use proxy::{ProxyRequest, ProxyTarget, ProxyTargetError};
+pub use reflector::{NodeIndex, Snapshot, Store, Writer, pod_node, reflect, reflector};
+pub use retry::RetryPolicy;
+pub use selector::{Expression, FieldExpression, FieldSelector, LabelSelector};
+pub use watcher::{
+   Health, KillHandle, LagPolicy, ResourceEvent, ResourceWatcher, Resync, Subscription, Watcher, WatcherConfig,
+   WatcherError, WatcherErrorKind, WatcherEvent,
+};
 
 
 
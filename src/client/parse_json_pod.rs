@@ -1,7 +1,12 @@
-use super::{Pod, APIError, errors};
+use std::collections::BTreeMap;
+
+use super::{APIError, Container, ContainerState, Owner, Pod, Resources, errors};
 use k8s_openapi::{
-   api::core::v1::Pod as JsonPod,
-   apimachinery::pkg::apis::meta::v1::ObjectMeta,
+   api::core::v1::{
+      Container as JsonContainer, ContainerState as JsonContainerState, ContainerStatus, Pod as JsonPod, PodSpec,
+      PodStatus, ResourceRequirements,
+   },
+   apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::ObjectMeta},
 };
 
 
@@ -9,34 +14,33 @@ pub fn parse_json_pod(pod: JsonPod, context: &str) -> Result<Pod, APIError> {
 
    let JsonPod {
       metadata,
+      spec,
       status,
-      ..
    } = pod;
 
    let ObjectMeta {
       name,
       namespace,
       uid,
+      labels,
+      owner_references,
       ..
    } = metadata;
 
    let name = name.ok_or(errors::NAME)?;
    let uid = uid.ok_or(errors::UID)?;
    let namespace = namespace.ok_or(errors::NAMESPACE)?;
-   let status = match status
+   let ready = match status
+      .as_ref()
       .ok_or(errors::STATUS)
-      .and_then(|x| x.conditions.ok_or(errors::CONDITION))
+      .and_then(|x| x.conditions.as_ref().ok_or(errors::CONDITION))
       .and_then(|x| {
          x.iter()
             .find_map(|status| {
                if status.type_ != "Ready" {
                   None
                } else {
-                  if status.status == "True" {
-                     Some(true)
-                  } else {
-                     Some(false)
-                  }
+                  Some(status.status == "True")
                }
             })
             .ok_or(errors::READY_CONDITION)
@@ -47,6 +51,197 @@ pub fn parse_json_pod(pod: JsonPod, context: &str) -> Result<Pod, APIError> {
          false
       }
    };
-   
-   Ok(Pod::new(uid.into(), namespace.into(), name.into(), status))
+
+   let owners = owner_references
+      .unwrap_or_default()
+      .into_iter()
+      .map(|owner| Owner {
+         kind: owner.kind.into(),
+         name: owner.name.into(),
+         uid: owner.uid.into(),
+         controller: owner.controller.unwrap_or(false),
+      })
+      .collect();
+
+   let PodSpec {
+      node_name,
+      containers,
+      ..
+   } = spec.unwrap_or_default();
+
+   let PodStatus {
+      host_ip,
+      phase,
+      container_statuses,
+      ..
+   } = status.unwrap_or_default();
+
+   let containers = parse_containers(containers, container_statuses.unwrap_or_default());
+
+   let mut pod = Pod::new(uid.into(), namespace.into(), name.into(), ready);
+   pod.node_name = node_name.map(Into::into);
+   pod.host_ip = host_ip.map(Into::into);
+   pod.phase = phase.map(Into::into);
+   pod.labels = labels.unwrap_or_default();
+   pod.owners = owners;
+   pod.containers = containers;
+
+   Ok(pod)
+}
+
+/// Joins the spec's containers with their statuses by name, keeping the spec's order.
+fn parse_containers(containers: Vec<JsonContainer>, mut statuses: Vec<ContainerStatus>) -> Vec<Container>
+{
+   containers
+      .into_iter()
+      .map(|container| {
+         let (requests, limits) = parse_resources(container.resources);
+         let status = statuses
+            .iter()
+            .position(|status| status.name == container.name)
+            .map(|index| statuses.swap_remove(index));
+
+         let (ready, restart_count, state) = match status {
+            None => (false, 0, ContainerState::Unknown),
+            Some(status) => (status.ready, status.restart_count, parse_container_state(status.state)),
+         };
+
+         Container {
+            name: container.name.into(),
+            ready,
+            restart_count,
+            state,
+            requests,
+            limits,
+         }
+      })
+      .collect()
+}
+
+fn parse_container_state(state: Option<JsonContainerState>) -> ContainerState
+{
+   let Some(state) = state else {
+      return ContainerState::Unknown;
+   };
+
+   if let Some(terminated) = state.terminated {
+      return ContainerState::Terminated {
+         reason: terminated.reason.map(Into::into),
+         exit_code: terminated.exit_code,
+      };
+   };
+
+   if state.running.is_some() {
+      return ContainerState::Running;
+   };
+
+   match state.waiting {
+      Some(waiting) => ContainerState::Waiting {
+         reason: waiting.reason.map(Into::into),
+      },
+      None => ContainerState::Unknown,
+   }
+}
+
+fn parse_resources(resources: Option<ResourceRequirements>) -> (Resources, Resources)
+{
+   let ResourceRequirements { requests, limits, .. } = resources.unwrap_or_default();
+
+   let parse = |quantities: Option<BTreeMap<String, Quantity>>| {
+      let quantities = quantities.unwrap_or_default();
+      let get = |name: &str| quantities.get(name).and_then(|Quantity(quantity)| parse_quantity(quantity));
+
+      Resources {
+         cpu: get("cpu"),
+         memory: get("memory"),
+      }
+   };
+
+   (parse(requests), parse(limits))
+}
+
+/// Parses a Kubernetes quantity such as `250m`, `1.5`, `128Mi`, `1G` or `5e3` into a plain
+/// number, so cpu comes out in cores and memory in bytes.
+fn parse_quantity(quantity: &str) -> Option<f64>
+{
+   let quantity = quantity.trim();
+
+   const SUFFIXES: [(&str, f64); 14] = [
+      ("Ki", 1024.0),
+      ("Mi", 1_048_576.0),
+      ("Gi", 1_073_741_824.0),
+      ("Ti", 1_099_511_627_776.0),
+      ("Pi", 1_125_899_906_842_624.0),
+      ("Ei", 1_152_921_504_606_846_976.0),
+      ("n", 1e-9),
+      ("u", 1e-6),
+      ("m", 1e-3),
+      ("k", 1e3),
+      ("M", 1e6),
+      ("G", 1e9),
+      ("T", 1e12),
+      ("P", 1e15),
+   ];
+
+   for (suffix, factor) in SUFFIXES {
+      if let Some(number) = quantity.strip_suffix(suffix) {
+         return number.parse::<f64>().ok().map(|number| number * factor);
+      };
+   }
+
+   // a trailing E is the exa suffix, any other E is an exponent and parses as is
+   if let Some(number) = quantity.strip_suffix('E') {
+      return number.parse::<f64>().ok().map(|number| number * 1e18);
+   };
+
+   quantity.parse().ok()
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn quantities()
+   {
+      let cases = [
+         ("500m", Some(0.5)),
+         ("250m", Some(0.25)),
+         ("1", Some(1.0)),
+         ("1.5", Some(1.5)),
+         (" 2 ", Some(2.0)),
+         ("100n", Some(100e-9)),
+         ("10u", Some(10e-6)),
+         ("1k", Some(1e3)),
+         ("128M", Some(128e6)),
+         ("1G", Some(1e9)),
+         ("2T", Some(2e12)),
+         ("1P", Some(1e15)),
+         ("1E", Some(1e18)),
+         ("1Ki", Some(1024.0)),
+         ("128Mi", Some(128.0 * 1024.0 * 1024.0)),
+         ("1Gi", Some(1024.0 * 1024.0 * 1024.0)),
+         ("1.5Gi", Some(1.5 * 1024.0 * 1024.0 * 1024.0)),
+         ("1Ti", Some(1_099_511_627_776.0)),
+         ("1Pi", Some(1_125_899_906_842_624.0)),
+         ("1Ei", Some(1_152_921_504_606_846_976.0)),
+         ("1e3", Some(1e3)),
+         ("5E3", Some(5e3)),
+         ("1e-3", Some(1e-3)),
+         ("", None),
+         ("Mi", None),
+         ("1Xi", None),
+         ("one", None),
+      ];
+
+      for (quantity, expected) in cases {
+         let parsed = parse_quantity(quantity);
+         let close = match (parsed, expected) {
+            (Some(parsed), Some(expected)) => (parsed - expected).abs() <= expected.abs() * 1e-12,
+            (parsed, expected) => parsed == expected,
+         };
+         assert!(close, "{quantity:?} parsed as {parsed:?}, expected {expected:?}");
+      }
+   }
 }
@@ -0,0 +1,452 @@
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt, stream::FuturesUnordered, stream::SelectAll};
+use k8s_openapi::api::core::v1::Pod as JsonPod;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use super::ws::{self, V4_CHANNEL};
+use super::{APIError, Api, Base};
+
+/// Bytes buffered in each direction of a forwarded port before the other side has to read.
+const BUFFER: usize = 64 * 1024;
+
+/// Messages queued for a forwarded port before the session waits for it to be read.
+const QUEUE: usize = 256;
+
+/// Failures of a `LocalPortForward` kept until they are read.
+const ERRORS: usize = 16;
+
+/// Each port takes a data and an error channel, and channels are numbered by a single byte.
+const MAX_PORTS: usize = 128;
+
+/// A port-forward session to a pod, with one stream per forwarded port.
+///
+/// Dropping a stream stops forwarding its port, and the session closes once every port has
+/// stopped or the pod closed it. Shutting a stream down only ends what is sent, and it keeps
+/// receiving the answer. Dropping the session closes it right away.
+#[derive(Debug)]
+pub struct PortForward
+{
+   ports: Vec<u16>,
+   streams: Vec<Option<DuplexStream>>,
+   task: tokio::task::JoinHandle<Result<(), APIError>>,
+   guard: DropGuard,
+}
+
+impl PortForward
+{
+   /// Runs the session over an already upgraded socket.
+   pub(crate) fn new<S>(socket: S, ports: &[u16]) -> Self
+   where
+      S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+   {
+      let (streams, locals) = ports.iter().map(|_| tokio::io::duplex(BUFFER)).unzip::<_, _, Vec<_>, Vec<_>>();
+      let kill_signal = CancellationToken::new();
+      let guard = kill_signal.clone().drop_guard();
+      let task = tokio::spawn(forward(socket, ports.to_vec(), locals, kill_signal));
+
+      Self {
+         ports: ports.to_vec(),
+         streams: streams.into_iter().map(Some).collect(),
+         task,
+         guard,
+      }
+   }
+
+   pub fn ports(&self) -> &[u16]
+   {
+      &self.ports
+   }
+
+   /// The stream forwarded to `port` of the pod; each can only be taken once.
+   pub fn take_stream(&mut self, port: u16) -> Option<DuplexStream>
+   {
+      let index = self.ports.iter().position(|&x| x == port)?;
+      self.streams[index].take()
+   }
+
+   /// Waits for the session to close, returning the error that closed it, if any.
+   ///
+   /// Streams that were not taken are dropped, so only the taken ones keep it open.
+   pub async fn join(self) -> Result<(), APIError>
+   {
+      let Self {
+         ports: _,
+         streams,
+         task,
+         guard,
+      } = self;
+
+      drop(streams);
+      let result = task.await;
+      drop(guard);
+
+      result.map_err(|e| APIError::Io(std::io::Error::other(e)))?
+   }
+}
+
+/// Opens a port-forward session to `ports` of a pod.
+pub async fn port_forward(base: &Base, namespace: &str, name: &str, ports: &[u16]) -> Result<PortForward, APIError>
+{
+   if ports.is_empty() || ports.len() > MAX_PORTS {
+      return Err(APIError::Protocol(
+         format!("a port-forward session takes 1 to {MAX_PORTS} ports, got {}", ports.len()).into(),
+      ));
+   };
+
+   let api = Api::<JsonPod>::namespaced(base.clone(), namespace);
+   let path = api.url_path(Some(name), Some("portforward"));
+   let query: Vec<_> = ports.iter().map(|port| ("ports", port.to_string())).collect();
+
+   let (socket, _) = ws::connect(base, &path, &query, &[V4_CHANNEL]).await?;
+   Ok(PortForward::new(socket, ports))
+}
+
+/// Forwards connections to a local address to a port of a pod, like `kubectl port-forward`,
+/// each over a session of its own. Dropping it stops accepting connections.
+#[derive(Debug)]
+pub struct LocalPortForward
+{
+   address: SocketAddr,
+   errors: mpsc::Receiver<APIError>,
+   _guard: DropGuard,
+}
+
+impl LocalPortForward
+{
+   /// Where connections are accepted, with the actual port when bound to port 0.
+   pub fn local_addr(&self) -> SocketAddr
+   {
+      self.address
+   }
+
+   /// The next failure to accept or forward a connection. Failures are dropped while
+   /// `ERRORS` of them are waiting to be read.
+   pub async fn error(&mut self) -> Option<APIError>
+   {
+      self.errors.recv().await
+   }
+}
+
+pub async fn port_forward_local(
+   base: &Base,
+   namespace: &str,
+   name: &str,
+   port: u16,
+   address: SocketAddr,
+) -> Result<LocalPortForward, APIError>
+{
+   let listener = tokio::net::TcpListener::bind(address).await?;
+   let address = listener.local_addr()?;
+
+   let kill_signal = CancellationToken::new();
+   let guard = kill_signal.clone().drop_guard();
+   let (error_sender, errors) = mpsc::channel(ERRORS);
+
+   let base = base.clone();
+   let namespace: Box<str> = namespace.into();
+   let name: Box<str> = name.into();
+
+   tokio::spawn(async move {
+      loop {
+         let connection = tokio::select! {
+            _ = kill_signal.cancelled() => return,
+            connection = listener.accept() => connection,
+         };
+
+         let mut connection = match connection {
+            Ok((connection, _)) => connection,
+            Err(e) => {
+               let _ = error_sender.try_send(e.into());
+               continue;
+            },
+         };
+
+         let base = base.clone();
+         let namespace = namespace.clone();
+         let name = name.clone();
+         let kill_signal = kill_signal.clone();
+         let error_sender = error_sender.clone();
+
+         tokio::spawn(async move {
+            let forward = async {
+               let mut session = port_forward(&base, &namespace, &name, &[port]).await?;
+               let mut stream = session.take_stream(port).expect("the session forwards this port");
+               tokio::io::copy_bidirectional(&mut connection, &mut stream).await?;
+               Ok::<_, APIError>(())
+            };
+
+            let result = tokio::select! {
+               _ = kill_signal.cancelled() => return,
+               result = forward => result,
+            };
+
+            if let Err(e) = result {
+               let _ = error_sender.try_send(e);
+            };
+         });
+      }
+   });
+
+   Ok(LocalPortForward {
+      address,
+      errors,
+      _guard: guard,
+   })
+}
+
+/// Moves data between the socket and the local streams until every port stopped, the socket
+/// closed or the session is killed.
+///
+/// The first message of every channel starts with the port it belongs to, which is skipped.
+/// Anything sent on a port's error channel ends the session with that error.
+///
+/// Each port's stream is written by a task of its own, so a port that is read slowly does
+/// not hold back the others until it falls `QUEUE` messages behind, when the session waits
+/// for it rather than losing data. A port stops once its stream was dropped; a stream that
+/// only shut down its write half keeps receiving until the pod closes the connection.
+async fn forward<S>(
+   mut socket: S,
+   ports: Vec<u16>,
+   locals: Vec<DuplexStream>,
+   kill_signal: CancellationToken,
+) -> Result<(), APIError>
+where
+   S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+   let mut writers = Vec::with_capacity(locals.len());
+   let mut dropped = FuturesUnordered::new();
+   let mut reads = SelectAll::new();
+
+   for (index, local) in locals.into_iter().enumerate() {
+      let (reader, writer) = tokio::io::split(local);
+      let writer = ws::queue_writes(writer, QUEUE);
+      let closed = writer.clone();
+      dropped.push(async move {
+         closed.closed().await;
+         index
+      });
+      writers.push(Some(writer));
+
+      let end = futures::stream::once(async move { (index, None) });
+      reads.push(ReaderStream::new(reader).map(move |chunk| (index, Some(chunk))).chain(end).boxed());
+   }
+
+   let mut open = ports.len();
+   let mut started = vec![false; ports.len() * 2];
+
+   loop {
+      tokio::select! {
+         _ = kill_signal.cancelled() => break,
+         message = socket.next() => {
+            let message = match message {
+               None | Some(Ok(Message::Close(_))) => return Ok(()),
+               Some(message) => message?,
+            };
+
+            let Some((channel, mut data)) = ws::unframe(message) else {
+               continue;
+            };
+
+            let index = channel as usize / 2;
+            let Some(&port) = ports.get(index) else {
+               return Err(APIError::Protocol(format!("message on unknown channel {channel}").into()));
+            };
+
+            if !started[channel as usize] {
+               if data.len() < 2 {
+                  return Err(APIError::Protocol(format!("channel {channel} did not start with its port").into()));
+               };
+               started[channel as usize] = true;
+               let _ = data.split_to(2);
+            };
+
+            if channel % 2 == 1 {
+               if data.is_empty() {
+                  continue;
+               };
+               let message = String::from_utf8_lossy(&data).into();
+               return Err(APIError::PortForward { port, message });
+            };
+
+            if data.is_empty() {
+               continue;
+            };
+
+            tokio::select! {
+               _ = kill_signal.cancelled() => break,
+               _ = ws::write_queued(&mut writers[index], data) => (),
+            };
+         },
+         Some((index, chunk)) = reads.next() => match chunk {
+            Some(Ok(data)) => socket.send(ws::frame(index as u8 * 2, &data)).await?,
+            Some(Err(e)) => return Err(e.into()),
+            // shut down or dropped, which an empty message tells apart
+            None => ws::write_queued(&mut writers[index], Bytes::new()).await,
+         },
+         Some(index) = dropped.next() => {
+            writers[index] = None;
+            open -= 1;
+            if open == 0 {
+               break;
+            };
+         },
+      }
+   }
+
+   let _ = socket.close().await;
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+   use std::time::Duration;
+
+   use bytes::Bytes;
+   use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+   use super::*;
+   use crate::client::ws::stand_in::{self, StandIn};
+
+   /// A session over an in-memory socket, and the apiserver's end of it.
+   async fn session(ports: &[u16]) -> (PortForward, StandIn)
+   {
+      let (client, server) = stand_in::pair(BUFFER).await;
+      (PortForward::new(client, ports), server)
+   }
+
+   /// Starts the data and error channel of every port with the port, like the apiserver.
+   async fn start(server: &mut StandIn, ports: &[u16])
+   {
+      for (index, port) in ports.iter().enumerate() {
+         let channel = index as u8 * 2;
+         server.send(ws::frame(channel, &port.to_le_bytes())).await.unwrap();
+         server.send(ws::frame(channel + 1, &port.to_le_bytes())).await.unwrap();
+      }
+   }
+
+   async fn read(stream: &mut DuplexStream, len: usize) -> Vec<u8>
+   {
+      let mut data = vec![0; len];
+      tokio::time::timeout(Duration::from_secs(1), stream.read_exact(&mut data))
+         .await
+         .expect("read hung")
+         .unwrap();
+      data
+   }
+
+   async fn join(forward: PortForward) -> Result<(), APIError>
+   {
+      tokio::time::timeout(Duration::from_secs(1), forward.join())
+         .await
+         .expect("join hung")
+   }
+
+   #[tokio::test]
+   async fn forwards_data_of_every_port()
+   {
+      let (mut forward, mut server) = session(&[8080, 9090]).await;
+      start(&mut server, &[8080, 9090]).await;
+      server.send(ws::frame(0, b"hello")).await.unwrap();
+      server.send(ws::frame(2, b"world")).await.unwrap();
+
+      let mut first = forward.take_stream(8080).unwrap();
+      let mut second = forward.take_stream(9090).unwrap();
+      assert!(forward.take_stream(8080).is_none());
+
+      // the port prefix of each channel's first message is not data
+      assert_eq!(read(&mut first, 5).await, b"hello");
+      assert_eq!(read(&mut second, 5).await, b"world");
+
+      second.write_all(b"ping").await.unwrap();
+      assert_eq!(stand_in::receive(&mut server).await, Some((2, Bytes::from_static(b"ping"))));
+
+      drop((first, second));
+      join(forward).await.unwrap();
+   }
+
+   #[tokio::test]
+   async fn error_channel_ends_the_session()
+   {
+      let (mut forward, mut server) = session(&[8080]).await;
+      let _stream = forward.take_stream(8080).unwrap();
+      start(&mut server, &[8080]).await;
+      server.send(ws::frame(1, b"connection refused")).await.unwrap();
+
+      match join(forward).await {
+         Err(APIError::PortForward { port, message }) => {
+            assert_eq!(port, 8080);
+            assert_eq!(&*message, "connection refused");
+         },
+         other => panic!("expected a port-forward error, got {other:?}"),
+      };
+   }
+
+   #[tokio::test]
+   async fn channel_without_its_port_is_rejected()
+   {
+      let (mut forward, mut server) = session(&[8080]).await;
+      let _stream = forward.take_stream(8080).unwrap();
+      server.send(ws::frame(0, b"x")).await.unwrap();
+
+      assert!(matches!(join(forward).await, Err(APIError::Protocol(_))));
+   }
+
+   #[tokio::test]
+   async fn slow_reader_gets_all_of_its_port()
+   {
+      let (mut forward, mut server) = session(&[8080]).await;
+      let mut stream = forward.take_stream(8080).unwrap();
+
+      // more than the stream and its queue hold together
+      let messages = QUEUE + 2 * BUFFER / 1024;
+      let sender = tokio::spawn(async move {
+         start(&mut server, &[8080]).await;
+         for _ in 0..messages {
+            server.send(ws::frame(0, &[7; 1024])).await.unwrap();
+         }
+         server.close(None).await.unwrap();
+         server
+      });
+
+      tokio::time::sleep(Duration::from_millis(100)).await;
+      let mut data = vec![];
+      tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut data))
+         .await
+         .expect("read hung")
+         .unwrap();
+
+      assert_eq!(data.len(), messages * 1024);
+      join(forward).await.unwrap();
+      drop(sender.await.unwrap());
+   }
+
+   #[tokio::test]
+   async fn shut_down_stream_still_receives()
+   {
+      let (mut forward, mut server) = session(&[8080]).await;
+      let mut stream = forward.take_stream(8080).unwrap();
+      start(&mut server, &[8080]).await;
+
+      stream.write_all(b"request").await.unwrap();
+      stream.shutdown().await.unwrap();
+      assert_eq!(stand_in::receive(&mut server).await, Some((0, Bytes::from_static(b"request"))));
+
+      server.send(ws::frame(0, b"response")).await.unwrap();
+      server.close(None).await.unwrap();
+
+      let mut data = vec![];
+      tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut data))
+         .await
+         .expect("read hung")
+         .unwrap();
+      assert_eq!(data, b"response");
+      join(forward).await.unwrap();
+   }
+}
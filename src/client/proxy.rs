@@ -0,0 +1,249 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStream};
+use reqwest::{Body, Method, Response};
+
+use super::{APIError, Base, response_into_error};
+
+/// What a proxy request is sent to: a pod, node or service name, optionally with the scheme
+/// and port the apiserver should use, written `[scheme:]name[:port]` as in `https:cadvisor:8443`.
+///
+/// Without a port the apiserver uses the default one of the object, over plain HTTP unless
+/// a scheme is given. Ports may also be named, e.g. `metrics`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyTarget
+{
+   pub scheme: Option<Box<str>>,
+   pub name: Box<str>,
+   pub port: Option<Box<str>>,
+}
+
+impl ProxyTarget
+{
+   pub fn new(name: &str) -> Self
+   {
+      Self {
+         scheme: None,
+         name: name.into(),
+         port: None,
+      }
+   }
+
+   pub fn scheme(mut self, scheme: &str) -> Self
+   {
+      self.scheme = Some(scheme.into());
+      self
+   }
+
+   pub fn port(mut self, port: &str) -> Self
+   {
+      self.port = Some(port.into());
+      self
+   }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyTargetError(pub Box<str>);
+
+impl std::fmt::Display for ProxyTargetError
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write!(f, "invalid proxy target \"{}\", expected [scheme:]name[:port]", self.0)
+   }
+}
+
+impl std::error::Error for ProxyTargetError {}
+
+impl FromStr for ProxyTarget
+{
+   type Err = ProxyTargetError;
+
+   /// `name`, `name:port` or `scheme:name:port`, where the scheme is http or https.
+   fn from_str(target: &str) -> Result<Self, Self::Err>
+   {
+      let invalid = || ProxyTargetError(target.into());
+      let parts: Vec<&str> = target.split(':').collect();
+
+      let (scheme, name, port) = match parts.as_slice() {
+         [name] => (None, *name, None),
+         [name, port] => (None, *name, Some(*port)),
+         [scheme, name, port] if matches!(*scheme, "http" | "https") => (Some(*scheme), *name, Some(*port)),
+         _ => return Err(invalid()),
+      };
+
+      if name.is_empty() || name.contains('/') {
+         return Err(invalid());
+      };
+
+      Ok(Self {
+         scheme: scheme.map(Into::into),
+         name: name.into(),
+         port: port.filter(|port| !port.is_empty()).map(Into::into),
+      })
+   }
+}
+
+impl std::fmt::Display for ProxyTarget
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      // a scheme is only recognised with the port spelled out, if only as empty
+      match (&self.scheme, &self.port) {
+         (Some(scheme), port) => write!(f, "{scheme}:{}:{}", self.name, port.as_deref().unwrap_or_default()),
+         (None, Some(port)) => write!(f, "{}:{port}", self.name),
+         (None, None) => write!(f, "{}", self.name),
+      }
+   }
+}
+
+/// A request to a path behind the apiserver's proxy subresource, built by `Proxy`.
+///
+/// GET by default; the answer is checked for an error status and then returned as is,
+/// or read as bytes, text or a stream of chunks.
+#[derive(Debug)]
+pub struct ProxyRequest
+{
+   client: Arc<Base>,
+   method: Method,
+   path: String,
+   query: Vec<(Box<str>, Box<str>)>,
+   body: Option<Body>,
+}
+
+impl ProxyRequest
+{
+   pub(crate) fn new(client: Arc<Base>, proxy_path: String, path: &str) -> Self
+   {
+      let path = format!("{proxy_path}/{}", path.trim_start_matches('/'));
+
+      Self {
+         client,
+         method: Method::GET,
+         path,
+         query: vec![],
+         body: None,
+      }
+   }
+
+   pub fn path(&self) -> &str
+   {
+      &self.path
+   }
+
+   pub fn method(mut self, method: Method) -> Self
+   {
+      self.method = method;
+      self
+   }
+
+   pub fn query(mut self, key: &str, value: &str) -> Self
+   {
+      self.query.push((key.into(), value.into()));
+      self
+   }
+
+   pub fn body(mut self, body: impl Into<Body>) -> Self
+   {
+      self.body = Some(body.into());
+      self
+   }
+
+   /// Streams the body as it is produced; such requests are not retried.
+   pub fn body_stream<S>(self, stream: S) -> Self
+   where
+      S: TryStream + Send + 'static,
+      S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+      Bytes: From<S::Ok>,
+   {
+      self.body(Body::wrap_stream(stream))
+   }
+
+   pub async fn send(self) -> Result<Response, APIError>
+   {
+      let Self {
+         client,
+         method,
+         path,
+         query,
+         body,
+      } = self;
+
+      let mut request = client.request(method, path);
+      if !query.is_empty() {
+         request = request.query(&query);
+      };
+      if let Some(body) = body {
+         request = request.body(body);
+      };
+
+      let response = client.send(request).await?;
+      response_into_error(response).await
+   }
+
+   pub async fn bytes(self) -> Result<Bytes, APIError>
+   {
+      Ok(self.send().await?.bytes().await?)
+   }
+
+   pub async fn text(self) -> Result<String, APIError>
+   {
+      Ok(self.send().await?.text().await?)
+   }
+
+   /// The body as it arrives, for endpoints that keep streaming such as logs.
+   pub async fn stream(self) -> Result<impl Stream<Item = Result<Bytes, APIError>> + use<>, APIError>
+   {
+      let response = self.send().await?;
+      Ok(response.bytes_stream().map(|chunk| chunk.map_err(APIError::from)))
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   #[test]
+   fn targets_round_trip()
+   {
+      let cases = [
+         ("cadvisor-x", ProxyTarget::new("cadvisor-x")),
+         ("cadvisor-x:8080", ProxyTarget::new("cadvisor-x").port("8080")),
+         ("cadvisor-x:metrics", ProxyTarget::new("cadvisor-x").port("metrics")),
+         ("https:cadvisor-x:8443", ProxyTarget::new("cadvisor-x").scheme("https").port("8443")),
+         ("http:cadvisor-x:metrics", ProxyTarget::new("cadvisor-x").scheme("http").port("metrics")),
+         // the default port of the object, over https
+         ("https:cadvisor-x:", ProxyTarget::new("cadvisor-x").scheme("https")),
+      ];
+
+      for (text, target) in cases {
+         assert_eq!(text.parse::<ProxyTarget>(), Ok(target.clone()), "{text}");
+         assert_eq!(target.to_string(), text);
+      }
+   }
+
+   #[test]
+   fn an_empty_port_is_the_default_one()
+   {
+      let target: ProxyTarget = "cadvisor-x:".parse().unwrap();
+
+      assert_eq!(target, ProxyTarget::new("cadvisor-x"));
+      assert_eq!(target.to_string(), "cadvisor-x");
+   }
+
+   #[test]
+   fn invalid_targets()
+   {
+      for text in ["", ":8080", "https::8443", "ftp:cadvisor-x:21", "https:cadvisor-x:8443:1", "ns/cadvisor-x", "a/b:80"] {
+         assert_eq!(text.parse::<ProxyTarget>(), Err(ProxyTargetError(text.into())), "{text}");
+      }
+
+      assert_eq!(
+         ProxyTargetError("a:b:c:d".into()).to_string(),
+         "invalid proxy target \"a:b:c:d\", expected [scheme:]name[:port]"
+      );
+   }
+}
@@ -0,0 +1,619 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+
+use futures::{Stream, StreamExt};
+use k8s_openapi::{Metadata, api::core::v1::Pod as JsonPod, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use tokio::sync::watch;
+
+use super::watcher::key;
+use super::{Health, LabelSelector, ResourceEvent, ResourceVersion, Uid, WatcherError, WatcherEvent};
+
+/// Picks the node an object is placed on, for the store's node index.
+pub type NodeIndex<K> = fn(&K) -> Option<&str>;
+
+/// The node a pod is scheduled on.
+pub fn pod_node(pod: &JsonPod) -> Option<&str>
+{
+   pod.spec.as_ref()?.node_name.as_deref()
+}
+
+type Label = (Box<str>, Box<str>);
+
+#[derive(Debug)]
+struct Inner<K>
+{
+   objects: HashMap<Uid, Arc<K>>,
+   names: HashMap<(Box<str>, Box<str>), Uid>,
+   nodes: HashMap<Box<str>, HashSet<Uid>>,
+   labels: HashMap<Label, HashSet<Uid>>,
+   version: ResourceVersion,
+   ready: bool,
+}
+
+impl<K> Default for Inner<K>
+{
+   fn default() -> Self
+   {
+      Self {
+         objects: HashMap::new(),
+         names: HashMap::new(),
+         nodes: HashMap::new(),
+         labels: HashMap::new(),
+         version: "".into(),
+         ready: false,
+      }
+   }
+}
+
+fn name_key(metadata: &ObjectMeta) -> (Box<str>, Box<str>)
+{
+   (
+      metadata.namespace.as_deref().unwrap_or_default().into(),
+      metadata.name.as_deref().unwrap_or_default().into(),
+   )
+}
+
+fn unindex<T: std::hash::Hash + Eq>(index: &mut HashMap<T, HashSet<Uid>>, entry: T, uid: &Uid)
+{
+   if let Some(uids) = index.get_mut(&entry) {
+      uids.remove(uid);
+      if uids.is_empty() {
+         index.remove(&entry);
+      };
+   };
+}
+
+impl<K: Metadata<Ty = ObjectMeta>> Inner<K>
+{
+   fn insert(&mut self, object: Arc<K>, node_index: Option<NodeIndex<K>>)
+   {
+      let uid = key(object.as_ref());
+      self.remove(&uid, node_index);
+
+      let metadata = object.metadata();
+      self.names.insert(name_key(metadata), uid.clone());
+
+      for (name, value) in metadata.labels.iter().flatten() {
+         let label = (name.as_str().into(), value.as_str().into());
+         self.labels.entry(label).or_default().insert(uid.clone());
+      }
+
+      if let Some(node) = node_index.and_then(|index| index(&object)) {
+         self.nodes.entry(node.into()).or_default().insert(uid.clone());
+      };
+
+      self.objects.insert(uid, object);
+   }
+
+   fn remove(&mut self, uid: &Uid, node_index: Option<NodeIndex<K>>)
+   {
+      let Some(object) = self.objects.remove(uid) else {
+         return;
+      };
+
+      let metadata = object.metadata();
+      let name = name_key(metadata);
+
+      // a recreated object may already hold the name under its new uid
+      if self.names.get(&name) == Some(uid) {
+         self.names.remove(&name);
+      };
+
+      for (name, value) in metadata.labels.iter().flatten() {
+         unindex(&mut self.labels, (name.as_str().into(), value.as_str().into()), uid);
+      }
+
+      if let Some(node) = node_index.and_then(|index| index(&object)) {
+         unindex(&mut self.nodes, node.into(), uid);
+      };
+   }
+
+   fn resolve(&self, uids: Option<&HashSet<Uid>>) -> Vec<Arc<K>>
+   {
+      uids.into_iter()
+         .flatten()
+         .filter_map(|uid| self.objects.get(uid).cloned())
+         .collect()
+   }
+}
+
+/// The only handle that changes a store, fed by a watcher through `apply`.
+#[derive(Debug)]
+pub struct Writer<K>
+{
+   inner: Arc<RwLock<Inner<K>>>,
+   changed: watch::Sender<u64>,
+   node_index: Option<NodeIndex<K>>,
+}
+
+impl<K: Metadata<Ty = ObjectMeta>> Default for Writer<K>
+{
+   fn default() -> Self
+   {
+      Self::new()
+   }
+}
+
+impl Writer<JsonPod>
+{
+   /// A pod store with the node index filled from `spec.nodeName`.
+   pub fn pods() -> Self
+   {
+      Self::new().with_node_index(pod_node)
+   }
+}
+
+impl<K: Metadata<Ty = ObjectMeta>> Writer<K>
+{
+   pub fn new() -> Self
+   {
+      Self {
+         inner: Arc::new(RwLock::new(Inner::default())),
+         changed: watch::channel(0).0,
+         node_index: None,
+      }
+   }
+
+   pub fn with_node_index(mut self, node_index: NodeIndex<K>) -> Self
+   {
+      self.node_index = Some(node_index);
+      self
+   }
+
+   /// A read-only view of the store, which can be cloned and shared across tasks.
+   pub fn store(&self) -> Store<K>
+   {
+      Store {
+         inner: self.inner.clone(),
+         changed: self.changed.subscribe(),
+      }
+   }
+
+   /// Applies one watcher event. The store becomes ready once the watcher is first
+   /// connected, at which point its initial list has been applied.
+   pub fn apply(&mut self, event: &WatcherEvent<ResourceEvent<K>>)
+   where
+      K: Clone,
+   {
+      {
+         let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+
+         match event {
+            WatcherEvent::Event(ResourceEvent::Added(object) | ResourceEvent::Modified(object)) => {
+               if let Some(version) = &object.metadata().resource_version {
+                  inner.version = version.as_str().into();
+               };
+               inner.insert(Arc::new(object.clone()), self.node_index);
+            },
+            WatcherEvent::Event(ResourceEvent::Deleted(object)) => {
+               if let Some(version) = &object.metadata().resource_version {
+                  inner.version = version.as_str().into();
+               };
+               inner.remove(&key(object), self.node_index);
+            },
+            WatcherEvent::Event(ResourceEvent::Bookmark(version)) => inner.version = version.clone(),
+            WatcherEvent::Health(Health::Connected) => inner.ready = true,
+            WatcherEvent::Health(_) | WatcherEvent::Resynced(_) => return,
+         };
+      }
+
+      self.changed.send_modify(|generation| *generation += 1);
+   }
+}
+
+/// Shared read access to objects kept current by a watcher.
+///
+/// Lookups return the objects as `Arc`s, so reading never copies them and never blocks the
+/// writer for longer than the lookup itself.
+#[derive(Debug)]
+pub struct Store<K>
+{
+   inner: Arc<RwLock<Inner<K>>>,
+   changed: watch::Receiver<u64>,
+}
+
+impl<K> Clone for Store<K>
+{
+   fn clone(&self) -> Self
+   {
+      Self {
+         inner: self.inner.clone(),
+         changed: self.changed.clone(),
+      }
+   }
+}
+
+impl<K: Metadata<Ty = ObjectMeta>> Store<K>
+{
+   fn read(&self) -> RwLockReadGuard<'_, Inner<K>>
+   {
+      self.inner.read().unwrap_or_else(PoisonError::into_inner)
+   }
+
+   pub fn get(&self, uid: &str) -> Option<Arc<K>>
+   {
+      self.read().objects.get(uid).cloned()
+   }
+
+   /// Looks an object up by name; cluster-scoped objects have no namespace.
+   pub fn get_by_name(&self, namespace: Option<&str>, name: &str) -> Option<Arc<K>>
+   {
+      let inner = self.read();
+      let uid = inner.names.get(&(namespace.unwrap_or_default().into(), name.into()))?;
+      inner.objects.get(uid).cloned()
+   }
+
+   pub fn list(&self) -> Vec<Arc<K>>
+   {
+      self.read().objects.values().cloned().collect()
+   }
+
+   /// Objects on `node`; always empty for a store without a node index.
+   pub fn list_by_node(&self, node: &str) -> Vec<Arc<K>>
+   {
+      let inner = self.read();
+      inner.resolve(inner.nodes.get(node))
+   }
+
+   pub fn list_by_label(&self, name: &str, value: &str) -> Vec<Arc<K>>
+   {
+      let inner = self.read();
+      inner.resolve(inner.labels.get(&(name.into(), value.into())))
+   }
+
+   pub fn select(&self, selector: &LabelSelector) -> Vec<Arc<K>>
+   {
+      let empty = Default::default();
+
+      self.read()
+         .objects
+         .values()
+         .filter(|object| selector.matches(object.metadata().labels.as_ref().unwrap_or(&empty)))
+         .cloned()
+         .collect()
+   }
+
+   pub fn len(&self) -> usize
+   {
+      self.read().objects.len()
+   }
+
+   pub fn is_empty(&self) -> bool
+   {
+      self.read().objects.is_empty()
+   }
+
+   /// The resourceVersion the store is current at.
+   pub fn version(&self) -> ResourceVersion
+   {
+      self.read().version.clone()
+   }
+
+   /// Whether the watcher's initial list has been applied.
+   pub fn is_ready(&self) -> bool
+   {
+      self.read().ready
+   }
+
+   /// All objects at one point in time, unaffected by later changes.
+   pub fn snapshot(&self) -> Snapshot<K>
+   {
+      let inner = self.read();
+
+      Snapshot {
+         objects: inner.objects.clone(),
+         version: inner.version.clone(),
+      }
+   }
+
+   /// Waits until `predicate` holds for the store, checking after every change.
+   ///
+   /// Returns false if the writer was dropped first, after which the store never changes.
+   pub async fn wait_until(&self, mut predicate: impl FnMut(&Self) -> bool) -> bool
+   {
+      let mut changed = self.changed.clone();
+
+      loop {
+         changed.mark_unchanged();
+
+         if predicate(self) {
+            return true;
+         };
+
+         if changed.changed().await.is_err() {
+            return predicate(self);
+         };
+      }
+   }
+
+   pub async fn wait_ready(&self) -> bool
+   {
+      self.wait_until(Self::is_ready).await
+   }
+}
+
+/// A copy of a store's objects at one resourceVersion.
+#[derive(Debug)]
+pub struct Snapshot<K>
+{
+   objects: HashMap<Uid, Arc<K>>,
+   version: ResourceVersion,
+}
+
+impl<K> Clone for Snapshot<K>
+{
+   fn clone(&self) -> Self
+   {
+      Self {
+         objects: self.objects.clone(),
+         version: self.version.clone(),
+      }
+   }
+}
+
+impl<K> Snapshot<K>
+{
+   pub fn get(&self, uid: &str) -> Option<&Arc<K>>
+   {
+      self.objects.get(uid)
+   }
+
+   pub fn iter(&self) -> impl Iterator<Item = &Arc<K>>
+   {
+      self.objects.values()
+   }
+
+   pub fn len(&self) -> usize
+   {
+      self.objects.len()
+   }
+
+   pub fn is_empty(&self) -> bool
+   {
+      self.objects.is_empty()
+   }
+
+   pub fn version(&self) -> &str
+   {
+      &self.version
+   }
+}
+
+/// Applies every event of `stream` to `writer` as it passes through.
+pub fn reflector<K, S>(mut writer: Writer<K>, stream: S) -> impl Stream<Item = S::Item>
+where
+   K: Metadata<Ty = ObjectMeta> + Clone,
+   S: Stream<Item = Result<WatcherEvent<ResourceEvent<K>>, WatcherError>>,
+{
+   stream.map(move |item| {
+      if let Ok(event) = &item {
+         writer.apply(event);
+      };
+      item
+   })
+}
+
+/// Keeps `writer`'s store current from `stream` in the background, until the stream ends.
+///
+/// The stream is usually a watcher or one of its subscriptions; kill the watcher to stop.
+pub fn reflect<K, S>(writer: Writer<K>, stream: S) -> Store<K>
+where
+   K: Metadata<Ty = ObjectMeta> + Clone + Send + Sync + 'static,
+   S: Stream<Item = Result<WatcherEvent<ResourceEvent<K>>, WatcherError>> + Send + 'static,
+{
+   let store = writer.store();
+
+   tokio::spawn(async move {
+      let stream = reflector(writer, stream);
+      futures::pin_mut!(stream);
+      while stream.next().await.is_some() {}
+   });
+
+   store
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use k8s_openapi::api::core::v1::ConfigMap;
+
+   fn object(uid: &str, name: &str) -> ConfigMap
+   {
+      ConfigMap {
+         metadata: ObjectMeta {
+            uid: Some(uid.into()),
+            name: Some(name.into()),
+            namespace: Some("default".into()),
+            ..Default::default()
+         },
+         ..Default::default()
+      }
+   }
+
+   #[test]
+   fn deleting_an_old_uid_keeps_the_recreated_name()
+   {
+      let mut writer = Writer::new();
+      let store = writer.store();
+
+      writer.apply(&WatcherEvent::Event(ResourceEvent::Added(object("old", "config"))));
+      // a relist reports the recreated object before the one it replaced
+      writer.apply(&WatcherEvent::Event(ResourceEvent::Added(object("new", "config"))));
+      writer.apply(&WatcherEvent::Event(ResourceEvent::Deleted(object("old", "config"))));
+
+      let found = store.get_by_name(Some("default"), "config").expect("recreated object by name");
+      assert_eq!(&*key(found.as_ref()), "new");
+      assert_eq!(store.len(), 1);
+   }
+
+   fn labelled(uid: &str, app: &str) -> ConfigMap
+   {
+      let mut object = object(uid, uid);
+      object.metadata.labels = Some([("app".to_string(), app.to_string())].into());
+      object
+   }
+
+   fn pod(uid: &str, node: Option<&str>) -> JsonPod
+   {
+      JsonPod {
+         metadata: ObjectMeta {
+            uid: Some(uid.into()),
+            name: Some(uid.into()),
+            namespace: Some("default".into()),
+            ..Default::default()
+         },
+         spec: Some(k8s_openapi::api::core::v1::PodSpec {
+            node_name: node.map(Into::into),
+            ..Default::default()
+         }),
+         ..Default::default()
+      }
+   }
+
+   fn uids<K: Metadata<Ty = ObjectMeta>>(objects: Vec<Arc<K>>) -> Vec<String>
+   {
+      let mut uids: Vec<String> = objects.iter().map(|object| key(object.as_ref()).into()).collect();
+      uids.sort();
+      uids
+   }
+
+   fn added<K>(object: K) -> WatcherEvent<ResourceEvent<K>>
+   {
+      WatcherEvent::Event(ResourceEvent::Added(object))
+   }
+
+   fn modified<K>(object: K) -> WatcherEvent<ResourceEvent<K>>
+   {
+      WatcherEvent::Event(ResourceEvent::Modified(object))
+   }
+
+   fn deleted<K>(object: K) -> WatcherEvent<ResourceEvent<K>>
+   {
+      WatcherEvent::Event(ResourceEvent::Deleted(object))
+   }
+
+   #[test]
+   fn the_label_index_follows_label_changes()
+   {
+      let mut writer = Writer::new();
+      let store = writer.store();
+
+      writer.apply(&added(labelled("a", "web")));
+      writer.apply(&added(labelled("b", "web")));
+      assert_eq!(uids(store.list_by_label("app", "web")), ["a", "b"]);
+
+      writer.apply(&modified(labelled("a", "api")));
+      assert_eq!(uids(store.list_by_label("app", "web")), ["b"]);
+      assert_eq!(uids(store.list_by_label("app", "api")), ["a"]);
+      assert_eq!(uids(store.select(&LabelSelector::new().equal("app", "api"))), ["a"]);
+
+      writer.apply(&modified(object("b", "b")));
+      assert!(store.list_by_label("app", "web").is_empty());
+
+      writer.apply(&deleted(labelled("a", "api")));
+      assert!(store.list_by_label("app", "api").is_empty());
+      assert_eq!(uids(store.list()), ["b"]);
+   }
+
+   #[test]
+   fn the_node_index_follows_scheduling()
+   {
+      let mut writer = Writer::pods();
+      let store = writer.store();
+
+      writer.apply(&added(pod("a", None)));
+      writer.apply(&added(pod("b", Some("node-0"))));
+      assert_eq!(uids(store.list_by_node("node-0")), ["b"]);
+
+      writer.apply(&modified(pod("a", Some("node-0"))));
+      assert_eq!(uids(store.list_by_node("node-0")), ["a", "b"]);
+
+      writer.apply(&modified(pod("b", Some("node-1"))));
+      assert_eq!(uids(store.list_by_node("node-0")), ["a"]);
+      assert_eq!(uids(store.list_by_node("node-1")), ["b"]);
+
+      writer.apply(&deleted(pod("a", Some("node-0"))));
+      assert!(store.list_by_node("node-0").is_empty());
+   }
+
+   #[test]
+   fn stores_without_a_node_index_have_no_nodes()
+   {
+      let mut writer = Writer::new();
+      let store = writer.store();
+
+      writer.apply(&added(pod("a", Some("node-0"))));
+
+      assert!(store.list_by_node("node-0").is_empty());
+      assert_eq!(store.len(), 1);
+   }
+
+   #[test]
+   fn ready_once_connected_after_the_initial_list()
+   {
+      let mut writer = Writer::new();
+      let store = writer.store();
+
+      writer.apply(&added(object("a", "a")));
+      writer.apply(&WatcherEvent::Event(ResourceEvent::Bookmark("5".into())));
+      assert!(!store.is_ready());
+      assert_eq!(&*store.version(), "5");
+
+      writer.apply(&WatcherEvent::Health(Health::Connected));
+      assert!(store.is_ready());
+      assert_eq!(store.snapshot().len(), 1);
+   }
+
+   #[tokio::test]
+   async fn wait_until_sees_later_changes()
+   {
+      let mut writer = Writer::new();
+      let store = writer.store();
+
+      let waiting = tokio::spawn({
+         let store = store.clone();
+         async move { store.wait_until(|store| store.len() == 2).await }
+      });
+
+      for uid in ["a", "b"] {
+         tokio::task::yield_now().await;
+         writer.apply(&added(object(uid, uid)));
+      }
+
+      let reached = tokio::time::timeout(std::time::Duration::from_secs(1), waiting).await.expect("never woke up");
+      assert!(reached.unwrap());
+
+      // the predicate is checked before waiting for a change
+      assert!(store.wait_until(|store| store.get("a").is_some()).await);
+   }
+
+   #[tokio::test]
+   async fn waiting_ends_when_the_writer_is_dropped()
+   {
+      let writer = Writer::<ConfigMap>::new();
+      let store = writer.store();
+
+      let waiting = tokio::spawn(async move { store.wait_ready().await });
+      tokio::task::yield_now().await;
+      drop(writer);
+
+      assert!(!waiting.await.unwrap());
+   }
+
+   #[tokio::test]
+   async fn reflect_becomes_ready_from_the_stream()
+   {
+      let events = vec![
+         Ok(added(object("a", "a"))),
+         Ok(added(object("b", "b"))),
+         Ok(WatcherEvent::Health(Health::Connected)),
+         Ok(deleted(object("a", "a"))),
+      ];
+      let store = reflect(Writer::new(), futures::stream::iter(events));
+
+      assert!(store.wait_ready().await);
+      assert!(store.wait_until(|store| store.len() == 1).await);
+      assert!(store.get_by_name(Some("default"), "b").is_some());
+   }
+}
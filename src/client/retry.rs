@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use reqwest::{Response, StatusCode, header::RETRY_AFTER};
+
+/// Exponential backoff with jitter for requests the apiserver asked us to retry.
+///
+/// API Priority and Fairness rejects requests over a flow's share with 429 and a
+/// `Retry-After`, and an overloaded apiserver answers 503; neither has processed the
+/// request, so both are safe to send again.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy
+{
+   pub max_retries: u32,
+   pub initial_backoff: Duration,
+   pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy
+{
+   fn default() -> Self
+   {
+      Self {
+         max_retries: 5,
+         initial_backoff: Duration::from_millis(200),
+         max_backoff: Duration::from_secs(10),
+      }
+   }
+}
+
+impl RetryPolicy
+{
+   pub fn none() -> Self
+   {
+      Self {
+         max_retries: 0,
+         ..Self::default()
+      }
+   }
+
+   /// Delay before retry number `attempt` (starting at 0): a random point in the upper half
+   /// of the exponential window, so synchronized clients spread out.
+   pub fn backoff(&self, attempt: u32) -> Duration
+   {
+      let window = self
+         .initial_backoff
+         .saturating_mul(2u32.saturating_pow(attempt))
+         .min(self.max_backoff);
+
+      window.mul_f64(0.5 + rand::random::<f64>() * 0.5)
+   }
+
+   /// How long to wait before sending the request again, or `None` if it should not be.
+   ///
+   /// A `Retry-After` from the server is honoured but never waited for longer than
+   /// `max_backoff`. Only its delay-seconds form is read, which is the one the apiserver
+   /// sends; an HTTP-date falls back to the backoff.
+   pub fn retry_after(&self, result: &Result<Response, reqwest::Error>, attempt: u32) -> Option<Duration>
+   {
+      if attempt >= self.max_retries {
+         return None;
+      };
+
+      let response = match result {
+         Ok(response) => response,
+         // nothing reached the server, so any request can be sent again
+         Err(e) if e.is_connect() => return Some(self.backoff(attempt)),
+         Err(_) => return None,
+      };
+
+      if !is_retryable_status(response.status()) {
+         return None;
+      };
+
+      let requested = response
+         .headers()
+         .get(RETRY_AFTER)
+         .and_then(|value| value.to_str().ok())
+         .and_then(|value| value.trim().parse::<u64>().ok())
+         .map(Duration::from_secs)
+         .map(|requested| requested.min(self.max_backoff));
+
+      Some(requested.unwrap_or_else(|| self.backoff(attempt)))
+   }
+}
+
+pub fn is_retryable_status(status: StatusCode) -> bool
+{
+   status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use tokio_tungstenite::tungstenite::http;
+
+   fn response(status: u16, retry_after: &str) -> Result<Response, reqwest::Error>
+   {
+      let response = http::Response::builder()
+         .status(status)
+         .header(RETRY_AFTER, retry_after)
+         .body("")
+         .unwrap();
+
+      Ok(response.into())
+   }
+
+   #[test]
+   fn retry_after_is_honoured_up_to_max_backoff()
+   {
+      let policy = RetryPolicy::default();
+
+      assert_eq!(policy.retry_after(&response(429, "3"), 0), Some(Duration::from_secs(3)));
+      assert_eq!(policy.retry_after(&response(503, "3600"), 0), Some(policy.max_backoff));
+      assert_eq!(policy.retry_after(&response(500, "3"), 0), None);
+      assert_eq!(policy.retry_after(&response(429, "3"), policy.max_retries), None);
+   }
+
+   #[test]
+   fn retry_after_dates_fall_back_to_the_backoff()
+   {
+      let policy = RetryPolicy {
+         initial_backoff: Duration::from_secs(1),
+         ..RetryPolicy::default()
+      };
+
+      let delay = policy
+         .retry_after(&response(503, "Wed, 21 Oct 2026 07:28:00 GMT"), 0)
+         .unwrap();
+      assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(1), "{delay:?}");
+   }
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+
+/// One requirement of a label selector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression
+{
+   Equal(Box<str>, Box<str>),
+   NotEqual(Box<str>, Box<str>),
+   In(Box<str>, Vec<Box<str>>),
+   NotIn(Box<str>, Vec<Box<str>>),
+   Exists(Box<str>),
+   DoesNotExist(Box<str>),
+}
+
+impl Expression
+{
+   pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool
+   {
+      match self {
+         Self::Equal(key, value) => labels.get(key.as_ref()).is_some_and(|x| x.as_str() == value.as_ref()),
+         Self::NotEqual(key, value) => labels.get(key.as_ref()).is_none_or(|x| x.as_str() != value.as_ref()),
+         Self::In(key, values) => labels
+            .get(key.as_ref())
+            .is_some_and(|x| values.iter().any(|value| x.as_str() == value.as_ref())),
+         Self::NotIn(key, values) => labels
+            .get(key.as_ref())
+            .is_none_or(|x| values.iter().all(|value| x.as_str() != value.as_ref())),
+         Self::Exists(key) => labels.contains_key(key.as_ref()),
+         Self::DoesNotExist(key) => !labels.contains_key(key.as_ref()),
+      }
+   }
+}
+
+impl std::fmt::Display for Expression
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Equal(key, value) => write!(f, "{key}={value}"),
+         Self::NotEqual(key, value) => write!(f, "{key}!={value}"),
+         Self::In(key, values) => write!(f, "{key} in ({})", values.join(",")),
+         Self::NotIn(key, values) => write!(f, "{key} notin ({})", values.join(",")),
+         Self::Exists(key) => write!(f, "{key}"),
+         Self::DoesNotExist(key) => write!(f, "!{key}"),
+      }
+   }
+}
+
+/// A set of label requirements that all have to hold, e.g. `k8s-app=cadvisor,tier in (a,b)`.
+///
+/// The rendered selector is passed as a query parameter, so it is url-encoded with the rest
+/// of the query when the request is built. Keys and values are passed through as they are:
+/// they are not validated here, and one the apiserver does not accept fails the request with
+/// a 400 `BadRequest`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelSelector
+{
+   expressions: Vec<Expression>,
+}
+
+impl LabelSelector
+{
+   pub fn new() -> Self
+   {
+      Self::default()
+   }
+
+   pub fn expression(mut self, expression: Expression) -> Self
+   {
+      self.expressions.push(expression);
+      self
+   }
+
+   pub fn equal(self, key: &str, value: &str) -> Self
+   {
+      self.expression(Expression::Equal(key.into(), value.into()))
+   }
+
+   pub fn not_equal(self, key: &str, value: &str) -> Self
+   {
+      self.expression(Expression::NotEqual(key.into(), value.into()))
+   }
+
+   pub fn one_of(self, key: &str, values: &[&str]) -> Self
+   {
+      let values = values.iter().map(|&value| value.into()).collect();
+      self.expression(Expression::In(key.into(), values))
+   }
+
+   pub fn none_of(self, key: &str, values: &[&str]) -> Self
+   {
+      let values = values.iter().map(|&value| value.into()).collect();
+      self.expression(Expression::NotIn(key.into(), values))
+   }
+
+   pub fn exists(self, key: &str) -> Self
+   {
+      self.expression(Expression::Exists(key.into()))
+   }
+
+   pub fn not_exists(self, key: &str) -> Self
+   {
+      self.expression(Expression::DoesNotExist(key.into()))
+   }
+
+   pub fn expressions(&self) -> &[Expression]
+   {
+      &self.expressions
+   }
+
+   pub fn is_empty(&self) -> bool
+   {
+      self.expressions.is_empty()
+   }
+
+   /// Whether an object with these labels is selected, evaluated the way the apiserver does.
+   pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool
+   {
+      self.expressions.iter().all(|expression| expression.matches(labels))
+   }
+}
+
+impl std::fmt::Display for LabelSelector
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write_joined(f, &self.expressions)
+   }
+}
+
+/// One requirement of a field selector; fields only support equality.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldExpression
+{
+   Equal(Box<str>, Box<str>),
+   NotEqual(Box<str>, Box<str>),
+}
+
+impl std::fmt::Display for FieldExpression
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Equal(field, value) => write!(f, "{field}={}", escape_field_value(value)),
+         Self::NotEqual(field, value) => write!(f, "{field}!={}", escape_field_value(value)),
+      }
+   }
+}
+
+/// A set of field requirements that all have to hold, e.g. `spec.nodeName=k3d-agent-0`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FieldSelector
+{
+   expressions: Vec<FieldExpression>,
+}
+
+impl FieldSelector
+{
+   pub fn new() -> Self
+   {
+      Self::default()
+   }
+
+   pub fn equal(mut self, field: &str, value: &str) -> Self
+   {
+      self.expressions.push(FieldExpression::Equal(field.into(), value.into()));
+      self
+   }
+
+   pub fn not_equal(mut self, field: &str, value: &str) -> Self
+   {
+      self.expressions.push(FieldExpression::NotEqual(field.into(), value.into()));
+      self
+   }
+
+   pub fn expressions(&self) -> &[FieldExpression]
+   {
+      &self.expressions
+   }
+
+   pub fn is_empty(&self) -> bool
+   {
+      self.expressions.is_empty()
+   }
+}
+
+impl std::fmt::Display for FieldSelector
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write_joined(f, &self.expressions)
+   }
+}
+
+fn write_joined(f: &mut std::fmt::Formatter<'_>, items: &[impl std::fmt::Display]) -> std::fmt::Result
+{
+   for (i, item) in items.iter().enumerate() {
+      if i != 0 {
+         write!(f, ",")?;
+      };
+      write!(f, "{item}")?;
+   }
+
+   Ok(())
+}
+
+/// Field values may contain the selector's own separators, which the apiserver expects escaped.
+fn escape_field_value(value: &str) -> String
+{
+   let mut escaped = String::with_capacity(value.len());
+
+   for c in value.chars() {
+      if matches!(c, '\\' | ',' | '=') {
+         escaped.push('\\');
+      };
+      escaped.push(c);
+   }
+
+   escaped
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String>
+   {
+      pairs.iter().map(|&(key, value)| (key.into(), value.into())).collect()
+   }
+
+   /// `selector` as the apiserver receives it, after being encoded into a query.
+   fn received(selector: &impl std::fmt::Display) -> String
+   {
+      let mut url = reqwest::Url::parse("https://apiserver/api/v1/pods").unwrap();
+      url.query_pairs_mut().append_pair("selector", &selector.to_string());
+      url.query_pairs().next().unwrap().1.into_owned()
+   }
+
+   #[test]
+   fn label_selectors_render_in_the_apiserver_syntax()
+   {
+      let selector = LabelSelector::new()
+         .equal("app.kubernetes.io/name", "cadvisor")
+         .not_equal("tier", "db")
+         .one_of("env", &["prod", "staging"])
+         .none_of("zone", &["a"])
+         .exists("k8s-app")
+         .not_exists("canary");
+
+      let rendered = "app.kubernetes.io/name=cadvisor,tier!=db,env in (prod,staging),zone notin (a),k8s-app,!canary";
+      assert_eq!(selector.to_string(), rendered);
+      assert_eq!(received(&selector), rendered);
+      assert_eq!(LabelSelector::new().to_string(), "");
+   }
+
+   #[test]
+   fn field_selectors_render_in_the_apiserver_syntax()
+   {
+      let selector = FieldSelector::new()
+         .equal("spec.nodeName", "k3d-agent-0")
+         .not_equal("status.phase", "Running");
+
+      let rendered = "spec.nodeName=k3d-agent-0,status.phase!=Running";
+      assert_eq!(selector.to_string(), rendered);
+      assert_eq!(received(&selector), rendered);
+   }
+
+   #[test]
+   fn field_values_escape_the_separators()
+   {
+      let selector = FieldSelector::new().equal("metadata.name", r"a,b=c\d");
+
+      assert_eq!(selector.to_string(), r"metadata.name=a\,b\=c\\d");
+      assert_eq!(received(&selector), r"metadata.name=a\,b\=c\\d");
+   }
+
+   #[test]
+   fn in_and_notin()
+   {
+      let selector = LabelSelector::new().one_of("env", &["prod", "staging"]);
+      assert!(selector.matches(&labels(&[("env", "prod")])));
+      assert!(!selector.matches(&labels(&[("env", "dev")])));
+      assert!(!selector.matches(&labels(&[])));
+
+      // like the apiserver, notin also selects objects without the label
+      let selector = LabelSelector::new().none_of("env", &["prod", "staging"]);
+      assert!(!selector.matches(&labels(&[("env", "staging")])));
+      assert!(selector.matches(&labels(&[("env", "dev")])));
+      assert!(selector.matches(&labels(&[])));
+   }
+
+   #[test]
+   fn exists_and_does_not_exist()
+   {
+      let selector = LabelSelector::new().exists("k8s-app");
+      assert!(selector.matches(&labels(&[("k8s-app", "")])));
+      assert!(!selector.matches(&labels(&[("app", "cadvisor")])));
+
+      let selector = LabelSelector::new().not_exists("k8s-app");
+      assert!(!selector.matches(&labels(&[("k8s-app", "cadvisor")])));
+      assert!(selector.matches(&labels(&[])));
+   }
+
+   #[test]
+   fn every_expression_has_to_hold()
+   {
+      let selector = LabelSelector::new().equal("app", "cadvisor").not_equal("tier", "db");
+
+      assert!(selector.matches(&labels(&[("app", "cadvisor")])));
+      assert!(selector.matches(&labels(&[("app", "cadvisor"), ("tier", "web")])));
+      assert!(!selector.matches(&labels(&[("app", "cadvisor"), ("tier", "db")])));
+      assert!(!selector.matches(&labels(&[("tier", "web")])));
+      assert!(LabelSelector::new().matches(&labels(&[])));
+   }
+}
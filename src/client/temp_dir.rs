@@ -0,0 +1,38 @@
+use std::path::{Path, PathBuf};
+
+/// A directory for the files a test writes, removed with everything in it when dropped.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir
+{
+   /// `name` keeps the directories of tests running at the same time apart.
+   pub fn new(name: &str) -> Self
+   {
+      let path = std::env::temp_dir().join(format!("kube-{}-{name}", std::process::id()));
+      let _ = std::fs::remove_dir_all(&path);
+      std::fs::create_dir_all(&path).unwrap();
+      Self(path)
+   }
+
+   pub fn path(&self) -> &Path
+   {
+      &self.0
+   }
+
+   /// Writes `contents` to `name` in the directory, returning its path.
+   pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf
+   {
+      let path = self.0.join(name);
+      std::fs::write(&path, contents).unwrap();
+      path
+   }
+}
+
+impl Drop for TempDir
+{
+   fn drop(&mut self)
+   {
+      let _ = std::fs::remove_dir_all(&self.0);
+   }
+}
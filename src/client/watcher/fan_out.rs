@@ -0,0 +1,320 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::task::{Context, Poll};
+
+use futures_core::stream::Stream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::sync::CancellationToken;
+
+use super::{WatcherError, WatcherEvent};
+
+type Item<Data> = Result<WatcherEvent<Data>, WatcherError>;
+
+/// What happens to events a subscription has no room left for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy
+{
+   /// Wait until the subscriber catches up, holding back the watcher and every other
+   /// subscriber meanwhile. Nothing is lost.
+   Block,
+   /// Drop the events the subscriber has no room for; `Subscription::missed` counts them.
+   Skip,
+   /// End the subscription, so a stuck consumer cannot hold back the others.
+   Disconnect,
+}
+
+#[derive(Debug)]
+struct Subscriber<Data>
+{
+   sender: mpsc::Sender<Item<Data>>,
+   lag: LagPolicy,
+   missed: Arc<AtomicU64>,
+}
+
+impl<Data> Clone for Subscriber<Data>
+{
+   fn clone(&self) -> Self
+   {
+      Self {
+         sender: self.sender.clone(),
+         lag: self.lag,
+         missed: self.missed.clone(),
+      }
+   }
+}
+
+/// Returned once every receiver of a watcher's events is gone.
+#[derive(Debug)]
+pub struct Closed;
+
+/// The sending side of a watcher, delivering each event to all of its subscriptions.
+#[derive(Debug)]
+pub struct Events<Data>
+{
+   subscribers: Arc<Mutex<Vec<Subscriber<Data>>>>,
+   kill_signal: CancellationToken,
+}
+
+impl<Data> Clone for Events<Data>
+{
+   fn clone(&self) -> Self
+   {
+      Self {
+         subscribers: self.subscribers.clone(),
+         kill_signal: self.kill_signal.clone(),
+      }
+   }
+}
+
+impl<Data: Clone> Events<Data>
+{
+   /// Sends that are waiting on a blocking subscriber give up once `kill_signal` is cancelled.
+   pub fn new(kill_signal: CancellationToken) -> Self
+   {
+      Self {
+         subscribers: Arc::new(Mutex::new(vec![])),
+         kill_signal,
+      }
+   }
+
+   /// Adds a receiver for every event sent from now on.
+   pub fn subscribe(&self, capacity: usize, lag: LagPolicy) -> Subscription<Data>
+   {
+      subscribe(&self.subscribers, capacity, lag)
+   }
+
+   /// A handle for adding subscriptions that does not keep them open once the sender is gone.
+   pub fn downgrade(&self) -> Subscribers<Data>
+   {
+      Subscribers(Arc::downgrade(&self.subscribers))
+   }
+
+   /// Delivers `item` to every subscription according to its lag policy, dropping those
+   /// that were closed or disconnected.
+   ///
+   /// Fails once the watcher is killed, even while a full `LagPolicy::Block` subscription
+   /// is being waited on.
+   pub async fn send(&self, item: Item<Data>) -> Result<(), Closed>
+   {
+      // the lock cannot be held while blocked on a subscriber
+      let subscribers = self.subscribers.lock().unwrap_or_else(PoisonError::into_inner).clone();
+      let mut dropped = vec![];
+
+      for subscriber in &subscribers {
+         let delivered = match subscriber.lag {
+            LagPolicy::Block => tokio::select! {
+               _ = self.kill_signal.cancelled() => return Err(Closed),
+               sent = subscriber.sender.send(item.clone()) => sent.is_ok(),
+            },
+            LagPolicy::Skip => match subscriber.sender.try_send(item.clone()) {
+               Ok(()) => true,
+               Err(TrySendError::Full(_)) => {
+                  subscriber.missed.fetch_add(1, Ordering::Relaxed);
+                  true
+               },
+               Err(TrySendError::Closed(_)) => false,
+            },
+            LagPolicy::Disconnect => subscriber.sender.try_send(item.clone()).is_ok(),
+         };
+
+         if !delivered {
+            dropped.push(subscriber.sender.clone());
+         };
+      }
+
+      let mut subscribers = self.subscribers.lock().unwrap_or_else(PoisonError::into_inner);
+      subscribers.retain(|subscriber| !dropped.iter().any(|sender| sender.same_channel(&subscriber.sender)));
+
+      if subscribers.is_empty() {
+         return Err(Closed);
+      };
+
+      Ok(())
+   }
+}
+
+fn subscribe<Data>(subscribers: &Mutex<Vec<Subscriber<Data>>>, capacity: usize, lag: LagPolicy) -> Subscription<Data>
+{
+   let (sender, receiver) = mpsc::channel(capacity.max(1));
+   let missed = Arc::new(AtomicU64::new(0));
+
+   let subscriber = Subscriber {
+      sender,
+      lag,
+      missed: missed.clone(),
+   };
+
+   subscribers.lock().unwrap_or_else(PoisonError::into_inner).push(subscriber);
+
+   Subscription { receiver, missed }
+}
+
+/// Adds subscriptions to a watcher's events for as long as its task is running.
+#[derive(Debug)]
+pub struct Subscribers<Data>(Weak<Mutex<Vec<Subscriber<Data>>>>);
+
+impl<Data> Subscribers<Data>
+{
+   /// Subscribing to a watcher that has stopped gives a subscription that has already ended.
+   pub fn subscribe(&self, capacity: usize, lag: LagPolicy) -> Subscription<Data>
+   {
+      match self.0.upgrade() {
+         Some(subscribers) => subscribe(&subscribers, capacity, lag),
+         None => Subscription {
+            receiver: mpsc::channel(1).1,
+            missed: Arc::new(AtomicU64::new(0)),
+         },
+      }
+   }
+}
+
+/// One consumer's copy of a watcher's events, see `Watcher::subscribe`.
+///
+/// The stream ends when the watcher stops, or when the subscription is disconnected for
+/// lagging behind.
+#[derive(Debug)]
+pub struct Subscription<Data>
+{
+   receiver: mpsc::Receiver<Item<Data>>,
+   missed: Arc<AtomicU64>,
+}
+
+impl<Data> Subscription<Data>
+{
+   pub async fn next(&mut self) -> Option<Item<Data>>
+   {
+      self.receiver.recv().await
+   }
+
+   /// How many events were dropped because this subscription was full, with `LagPolicy::Skip`.
+   pub fn missed(&self) -> u64
+   {
+      self.missed.load(Ordering::Relaxed)
+   }
+}
+
+impl<Data> Stream for Subscription<Data>
+{
+   type Item = Item<Data>;
+
+   fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>
+   {
+      self.get_mut().receiver.poll_recv(cx)
+   }
+}
+
+/// Stops a watcher from anywhere; clones all stop the same watcher.
+#[derive(Debug, Clone)]
+pub struct KillHandle(pub(super) CancellationToken);
+
+impl KillHandle
+{
+   pub fn kill(&self)
+   {
+      self.0.cancel();
+   }
+
+   pub fn is_killed(&self) -> bool
+   {
+      self.0.is_cancelled()
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn event(i: u32) -> Item<u32>
+   {
+      Ok(WatcherEvent::Event(i))
+   }
+
+   async fn received(subscription: &mut Subscription<u32>) -> Option<u32>
+   {
+      match subscription.next().await? {
+         Ok(WatcherEvent::Event(i)) => Some(i),
+         other => panic!("unexpected {other:?}"),
+      }
+   }
+
+   #[tokio::test]
+   async fn every_subscriber_gets_every_event()
+   {
+      let events = Events::new(CancellationToken::new());
+      let mut first = events.subscribe(4, LagPolicy::Block);
+      let mut second = events.subscribe(4, LagPolicy::Skip);
+
+      for i in 0..3 {
+         events.send(event(i)).await.unwrap();
+      }
+      drop(events);
+
+      for subscription in [&mut first, &mut second] {
+         for i in 0..3 {
+            assert_eq!(received(subscription).await, Some(i));
+         }
+         assert_eq!(received(subscription).await, None);
+         assert_eq!(subscription.missed(), 0);
+      }
+   }
+
+   #[tokio::test]
+   async fn skip_counts_the_events_it_drops()
+   {
+      let events = Events::new(CancellationToken::new());
+      let mut lagging = events.subscribe(2, LagPolicy::Skip);
+      let mut other = events.subscribe(8, LagPolicy::Block);
+
+      for i in 0..5 {
+         events.send(event(i)).await.unwrap();
+      }
+
+      assert_eq!(lagging.missed(), 3);
+      assert_eq!(received(&mut lagging).await, Some(0));
+      assert_eq!(received(&mut lagging).await, Some(1));
+
+      // once it has room again it receives new events
+      events.send(event(5)).await.unwrap();
+      assert_eq!(received(&mut lagging).await, Some(5));
+
+      for i in 0..6 {
+         assert_eq!(received(&mut other).await, Some(i));
+      }
+   }
+
+   #[tokio::test]
+   async fn disconnect_ends_only_the_lagging_subscription()
+   {
+      let events = Events::new(CancellationToken::new());
+      let mut lagging = events.subscribe(1, LagPolicy::Disconnect);
+      let mut other = events.subscribe(8, LagPolicy::Block);
+
+      for i in 0..3 {
+         events.send(event(i)).await.unwrap();
+      }
+
+      assert_eq!(received(&mut lagging).await, Some(0));
+      assert_eq!(received(&mut lagging).await, None);
+
+      for i in 0..3 {
+         assert_eq!(received(&mut other).await, Some(i));
+      }
+   }
+
+   #[tokio::test]
+   async fn send_fails_once_every_subscription_is_gone()
+   {
+      let events = Events::new(CancellationToken::new());
+      let first = events.subscribe(1, LagPolicy::Block);
+      let second = events.subscribe(1, LagPolicy::Disconnect);
+
+      drop(first);
+      events.send(event(0)).await.unwrap();
+
+      // the full disconnecting subscription was the last one left
+      assert!(events.send(event(1)).await.is_err());
+      drop(second);
+   }
+}
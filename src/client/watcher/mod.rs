@@ -0,0 +1,1123 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::StreamExt;
+use futures_core::stream::Stream;
+use k8s_openapi::{
+   ListableResource, Metadata, Resource,
+   apimachinery::pkg::apis::meta::v1::{ObjectMeta, WatchEvent},
+};
+use serde::de::DeserializeOwned;
+use tokio::time::Instant;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use super::{APIError, Api, ListParams, ResourceVersion, RetryPolicy, errors};
+
+mod fan_out;
+
+pub use fan_out::{KillHandle, LagPolicy, Subscription};
+use fan_out::{Events, Subscribers};
+
+/// A change to a watched resource, as reported by the watch or derived from a relist.
+#[derive(Debug, Clone)]
+pub enum ResourceEvent<K>
+{
+   Added(K),
+   Modified(K),
+   Deleted(K),
+   /// Nothing changed, but the watch has progressed to this resourceVersion.
+   Bookmark(ResourceVersion),
+}
+
+/// State of the watcher's connection to the apiserver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health
+{
+   /// The watch is open and events are current.
+   Connected,
+   /// The watch broke and is being reopened; once `Connected` again, whatever changed in the
+   /// meantime is delivered as events from a relist.
+   Reconnecting
+   {
+      attempt: u32,
+      reason: Box<str>,
+   },
+   /// Reconnecting has kept failing for longer than the retry policy allows. The watcher
+   /// keeps trying, but events may be stale until it is `Connected` again.
+   Degraded
+   {
+      attempt: u32,
+      reason: Box<str>,
+   },
+}
+
+impl std::fmt::Display for Health
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Connected => write!(f, "connected"),
+         Self::Reconnecting { attempt, reason } => write!(f, "reconnecting (attempt {attempt}): {reason}"),
+         Self::Degraded { attempt, reason } => write!(f, "degraded (attempt {attempt}): {reason}"),
+      }
+   }
+}
+
+/// The outcome of a periodic resync, see `WatcherConfig::resync`.
+///
+/// A drift is an object the fresh list disagreed with the watched state about, i.e. an event
+/// the watch should have delivered but did not. Its correction is delivered as events just
+/// before this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resync
+{
+   pub drifts: usize,
+   pub total_drifts: u64,
+   pub resyncs: u64,
+}
+
+impl std::fmt::Display for Resync
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write!(
+         f,
+         "resync found {} drifts ({} in {} resyncs)",
+         self.drifts, self.total_drifts, self.resyncs
+      )
+   }
+}
+
+/// What a watcher yields: a change to the watched resources or to the watcher's own health.
+#[derive(Debug, Clone)]
+pub enum WatcherEvent<Data>
+{
+   Event(Data),
+   Health(Health),
+   Resynced(Resync),
+}
+
+pub type Sender<Data> = Events<Data>;
+
+/// Events the watcher itself buffers before its task waits for them to be read.
+const CAPACITY: usize = 100;
+
+/// A task watching a resource in the background, yielding its events in order.
+///
+/// The watcher is a stream of its events, and `subscribe` hands out further copies of them.
+/// Dropping the watcher, or killing it, stops the task.
+#[derive(Debug)]
+pub struct Watcher<Resource, Data>
+{
+   subscribers: Subscribers<Data>,
+   receiver: Subscription<Data>,
+   kill_signal: CancellationToken,
+   guard: DropGuard,
+   handle: tokio::task::JoinHandle<()>,
+   phantom: std::marker::PhantomData<fn() -> Resource>,
+}
+
+/// Watches one kind of resource, see `Watcher::new`.
+pub type ResourceWatcher<K> = Watcher<K, ResourceEvent<K>>;
+
+/// How a `ResourceWatcher` watches, as opposed to the `ListParams` of what it watches.
+#[derive(Debug, Clone)]
+pub struct WatcherConfig
+{
+   /// How long each watch request stays open before it is resumed.
+   pub timeout: Duration,
+   /// How often to relist to catch events the watch silently dropped, if at all.
+   pub resync: Option<Duration>,
+}
+
+impl WatcherConfig
+{
+   pub fn new(timeout: Duration) -> Self
+   {
+      Self { timeout, resync: None }
+   }
+
+   pub fn resync(mut self, interval: Duration) -> Self
+   {
+      self.resync = Some(interval);
+      self
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatcherErrorKind
+{
+   StreamRetrieveError,
+   ReconciliationError,
+   StreamNextError,
+   Termination,
+}
+
+/// Shared, so that every subscription of a watcher receives the error that ended it.
+#[derive(Debug, Clone)]
+pub struct WatcherError
+{
+   cause: WatcherErrorKind,
+   error: Arc<APIError>,
+}
+
+impl std::fmt::Display for WatcherErrorKind
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::StreamRetrieveError => write!(f, "failed to open watch stream"),
+         Self::ReconciliationError => write!(f, "failed to reconcile state"),
+         Self::StreamNextError => write!(f, "failed to read watch stream"),
+         Self::Termination => write!(f, "failed to terminate watcher"),
+      }
+   }
+}
+
+impl std::fmt::Display for WatcherError
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write!(f, "{}: {}", self.cause, self.error)
+   }
+}
+
+impl std::error::Error for WatcherError
+{
+   fn source(&self) -> Option<&(dyn std::error::Error + 'static)>
+   {
+      Some(self.error.as_ref())
+   }
+}
+
+impl WatcherError
+{
+   /// Which stage of the watcher failed.
+   pub fn kind(&self) -> &WatcherErrorKind
+   {
+      &self.cause
+   }
+
+   pub fn error(&self) -> &APIError
+   {
+      &self.error
+   }
+
+   pub fn into_error(self) -> Arc<APIError>
+   {
+      self.error
+   }
+
+   pub fn is_retryable(&self) -> bool
+   {
+      self.error.is_retryable()
+   }
+
+   pub fn is_not_found(&self) -> bool
+   {
+      self.error.is_not_found()
+   }
+
+   pub fn is_gone(&self) -> bool
+   {
+      self.error.is_gone()
+   }
+
+   pub fn is_forbidden(&self) -> bool
+   {
+      self.error.is_forbidden()
+   }
+
+   /// Whether a watcher keeps going after this error; only rejected credentials end it, and
+   /// only when refreshing them did not help.
+   pub fn is_recoverable(&self) -> bool
+   {
+      !(self.error.is_unauthorized() || self.error.is_forbidden())
+   }
+
+   fn recon(error: APIError) -> Self
+   {
+      Self {
+         cause: WatcherErrorKind::ReconciliationError,
+         error: Arc::new(error),
+      }
+   }
+
+   fn stream(error: APIError) -> Self
+   {
+      Self {
+         cause: WatcherErrorKind::StreamRetrieveError,
+         error: Arc::new(error),
+      }
+   }
+
+   pub(crate) fn next(error: APIError) -> Self
+   {
+      Self {
+         cause: WatcherErrorKind::StreamNextError,
+         error: Arc::new(error),
+      }
+   }
+
+   fn terminate() -> Self
+   {
+      Self {
+         cause: WatcherErrorKind::Termination,
+         error: Arc::new(APIError::WatcherTermination),
+      }
+   }
+}
+
+impl<Resource, Data: Clone> Watcher<Resource, Data>
+{
+   /// Runs `task` in the background, handing it the sending end of the events and the
+   /// kill signal.
+   pub(crate) fn spawn<F>(task: impl FnOnce(Sender<Data>, CancellationToken) -> F) -> Self
+   where
+      F: Future<Output = ()> + Send + 'static,
+   {
+      let kill_signal = CancellationToken::new();
+      let events = Events::new(kill_signal.clone());
+      let receiver = events.subscribe(CAPACITY, LagPolicy::Block);
+      let guard = kill_signal.clone().drop_guard();
+      let subscribers = events.downgrade();
+      let handle = tokio::spawn(task(events, kill_signal.clone()));
+
+      Self {
+         subscribers,
+         receiver,
+         kill_signal,
+         guard,
+         handle,
+         phantom: std::marker::PhantomData,
+      }
+   }
+
+   /// The next event, or the error that ended the watcher.
+   pub async fn next_event(&mut self) -> Result<WatcherEvent<Data>, WatcherError> {
+      match self.receiver.next().await {
+         Some(Ok(v)) => Ok(v),
+         Some(Err(e)) => Err(e),
+         None => Err(WatcherError::next(APIError::ChannelSenderDropped)),
+      }
+   }
+
+   /// Another receiver of every event from now on, including the error ending the watcher.
+   ///
+   /// The watcher's own events are delivered with `LagPolicy::Block`, so a watcher that is
+   /// only read through subscriptions should be turned into a `KillHandle` with
+   /// `into_kill_handle`.
+   pub fn subscribe(&self, capacity: usize, lag: LagPolicy) -> Subscription<Data>
+   {
+      self.subscribers.subscribe(capacity, lag)
+   }
+
+   pub fn kill_handle(&self) -> KillHandle
+   {
+      KillHandle(self.kill_signal.clone())
+   }
+
+   /// Stops reading the watcher itself, leaving its task running for its subscriptions
+   /// until it is killed or they are all dropped.
+   pub fn into_kill_handle(self) -> KillHandle
+   {
+      KillHandle(self.guard.disarm())
+   }
+
+   /// Stops the task and waits for it to finish.
+   pub async fn kill(self) -> Result<(), WatcherError> {
+      let Self {
+         subscribers,
+         receiver,
+         kill_signal,
+         guard,
+         handle,
+         phantom: _,
+      } = self;
+
+      kill_signal.cancel();
+      // a task blocked on our full receiver could otherwise never see the signal
+      drop((subscribers, receiver, guard));
+
+      match handle.await {
+         Ok(_) => Ok(()),
+         Err(_) => Err(WatcherError::terminate()),
+      }
+   }
+}
+
+impl<Resource, Data> Stream for Watcher<Resource, Data>
+{
+   type Item = Result<WatcherEvent<Data>, WatcherError>;
+
+   /// Unlike `next`, the stream simply ends once the watcher has stopped.
+   fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>
+   {
+      Pin::new(&mut self.get_mut().receiver).poll_next(cx)
+   }
+}
+
+impl<K> Watcher<K, ResourceEvent<K>>
+where
+   K: Resource + ListableResource + Metadata<Ty = ObjectMeta> + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+   /// Lists the objects matching `params`, yielding each as `Added`, then watches them.
+   pub fn new(api: Api<K>, params: ListParams, config: WatcherConfig) -> Self
+   {
+      Self::resume(api, params, vec![], "", config)
+   }
+
+   /// Watches from `version`, `objects` being what a list at that version returned.
+   ///
+   /// Events start with whatever changed after the list. An empty version lists first,
+   /// like `new`.
+   pub fn resume(api: Api<K>, params: ListParams, objects: Vec<K>, version: &str, config: WatcherConfig) -> Self
+   {
+      let state = Known::new(objects, version);
+      Self::spawn(|sender, kill_signal| watch(api, params, state, config, sender, kill_signal))
+   }
+}
+
+/// The objects a watcher has seen, keyed by uid, and the version they are current at.
+struct Known<K>
+{
+   objects: HashMap<Box<str>, K>,
+   version: ResourceVersion,
+}
+
+/// Objects are keyed by uid, falling back to namespace/name for the odd object without one.
+pub(crate) fn key<K: Metadata<Ty = ObjectMeta>>(object: &K) -> Box<str>
+{
+   let metadata = object.metadata();
+
+   match &metadata.uid {
+      Some(uid) => uid.as_str().into(),
+      None => format!(
+         "{}/{}",
+         metadata.namespace.as_deref().unwrap_or_default(),
+         metadata.name.as_deref().unwrap_or_default()
+      )
+      .into(),
+   }
+}
+
+impl<K> Known<K>
+where
+   K: Metadata<Ty = ObjectMeta> + Clone,
+{
+   fn new(objects: Vec<K>, version: &str) -> Self
+   {
+      Self {
+         objects: objects.into_iter().map(|object| (key(&object), object)).collect(),
+         version: version.into(),
+      }
+   }
+
+   /// Replaces the known objects with a fresh list, returning the events that turn one
+   /// into the other, followed by a bookmark at the list's version. Objects whose
+   /// resourceVersion did not change produce no event.
+   fn reconcile(&mut self, objects: Vec<K>, version: &str) -> Vec<ResourceEvent<K>>
+   {
+      let mut old = std::mem::take(&mut self.objects);
+      let mut events = vec![];
+
+      for object in objects {
+         let key = key(&object);
+
+         match old.remove(&key) {
+            None => events.push(ResourceEvent::Added(object.clone())),
+            Some(previous) if previous.metadata().resource_version != object.metadata().resource_version => {
+               events.push(ResourceEvent::Modified(object.clone()));
+            },
+            Some(_) => (),
+         };
+
+         self.objects.insert(key, object);
+      }
+
+      events.extend(old.into_values().map(ResourceEvent::Deleted));
+      self.version = version.into();
+      events.push(ResourceEvent::Bookmark(self.version.clone()));
+      events
+   }
+
+   /// Corrects the known objects with a list taken while the watch kept running, at
+   /// resourceVersion `version`, returning the events that do so and how many are drifts.
+   ///
+   /// Whatever the watch delivered after the list is kept: objects known at a newer
+   /// resourceVersion than listed, objects created after the list, and objects in `deleted`,
+   /// the deletions the watch delivered while listing, deleted after it. Everything else the
+   /// list disagrees with is a drift.
+   fn resync(
+      &mut self,
+      objects: Vec<K>,
+      version: &str,
+      deleted: &HashMap<Box<str>, ResourceVersion>,
+   ) -> (Vec<ResourceEvent<K>>, usize)
+   {
+      let mut unlisted: HashSet<Box<str>> = self.objects.keys().cloned().collect();
+      let mut events = vec![];
+      let mut drifts = 0;
+
+      for object in objects {
+         let key = key(&object);
+         unlisted.remove(&key);
+
+         let listed = object.metadata().resource_version.as_deref();
+         let event = match self.objects.get(&key) {
+            None if deleted.get(&key).is_some_and(|deletion| newer(deletion, version)) => continue,
+            None => ResourceEvent::Added(object.clone()),
+            Some(known) => match known.metadata().resource_version.as_deref() {
+               Some(current) if Some(current) == listed || listed.is_some_and(|listed| newer(current, listed)) => {
+                  continue;
+               },
+               // seeded by `Watcher::resume`, so there is nothing to compare with
+               None => {
+                  events.push(ResourceEvent::Modified(object.clone()));
+                  self.objects.insert(key, object);
+                  continue;
+               },
+               Some(_) => ResourceEvent::Modified(object.clone()),
+            },
+         };
+
+         drifts += 1;
+         events.push(event);
+         self.objects.insert(key, object);
+      }
+
+      for key in unlisted {
+         let created_since = self.objects[&key]
+            .metadata()
+            .resource_version
+            .as_deref()
+            .is_some_and(|current| newer(current, version));
+
+         if !created_since && let Some(object) = self.objects.remove(&key) {
+            drifts += 1;
+            events.push(ResourceEvent::Deleted(object));
+         };
+      }
+
+      (events, drifts)
+   }
+
+   fn apply(&mut self, event: WatchEvent<K>) -> Option<ResourceEvent<K>>
+   {
+      if let Some(version) = event_version(&event) {
+         self.version = version.into();
+      };
+
+      let event = match event {
+         WatchEvent::Added(object) => {
+            self.objects.insert(key(&object), object.clone());
+            ResourceEvent::Added(object)
+         },
+         WatchEvent::Modified(object) => {
+            self.objects.insert(key(&object), object.clone());
+            ResourceEvent::Modified(object)
+         },
+         WatchEvent::Deleted(object) => {
+            self.objects.remove(&key(&object));
+            ResourceEvent::Deleted(object)
+         },
+         WatchEvent::Bookmark { resource_version, .. } => ResourceEvent::Bookmark(resource_version.into()),
+         WatchEvent::ErrorStatus(_) | WatchEvent::ErrorOther(_) => return None,
+      };
+
+      Some(event)
+   }
+}
+
+/// The resourceVersion a watch can be resumed from after this event.
+fn event_version<K: Metadata<Ty = ObjectMeta>>(event: &WatchEvent<K>) -> Option<&str>
+{
+   match event {
+      WatchEvent::Added(object) | WatchEvent::Modified(object) | WatchEvent::Deleted(object) => {
+         object.metadata().resource_version.as_deref()
+      },
+      WatchEvent::Bookmark { resource_version, .. } => Some(resource_version),
+      WatchEvent::ErrorStatus(_) | WatchEvent::ErrorOther(_) => None,
+   }
+}
+
+/// Whether resourceVersion `a` is newer than `b`.
+///
+/// ResourceVersions are opaque, but the apiserver's are etcd revisions, which only grow;
+/// versions that are not numbers are never considered newer.
+fn newer(a: &str, b: &str) -> bool
+{
+   match (a.parse::<u64>(), b.parse::<u64>()) {
+      (Ok(a), Ok(b)) => a > b,
+      _ => false,
+   }
+}
+
+/// All objects matching `params` and the resourceVersion of the list.
+async fn list<K>(api: &Api<K>, params: &ListParams) -> Result<(Vec<K>, ResourceVersion), WatcherError>
+where
+   K: Resource + ListableResource + DeserializeOwned + Clone,
+{
+   let list = api.list_all(params).await.map_err(WatcherError::recon)?;
+   let version = list
+      .metadata
+      .resource_version
+      .ok_or(errors::RESOURCE_VERSION)
+      .map_err(WatcherError::recon)?;
+
+   Ok((list.items, version.into()))
+}
+
+/// Lists the objects again, returning the events since the last known state.
+async fn relist<K>(
+   api: &Api<K>,
+   params: &ListParams,
+   known: &mut Known<K>,
+) -> Result<Vec<ResourceEvent<K>>, WatcherError>
+where
+   K: Resource + ListableResource + Metadata<Ty = ObjectMeta> + DeserializeOwned + Clone,
+{
+   let (objects, version) = list(api, params).await?;
+   Ok(known.reconcile(objects, &version))
+}
+
+
+async fn get_stream<K>(
+   api: &Api<K>,
+   params: &ListParams,
+   version: &str,
+   duration: Duration,
+) -> Result<impl Stream<Item = Result<Bytes, reqwest::Error>> + use<K>, WatcherError>
+where
+   K: Resource + DeserializeOwned,
+{
+   let response = api
+      .watch(params, version, duration)
+      .await
+      .map_err(WatcherError::stream)?;
+
+   Ok(response.bytes_stream())
+}
+
+/// Completes at `deadline`, or never without one.
+async fn resync_due(deadline: Option<Instant>)
+{
+   match deadline {
+      Some(deadline) => tokio::time::sleep_until(deadline).await,
+      None => std::future::pending().await,
+   }
+}
+
+/// Tracks failed reconnects so the watcher can back off and report its health.
+struct Connection
+{
+   policy: RetryPolicy,
+   failures: u32,
+   connected: bool,
+   /// The last failure rejected the credentials, which `Base::send` has since dropped.
+   rejected: bool,
+}
+
+impl Connection
+{
+   fn new(policy: RetryPolicy) -> Self
+   {
+      Self {
+         policy,
+         failures: 0,
+         connected: false,
+         rejected: false,
+      }
+   }
+
+   fn backoff(&self) -> Option<Duration>
+   {
+      self.failures.checked_sub(1).map(|attempt| self.policy.backoff(attempt))
+   }
+
+   /// Returns false when the watcher should stop, either because the error is not
+   /// recoverable, which has been reported to the receiver, or because the receiver is gone.
+   async fn failed<Data: Clone>(&mut self, error: WatcherError, sender: &Sender<Data>) -> bool
+   {
+      // the first rejection may be a rotated token, which the next request reads afresh
+      if !error.is_recoverable() && std::mem::replace(&mut self.rejected, true) {
+         let _ = sender.send(Err(error)).await;
+         return false;
+      };
+      self.rejected = !error.is_recoverable();
+
+      self.failures += 1;
+      self.connected = false;
+
+      let attempt = self.failures;
+      let reason = error.to_string().into();
+      let health = if self.failures > self.policy.max_retries {
+         Health::Degraded { attempt, reason }
+      } else {
+         Health::Reconnecting { attempt, reason }
+      };
+
+      sender.send(Ok(WatcherEvent::Health(health))).await.is_ok()
+   }
+
+   async fn connected<Data: Clone>(&mut self, sender: &Sender<Data>) -> bool
+   {
+      if self.connected {
+         return true;
+      };
+
+      self.connected = true;
+      self.failures = 0;
+      self.rejected = false;
+      sender.send(Ok(WatcherEvent::Health(Health::Connected))).await.is_ok()
+   }
+}
+
+async fn get_events<K: DeserializeOwned>(
+   stream: &mut (impl Stream<Item = Result<Bytes, reqwest::Error>> + Unpin),
+   event_builder: &mut Vec<u8>,
+) -> Result<Option<Vec<WatchEvent<K>>>, WatcherError>
+{
+   let mut events = vec![];
+
+   let bytes = match stream.next().await {
+      None => return Ok(None),
+      Some(Err(e)) => return Err(WatcherError::next(e.into())),
+      Some(Ok(bytes)) => bytes,
+   };
+
+   for byte in bytes {
+      if byte == b'\n' {
+         let bytes = std::mem::take(event_builder);
+         let event: WatchEvent<K> = serde_json::from_slice(&bytes).map_err(|e| WatcherError::next(e.into()))?;
+         event_builder.clear();
+         events.push(event);
+         continue;
+      };
+
+      event_builder.push(byte);
+   }
+
+   Ok(Some(events))
+}
+
+/// Watches the objects until killed, keeping `known` current.
+///
+/// Broken connections, truncated or invalid chunks and failing requests are retried with
+/// the client's backoff, and the objects are reconciled against a fresh list before
+/// watching again, so nothing missed in between is lost. Only credentials rejected twice in
+/// a row end the watch, since the first rejection refreshes them.
+///
+/// With `config.resync` set, the objects are also listed that long after the last list,
+/// while the watch keeps running, and the drifts the list corrects are reported as
+/// `WatcherEvent::Resynced`.
+async fn watch<K>(
+   api: Api<K>,
+   params: ListParams,
+   mut known: Known<K>,
+   config: WatcherConfig,
+   sender: Sender<ResourceEvent<K>>,
+   kill_signal: CancellationToken,
+)
+where
+   K: Resource + ListableResource + Metadata<Ty = ObjectMeta> + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+   // the watch loop backs off and reconnects itself, so its requests are only sent once
+   let mut connection = Connection::new(api.base().retry_policy().clone());
+   let api = api.with_retry_policy(RetryPolicy::none());
+   let mut resync = known.version.is_empty();
+
+   let next_resync = || config.resync.map(|interval| Instant::now() + interval);
+   let mut resync_deadline = next_resync();
+   let mut resyncs = 0;
+   let mut total_drifts = 0;
+
+   'reconnection: loop {
+      if let Some(backoff) = connection.backoff() {
+         tokio::select! {
+            _ = kill_signal.cancelled() => return,
+            _ = tokio::time::sleep(backoff) => (),
+         };
+      };
+
+      if resync {
+         let events = tokio::select! {
+            _ = kill_signal.cancelled() => return,
+            events = relist(&api, &params, &mut known) => events,
+         };
+
+         let events = match events {
+            Ok(events) => events,
+            Err(e) => {
+               if !connection.failed(e, &sender).await {
+                  return;
+               };
+               continue 'reconnection;
+            },
+         };
+
+         for event in events {
+            if sender.send(Ok(WatcherEvent::Event(event))).await.is_err() {
+               return;
+            };
+         }
+
+         resync = false;
+         resync_deadline = next_resync();
+      };
+
+      let stream = tokio::select! {
+            _ = kill_signal.cancelled() => return,
+            stream = get_stream(&api, &params, &known.version, config.timeout) => stream,
+      };
+
+      let mut stream = match stream {
+         Ok(stream) => stream,
+         Err(e) if e.is_gone() => {
+            resync = true;
+            continue 'reconnection;
+         },
+         Err(e) => {
+            if !connection.failed(e, &sender).await {
+               return;
+            };
+            resync = true;
+            continue 'reconnection;
+         },
+      };
+
+      if !connection.connected(&sender).await {
+         return;
+      };
+
+      let mut event_builder = vec![];
+      // a periodic list in flight, and the deletions the watch delivered meanwhile
+      let mut listing = None;
+      let mut deleted = HashMap::new();
+
+      loop {
+         let events = tokio::select! {
+            _ = kill_signal.cancelled() => return,
+            _ = resync_due(resync_deadline), if listing.is_none() => {
+               listing = Some(Box::pin(list(&api, &params)));
+               continue;
+            },
+            listed = async { listing.as_mut().expect("guarded by is_some").await }, if listing.is_some() => {
+               listing = None;
+
+               let (objects, version) = match listed {
+                  Ok(listed) => listed,
+                  Err(e) => {
+                     if !connection.failed(e, &sender).await {
+                        return;
+                     };
+                     resync = true;
+                     continue 'reconnection;
+                  },
+               };
+
+               let (events, drifts) = known.resync(objects, &version, &std::mem::take(&mut deleted));
+               for event in events {
+                  if sender.send(Ok(WatcherEvent::Event(event))).await.is_err() {
+                     return;
+                  };
+               }
+
+               resyncs += 1;
+               total_drifts += drifts as u64;
+               let resync = Resync {
+                  drifts,
+                  total_drifts,
+                  resyncs,
+               };
+               if sender.send(Ok(WatcherEvent::Resynced(resync))).await.is_err() {
+                  return;
+               };
+
+               resync_deadline = next_resync();
+               continue;
+            },
+            events = get_events(&mut stream, &mut event_builder) => events,
+         };
+
+         let events = match events {
+            Err(e) => {
+               if !connection.failed(e, &sender).await {
+                  return;
+               };
+               resync = true;
+               continue 'reconnection;
+            },
+            // timeoutSeconds elapsed, resume from the last version seen
+            Ok(None) => continue 'reconnection,
+            Ok(Some(events)) => events,
+         };
+
+         for event in events {
+            // the apiserver closes the watch after an ERROR, usually 410 for an expired
+            // version, so whatever was missed is recovered from a fresh list
+            if let WatchEvent::ErrorStatus(status) = &event {
+               let error = APIError::from(status.clone());
+
+               if !error.is_gone() && !connection.failed(WatcherError::next(error), &sender).await {
+                  return;
+               };
+               resync = true;
+               continue 'reconnection;
+            };
+
+            let Some(event) = known.apply(event) else {
+               resync = true;
+               continue 'reconnection;
+            };
+
+            if listing.is_some()
+               && let ResourceEvent::Deleted(object) = &event
+               && let Some(version) = &object.metadata().resource_version
+            {
+               deleted.insert(key(object), version.as_str().into());
+            };
+
+            if sender.send(Ok(WatcherEvent::Event(event))).await.is_err() {
+               return;
+            };
+         }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+   use k8s_openapi::api::core::v1::ConfigMap;
+
+   fn object(uid: &str, version: &str) -> ConfigMap
+   {
+      ConfigMap {
+         metadata: ObjectMeta {
+            uid: Some(uid.into()),
+            resource_version: Some(version.into()),
+            ..Default::default()
+         },
+         ..Default::default()
+      }
+   }
+
+   fn summary(events: &[ResourceEvent<ConfigMap>]) -> Vec<String>
+   {
+      let mut summary: Vec<_> = events
+         .iter()
+         .map(|event| match event {
+            ResourceEvent::Added(object) => format!("added {}", key(object)),
+            ResourceEvent::Modified(object) => format!("modified {}", key(object)),
+            ResourceEvent::Deleted(object) => format!("deleted {}", key(object)),
+            ResourceEvent::Bookmark(version) => format!("bookmark {version}"),
+         })
+         .collect();
+      summary.sort();
+      summary
+   }
+
+   #[test]
+   fn resync_keeps_what_the_watch_saw_after_the_list()
+   {
+      // listed at 12 while the watch went on to 14
+      let mut known = Known::new(vec![object("modified", "13"), object("created", "14")], "14");
+      let deleted = HashMap::from([("deleted".into(), "14".into())]);
+      let listed = vec![object("modified", "11"), object("deleted", "8")];
+
+      let (events, drifts) = known.resync(listed, "12", &deleted);
+
+      assert!(events.is_empty());
+      assert_eq!(drifts, 0);
+      assert_eq!(&*known.version, "14");
+      assert_eq!(known.objects["modified"].metadata.resource_version.as_deref(), Some("13"));
+      assert!(known.objects.contains_key("created"));
+   }
+
+   #[test]
+   fn resync_corrects_what_the_watch_missed()
+   {
+      let mut known = Known::new(vec![object("same", "3"), object("modified", "5"), object("deleted", "4")], "10");
+      let listed = vec![object("same", "3"), object("modified", "9"), object("added", "7")];
+
+      let (events, drifts) = known.resync(listed, "12", &HashMap::new());
+
+      assert_eq!(summary(&events), ["added added", "deleted deleted", "modified modified"]);
+      assert_eq!(drifts, 3);
+      assert_eq!(known.objects.len(), 3);
+   }
+
+   #[test]
+   fn reconcile_replaces_the_objects()
+   {
+      let mut known = Known::new(vec![object("same", "3"), object("modified", "5"), object("deleted", "4")], "10");
+      let listed = vec![object("same", "3"), object("modified", "9"), object("added", "7")];
+
+      let events = known.reconcile(listed, "12");
+
+      assert_eq!(
+         summary(&events),
+         ["added added", "bookmark 12", "deleted deleted", "modified modified"]
+      );
+      assert_eq!(&*known.version, "12");
+   }
+
+   /// A watcher whose task sends events until it is told to stop.
+   fn flooding() -> Watcher<(), u32>
+   {
+      Watcher::spawn(|sender, _| async move {
+         for i in 0.. {
+            if sender.send(Ok(WatcherEvent::Event(i))).await.is_err() {
+               return;
+            };
+         }
+      })
+   }
+
+   #[tokio::test]
+   async fn kill_returns_with_a_full_receiver()
+   {
+      let watcher = flooding();
+      tokio::task::yield_now().await;
+
+      tokio::time::timeout(Duration::from_secs(1), watcher.kill())
+         .await
+         .expect("kill hung")
+         .unwrap();
+   }
+
+   #[tokio::test]
+   async fn kill_returns_with_a_full_blocking_subscription()
+   {
+      let watcher = flooding();
+      let _subscription = watcher.subscribe(1, LagPolicy::Block);
+      tokio::task::yield_now().await;
+
+      tokio::time::timeout(Duration::from_secs(1), watcher.kill())
+         .await
+         .expect("kill hung")
+         .unwrap();
+   }
+
+   /// Serves ConfigMaps to the token in `token_file` only once it reads `new`, rotating the
+   /// file to it on the first rejection when `rotate` is set.
+   async fn apiserver(token_file: std::path::PathBuf, rotate: bool) -> String
+   {
+      use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+      const REJECTED: &str = r#"{"kind":"Status","apiVersion":"v1","status":"Failure","reason":"Unauthorized","code":401}"#;
+      const LIST: &str = r#"{"apiVersion":"v1","kind":"ConfigMapList","metadata":{"resourceVersion":"1"},"items":[]}"#;
+
+      let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap();
+
+      tokio::spawn(async move {
+         loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let token_file = token_file.clone();
+
+            tokio::spawn(async move {
+               let mut request = vec![];
+               while !request.ends_with(b"\r\n\r\n") {
+                  let mut byte = [0];
+                  if socket.read(&mut byte).await.unwrap() == 0 {
+                     return;
+                  };
+                  request.push(byte[0]);
+               }
+               let request = String::from_utf8(request).unwrap();
+
+               let (status, body) = if !request.contains("authorization: Bearer new\r\n") {
+                  if rotate {
+                     std::fs::write(&token_file, "new").unwrap();
+                  };
+                  ("401 Unauthorized", REJECTED)
+               } else if request.contains("watch=true") {
+                  ("200 OK", "")
+               } else {
+                  ("200 OK", LIST)
+               };
+
+               let response = format!("HTTP/1.1 {status}\r\ncontent-type: application/json\r\nconnection: close\r\n\r\n{body}");
+               socket.write_all(response.as_bytes()).await.unwrap();
+
+               // an open watch that never delivers an event
+               if body.is_empty() {
+                  std::future::pending::<()>().await;
+               };
+            });
+         }
+      });
+
+      format!("http://{address}")
+   }
+
+   async fn token_file_watcher(name: &str, rotate: bool) -> ResourceWatcher<ConfigMap>
+   {
+      let token_file = std::env::temp_dir().join(format!("kube-watch-token-{}-{name}", std::process::id()));
+      std::fs::write(&token_file, "old").unwrap();
+
+      let config = crate::client::Config {
+         context: "test".into(),
+         namespace: "default".into(),
+         cluster: crate::client::Cluster {
+            server: apiserver(token_file.clone(), rotate).await,
+            ..Default::default()
+         },
+         user: crate::client::AuthInfo {
+            token_file: Some(token_file.to_string_lossy().into()),
+            ..Default::default()
+         },
+      };
+      let client = crate::client::KubeClient::from_config(config).unwrap().with_retry_policy(RetryPolicy {
+         initial_backoff: Duration::from_millis(10),
+         ..RetryPolicy::default()
+      });
+
+      ResourceWatcher::new(
+         client.default_namespaced(),
+         ListParams::default(),
+         WatcherConfig::new(Duration::from_secs(60)),
+      )
+   }
+
+   #[tokio::test]
+   async fn retries_once_after_a_rotated_token_is_rejected()
+   {
+      let mut watcher = token_file_watcher("rotated", true).await;
+
+      let rejected = watcher.next_event().await.unwrap();
+      assert!(matches!(rejected, WatcherEvent::Health(Health::Reconnecting { attempt: 1, .. })));
+
+      // the relist's bookmark comes first
+      let connected = tokio::time::timeout(Duration::from_secs(5), async {
+         loop {
+            match watcher.next_event().await {
+               Ok(WatcherEvent::Event(_)) => continue,
+               health => return health,
+            };
+         }
+      });
+      let connected = connected.await.expect("never reconnected");
+      assert!(matches!(connected, Ok(WatcherEvent::Health(Health::Connected))), "{connected:?}");
+   }
+
+   #[tokio::test]
+   async fn stops_when_credentials_are_rejected_twice()
+   {
+      let mut watcher = token_file_watcher("stale", false).await;
+
+      let rejected = watcher.next_event().await.unwrap();
+      assert!(matches!(rejected, WatcherEvent::Health(Health::Reconnecting { attempt: 1, .. })));
+
+      let stopped = tokio::time::timeout(Duration::from_secs(5), watcher.next_event()).await.expect("never stopped");
+      assert!(stopped.is_err_and(|e| e.error().is_unauthorized()));
+   }
+}
@@ -0,0 +1,204 @@
+use bytes::Bytes;
+use reqwest::{StatusCode, Upgraded, header};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio_tungstenite::{
+   WebSocketStream,
+   tungstenite::{
+      Message,
+      handshake::{client::generate_key, derive_accept_key},
+      protocol::Role,
+   },
+};
+
+use super::{APIError, Base, response_into_error};
+
+/// The channel protocol `portforward` and `exec` both speak, each message starting with the
+/// byte of the channel it belongs to.
+pub const V4_CHANNEL: &str = "v4.channel.k8s.io";
+
+/// v4 with a way to close a single channel, which `exec` needs to end stdin.
+pub const V5_CHANNEL: &str = "v5.channel.k8s.io";
+
+pub type WebSocket = WebSocketStream<Upgraded>;
+
+/// Upgrades a GET of `path` to a WebSocket speaking one of `protocols`, returning it with the
+/// protocol the apiserver picked.
+///
+/// The request goes through `Base::send`, so it is authenticated and rate limited like any
+/// other. An apiserver refusing the upgrade answers with a `Status`, which is returned as is.
+pub async fn connect(
+   base: &Base,
+   path: &str,
+   query: &[(&str, String)],
+   protocols: &[&str],
+) -> Result<(WebSocket, Box<str>), APIError>
+{
+   let key = generate_key();
+
+   let request = base
+      .upgrade(path)
+      .query(query)
+      .header(header::CONNECTION, "Upgrade")
+      .header(header::UPGRADE, "websocket")
+      .header(header::SEC_WEBSOCKET_VERSION, "13")
+      .header(header::SEC_WEBSOCKET_KEY, &key)
+      .header(header::SEC_WEBSOCKET_PROTOCOL, protocols.join(", "));
+
+   let response = base.send(request).await?;
+
+   if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+      let status = response.status();
+      response_into_error(response).await?;
+      return Err(APIError::Upgrade(format!("expected 101 Switching Protocols, got {status}").into()));
+   };
+
+   let headers = response.headers();
+
+   let accept = headers.get(header::SEC_WEBSOCKET_ACCEPT).and_then(|x| x.to_str().ok());
+   if accept != Some(derive_accept_key(key.as_bytes()).as_str()) {
+      return Err(APIError::Upgrade("invalid Sec-WebSocket-Accept".into()));
+   };
+
+   let protocol = headers
+      .get(header::SEC_WEBSOCKET_PROTOCOL)
+      .and_then(|x| x.to_str().ok())
+      .filter(|protocol| protocols.contains(protocol))
+      .ok_or_else(|| APIError::Upgrade(format!("apiserver did not agree to any of {}", protocols.join(", ")).into()))?
+      .into();
+
+   let upgraded = response.upgrade().await?;
+   let socket = WebSocketStream::from_raw_socket(upgraded, Role::Client, None).await;
+
+   Ok((socket, protocol))
+}
+
+/// A message for `channel`.
+pub fn frame(channel: u8, data: &[u8]) -> Message
+{
+   let mut frame = Vec::with_capacity(data.len() + 1);
+   frame.push(channel);
+   frame.extend_from_slice(data);
+   Message::Binary(frame.into())
+}
+
+/// Splits a received message into its channel and payload; messages other than binary
+/// ones carry no channel data.
+pub fn unframe(message: Message) -> Option<(u8, Bytes)>
+{
+   let Message::Binary(mut data) = message else {
+      return None;
+   };
+
+   if data.is_empty() {
+      return None;
+   };
+
+   let channel = data.split_to(1)[0];
+   Some((channel, data))
+}
+
+/// Writes the data sent to the returned queue to `writer` from a task of its own, so a
+/// stream nobody reads cannot hold back the socket. The queue takes `capacity` messages,
+/// and is closed once `writer` fails, e.g. because it was dropped.
+///
+/// An empty message checks whether `writer` still takes data, closing the queue if not.
+pub fn queue_writes<W>(mut writer: W, capacity: usize) -> mpsc::Sender<Bytes>
+where
+   W: AsyncWrite + Unpin + Send + 'static,
+{
+   let (sender, mut queue) = mpsc::channel::<Bytes>(capacity);
+
+   tokio::spawn(async move {
+      while let Some(data) = queue.recv().await {
+         let written = match data.is_empty() {
+            true => writer.write(&data).await.map(drop),
+            false => writer.write_all(&data).await,
+         };
+
+         if written.is_err() {
+            return;
+         };
+      }
+   });
+
+   sender
+}
+
+/// Queues `data` for a stream, waiting while its queue is full so nothing is lost to a
+/// slow reader. A stream whose queue closed is set to `None` and stops receiving.
+pub async fn write_queued(stream: &mut Option<mpsc::Sender<Bytes>>, data: Bytes)
+{
+   if let Some(writer) = stream
+      && writer.send(data).await.is_err()
+   {
+      *stream = None;
+   };
+}
+
+/// The apiserver's end of a WebSocket, for testing sessions without one.
+#[cfg(test)]
+pub mod stand_in
+{
+   use std::time::Duration;
+
+   use bytes::Bytes;
+   use futures::StreamExt;
+   use tokio::io::DuplexStream;
+   use tokio_tungstenite::WebSocketStream;
+   use tokio_tungstenite::tungstenite::protocol::Role;
+
+   pub type StandIn = WebSocketStream<DuplexStream>;
+
+   /// A client socket over an in-memory pipe of `buffer` bytes, and the apiserver's end of it.
+   pub async fn pair(buffer: usize) -> (WebSocketStream<DuplexStream>, StandIn)
+   {
+      let (client, server) = tokio::io::duplex(buffer);
+      let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+      let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+      (client, server)
+   }
+
+   /// The next message on a channel, skipping the rest of the WebSocket protocol.
+   pub async fn receive(server: &mut StandIn) -> Option<(u8, Bytes)>
+   {
+      loop {
+         let message = tokio::time::timeout(Duration::from_secs(1), server.next())
+            .await
+            .expect("receive hung")?
+            .ok()?;
+
+         if let Some(framed) = super::unframe(message) {
+            return Some(framed);
+         };
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use std::time::Duration;
+
+   use super::*;
+
+   #[tokio::test]
+   async fn queued_writes_do_not_wait_for_the_reader()
+   {
+      let (writer, mut reader) = tokio::io::duplex(16);
+      let queue = queue_writes(writer, 4);
+
+      // more than the pipe holds, so only a task of its own can be writing
+      for data in [&b"0123456789"[..], b"abcdefghij", b"klmnopqrst"] {
+         tokio::time::timeout(Duration::from_secs(1), queue.send(Bytes::from_static(data)))
+            .await
+            .expect("send waited for the reader")
+            .unwrap();
+      }
+      drop(queue);
+
+      let mut data = vec![];
+      tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut data).await.unwrap();
+      assert_eq!(data, b"0123456789abcdefghijklmnopqrst");
+   }
+}
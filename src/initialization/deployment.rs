@@ -1,25 +1,18 @@
 use std::sync::Arc;
-use crate::client::KubeClient;
+use crate::client::{APIError, KubeClient, errors};
 
 pub async fn get_deployment_uuid(
    client: &KubeClient,
    namespace: &str,
    deployment_name: &str,
-) -> Arc<str> {
+) -> Result<Arc<str>, APIError> {
    use k8s_openapi::api::apps::v1::Deployment;
 
-   let endpoint = format!(
-      "/apis/apps/v1/namespaces/{}/deployments/{}",
-      namespace, deployment_name
-   );
-   let response = client
-      .get(endpoint)
-      .send()
-      .await
-      .unwrap()
-      .error_for_status()
-      .unwrap();
-   let deployment = response.json::<Deployment>().await.unwrap();
+   let deployment = client
+      .namespaced::<Deployment>(namespace)
+      .get(deployment_name)
+      .await?;
 
-   deployment.metadata.uid.unwrap().into()
+   let uid = deployment.metadata.uid.ok_or(errors::UID)?;
+   Ok(uid.into())
 }
@@ -1,34 +1,26 @@
 use std::collections::HashSet;
 
-use crate::client::KubeClient;
+use crate::client::{APIError, KubeClient, LabelSelector, ListParams, errors};
 
-pub async fn get_pods_uids(client: &KubeClient, template_hash: &str) -> (Box<str>, HashSet<Box<str>>) {
-   use k8s_openapi::{List, api::core::v1::Pod};
+pub async fn get_pods_uids(
+   client: &KubeClient,
+   namespace: &str,
+   template_hash: &str,
+) -> Result<(Box<str>, HashSet<Box<str>>), APIError> {
+   use k8s_openapi::api::core::v1::Pod;
 
-   let endpoint =
-      format!("/api/v1/namespaces/default/pods?labelSelector=pod-template-hash={template_hash}");
-   let response = client
-      .get(endpoint)
-      .send()
-      .await
-      .unwrap()
-      .error_for_status()
-      .unwrap();
-   let pods = response.json::<List<Pod>>().await.unwrap();
+   let selector = LabelSelector::new().equal("pod-template-hash", template_hash);
+   let params = ListParams::default().labels(selector);
+   let pods = client.namespaced::<Pod>(namespace).list_all(&params).await?;
 
    let mut uids = HashSet::new();
 
-   for pod in pods.items.iter() {
-      let uid = pod.metadata.uid.as_ref().unwrap();
-      uids.insert(uid);
+   for pod in pods.items {
+      let uid = pod.metadata.uid.ok_or(errors::UID)?;
+      uids.insert(uid.into());
    }
 
-   let uids = uids
-      .into_iter()
-      .map(|string| string.clone().into())
-      .collect();
+   let version = pods.metadata.resource_version.ok_or(errors::RESOURCE_VERSION)?;
 
-   let version = pods.metadata.resource_version.unwrap().into();
-
-   (version, uids)
+   Ok((version.into(), uids))
 }
@@ -1,49 +1,41 @@
-use crate::client::KubeClient;
+use futures::TryStreamExt;
+
+use crate::client::{APIError, KubeClient, ListParams};
 
 pub async fn get_replicaset(
    client: &KubeClient,
    namespace: &str,
    deployment_uid: &str,
 ) 
-   -> Box<str>
+   -> Result<Option<Box<str>>, APIError>
 {
-   use k8s_openapi::{List, api::apps::v1::ReplicaSet};
-
-   let endpoint = format!("/apis/apps/v1/namespaces/{}/replicasets", namespace);
-   let response = client
-      .get(endpoint)
-      .send()
-      .await
-      .unwrap()
-      .error_for_status()
-      .unwrap();
+   use k8s_openapi::api::apps::v1::ReplicaSet;
 
-   let replica_sets = response.json::<List<ReplicaSet>>().await.unwrap();
+   let pages = client
+      .namespaced::<ReplicaSet>(namespace)
+      .list_pages(&ListParams::default());
+   let mut pages = std::pin::pin!(pages);
 
    let mut replica_set = None;
 
-   for set in replica_sets.items {
-      let owners = set.metadata.owner_references.as_ref().unwrap();
-      for owner in owners {
-         if owner.uid == deployment_uid && owner.controller.unwrap() {
-            replica_set = Some(set);
-            break;
-         };
-      }
+   // stop listing as soon as the owned replica set turns up
+   while let Some(page) = pages.try_next().await? {
+      replica_set = page.items.into_iter().find(|set| {
+         set.metadata
+            .owner_references
+            .iter()
+            .flatten()
+            .any(|owner| owner.uid == deployment_uid && owner.controller == Some(true))
+      });
+
+      if replica_set.is_some() {
+         break;
+      };
    }
 
-   let replica_set = replica_set.expect(&format!(
-      "no replica set found for the deployment {deployment_uid}"
-   ));
-
-
    let pod_template_hash = replica_set
-      .metadata
-      .labels
-      .as_ref()
-      .unwrap()
-      .get("pod-template-hash")
-      .unwrap();
-
-   pod_template_hash.clone().into()
+      .and_then(|set| set.metadata.labels)
+      .and_then(|mut labels| labels.remove("pod-template-hash"));
+
+   Ok(pod_template_hash.map(Into::into))
 }
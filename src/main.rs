@@ -1,28 +1,60 @@
 use kube::client::CAdvisorDaemonSetMetadata;
 use kube::client::KubeClient;
+use kube::client::LabelSelector;
 
 use kube::metrics;
 
+/// What is measured unless `KUBE_QUERY` gives another query; cadvisor reports the total
+/// with `cpu="total"` or without a cpu label at all, depending on its version.
+const CPU_QUERY: &str = r#"sum(container_cpu_usage_seconds_total{cpu=~"total|"})"#;
+
+const USAGE: &str = r#"usage: kube <namespace> <deployment> [container...]
+
+Measures the pods of a Deployment through the cadvisor DaemonSet in kube-system until
+interrupted with ctrl-c, narrowed down to the given containers if any.
+
+The cluster is the one the pod runs in, otherwise the current kubeconfig context.
+
+environment:
+  KUBE_QUERY  what to measure, e.g. sum(container_memory_working_set_bytes); counters
+              are reported per second, so CPU seconds in cores, and gauges as they are
+              (default: sum(container_cpu_usage_seconds_total{cpu=~"total|"}))"#;
+
 #[tokio::main]
 async fn main()
 {
 
 
-   let client = KubeClient::new().unwrap();
-
-   let namespace = "kube-system".into();
-   let key = "k8s-app".into();
-   let value = "cadvisor".into();
+   let args: Vec<String> = std::env::args().skip(1).collect();
+   let (namespace, deployment, containers) = match args.as_slice() {
+      [flag] if flag == "-h" || flag == "--help" => {
+         println!("{USAGE}");
+         return;
+      },
+      [namespace, deployment, containers @ ..] => (namespace, deployment, containers),
+      _ => {
+         eprintln!("{USAGE}");
+         std::process::exit(2);
+      },
+   };
+   let containers: Vec<&str> = containers.iter().map(String::as_str).collect();
+   let target = metrics::Target::owner(namespace, "Deployment", deployment).containers(&containers);
 
-   let daemon_set_meta = CAdvisorDaemonSetMetadata {
-      key,
-      value,
-      namespace,
+   let query: metrics::Query = match std::env::var("KUBE_QUERY").as_deref().unwrap_or(CPU_QUERY).parse() {
+      Ok(query) => query,
+      Err(e) => {
+         eprintln!("{e}");
+         std::process::exit(2);
+      },
    };
 
+   let client = KubeClient::infer().unwrap();
+
+   let selector = LabelSelector::new().equal("k8s-app", "cadvisor");
+   let daemon_set_meta = CAdvisorDaemonSetMetadata::new("kube-system", selector);
+
    let daemon_set_state = client.get.daemon_set_pods(&daemon_set_meta).await.unwrap();
 
-   let duration = std::time::Duration::from_secs(10);
 
 
    // let mut watcher = client.watch.daemon_set_pods (daemon_set_meta, daemon_set_state, duration);
@@ -31,9 +63,9 @@ async fn main()
    //     println!("event: {:?} from {}", event.kind, event.pod.name);
    // };
 
-   let metric = metrics::MetricCollector::new(client, daemon_set_meta, daemon_set_state);
+   let metric = metrics::MetricCollector::new(client, daemon_set_meta, daemon_set_state, target, query);
 
    tokio::signal::ctrl_c().await.unwrap();
    let result = metric.kill().await;
-   println!("got the scrape result");
+   println!("got the scrape result: {} points over {} nodes", result.timestamps.len(), result.collector_map.len());
 }
@@ -9,38 +9,71 @@ use tokio::{
 };
 
 use crate::client::{
-   CAdvisorDaemonSetMetadata, CAdvisorPods, DaemonSetEvent, EventKind, KubeClient, WatcherError,
+   CAdvisorDaemonSetMetadata, CAdvisorPods, DaemonSetEvent, EventKind, Health, KubeClient, Resync, WatcherConfig,
+   WatcherError, WatcherEvent,
 };
 
 use crate::metrics::node::{NodeMetric, NodeMetricCollector};
-use crate::metrics::querier::TopLevelMetric;
+use crate::metrics::querier::{Io, Memory};
+use crate::metrics::selector::Query;
+use crate::metrics::target::{Target, TargetPods};
 
-use super::querier::QueryTask;
+use super::querier::{Measurement, QueryTask};
 
+/// How long each watch of the cadvisor pods stays open before it is resumed.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the cadvisor pods are relisted to correct events their watch missed.
+const RESYNC: Duration = Duration::from_secs(300);
+
+/// What a collector measured: per node, keyed by the uid of its cadvisor pod, and the
+/// totals over all nodes at `timestamps`. `values` are the query's, a rate per second when
+/// it selects counters, I/O is in bytes per second, and `io_total` in bytes over the whole
+/// run.
+///
+/// Samples that were out of the ordinary are annotated on the collector of their node, and
+/// `health`, `resyncs` and `events` record how the watch of the cadvisor pods went.
 #[derive(Debug)]
 pub struct ScrapeResult
 {
-   collector_map: HashMap<String, NodeMetricCollector>,
-   cpu_percentages: Vec<f64>,
-   timestamps: Vec<f64>,
+   pub collector_map: HashMap<String, NodeMetricCollector>,
+   pub values: Vec<f64>,
+   pub memory: Vec<Memory>,
+   pub io: Vec<Io>,
+   pub io_total: Io,
+   pub timestamps: Vec<f64>,
+   pub health: Vec<Health>,
+   pub resyncs: Vec<Resync>,
+   /// Every change to the cadvisor pods, with the fields that changed.
+   pub events: Vec<DaemonSetEvent>,
+}
+
+/// What the watcher of the cadvisor pods reported about itself.
+#[derive(Debug, Default)]
+struct WatcherReport
+{
+   health: Vec<Health>,
+   resyncs: Vec<Resync>,
+   events: Vec<DaemonSetEvent>,
 }
 
 fn handle_event(
-   event: Result<DaemonSetEvent, WatcherError>,
-   metric_sender: &mpsc::Sender<NodeMetric>,
-   client: &KubeClient,
+   event: Result<WatcherEvent<DaemonSetEvent>, WatcherError>,
+   measurement: &Measurement,
    collector_map: &mut HashMap<String, NodeMetricCollector>,
    running_querier_map: &mut HashMap<String, QueryTask>,
    paused_querier_map: &mut HashMap<String, QueryTask>,
+   report: &mut WatcherReport,
 )
 {
    match event {
-      Ok(event) => {
-         println!("got event from watcher: {:?} from {}", event.kind, event.pod.name);
+      Ok(WatcherEvent::Health(health)) => report.health.push(health),
+      Ok(WatcherEvent::Resynced(resync)) => report.resyncs.push(resync),
+      Ok(WatcherEvent::Event(event)) => {
          match event.kind {
             EventKind::Created => {
-               let querier = QueryTask::new(&event.pod, metric_sender.clone(), client);
-               let collector = NodeMetricCollector::new();
+               let querier = QueryTask::new(&event.pod, measurement);
+               let collector = NodeMetricCollector::new(&measurement.query);
                assert!(
                   collector_map
                      .insert(event.pod.uid.clone().into(), collector)
@@ -79,10 +112,13 @@ fn handle_event(
                let paused_removed = paused_querier_map.remove(uid);
                assert_ne!(running_removed.is_some(), paused_removed.is_some());
             }
+            EventKind::Updated => (),
          }
+
+         report.events.push(event);
       }
       Err(e) => {
-         println!("Error in metric collector and reading from watcher: {e:?}");
+         println!("Error in metric collector and reading from watcher, watcher stopped: {e}");
       }
    };
 }
@@ -91,28 +127,45 @@ async fn scrape(
    client: KubeClient,
    daemon_set_meta: CAdvisorDaemonSetMetadata,
    daemon_set_state: CAdvisorPods,
+   target: Target,
+   query: Query,
    killed: oneshot::Receiver<()>,
    // cpu_count: u32,
 ) -> ScrapeResult
 {
    let killed = killed.shared();
 
+   println!("measuring {query} of {target}");
+   let target = TargetPods::watch(&client, target);
+   if !target.wait_ready().await {
+      println!("watching the pods of {} stopped before they were listed", target.target());
+   };
+
    let mut round_set = HashSet::new();
    let mut round_min = None;
 
    let mut collector_map = HashMap::new();
    let mut running_querier_map: HashMap<String, QueryTask> = HashMap::new();
    let mut paused_querier_map: HashMap<String, QueryTask> = HashMap::new();
-   let mut cpu_percentages = Vec::new();
+   let mut values = Vec::new();
+   let mut memory = Vec::new();
+   let mut io = Vec::new();
    let mut timestamps = Vec::new();
+   let mut report = WatcherReport::default();
 
    let (metric_sender, mut metric_receiver) = mpsc::channel(100);
+   let measurement = Measurement {
+      client,
+      target,
+      query,
+      metric_sender,
+   };
 
    let CAdvisorPods { pods, .. } = &daemon_set_state;
 
    for pod in pods {
-      let querier = QueryTask::new(&pod, metric_sender.clone(), &client);
-      let collector = NodeMetricCollector::new();
+      let querier = QueryTask::new(pod, &measurement);
+      let collector = NodeMetricCollector::new(&measurement.query);
       assert!(
          collector_map
             .insert(pod.uid.clone().into(), collector)
@@ -135,9 +188,12 @@ async fn scrape(
    }
 
    let mut watcher =
-      client
+      measurement
+         .client
          .watch
-         .daemon_set_pods(daemon_set_meta, daemon_set_state, Duration::from_secs(60));
+         .daemon_set_pods(daemon_set_meta, daemon_set_state, WatcherConfig::new(WATCH_TIMEOUT).resync(RESYNC));
+
+   let mut watching = true;
 
    loop {
       let data_point = tokio::select! {
@@ -145,8 +201,17 @@ async fn scrape(
             println!("metric collector killed");
             break;
          },
-         event = watcher.next() => {
-            handle_event(event, &metric_sender, &client, &mut collector_map, &mut running_querier_map, &mut paused_querier_map);
+         // the watcher only yields an error once it has stopped
+         event = watcher.next_event(), if watching => {
+            watching = event.is_ok();
+            handle_event(
+               event,
+               &measurement,
+               &mut collector_map,
+               &mut running_querier_map,
+               &mut paused_querier_map,
+               &mut report,
+            );
             continue;
          },
          data_point = metric_receiver.recv() => match data_point {
@@ -159,13 +224,8 @@ async fn scrape(
       };
 
       let NodeMetric { uid, metric } = data_point;
-      let TopLevelMetric {
-         value: cpu,
-         timestamp: time,
-      } = metric;
-
       let collector = collector_map.get_mut(&uid).unwrap();
-      let (time, _) = match collector.next(time, cpu) {
+      let (time, _) = match collector.next(&metric) {
          None => continue,
          Some(x) => x,
       };
@@ -183,17 +243,27 @@ async fn scrape(
       if round_set.len() >= running_querier_map.len() {
          // println!("round complete. computing total value");
          round_set.clear();
-         let time = std::mem::replace(&mut round_min, None).unwrap();
-         let total_cpu: f64 = collector_map
+         let time = round_min.take().unwrap();
+         let total: f64 = collector_map
             .values()
             .map(|passed| passed.interporlate(time))
             .sum();
 
-         // let total_cpu = total_cpu / cpu_count as f64;
+         let total_memory: Memory = collector_map
+            .values()
+            .map(|passed| passed.interpolate_memory(time))
+            .sum();
+
+         let total_io: Io = collector_map
+            .values()
+            .map(|passed| passed.interpolate_io(time))
+            .sum();
 
-         println!("total cpu: {total_cpu}% @ {time}");
+         println!("total {}: {total} memory: {total_memory} io per second: {total_io} @ {time}", measurement.query);
          timestamps.push(time);
-         cpu_percentages.push(total_cpu);
+         values.push(total);
+         memory.push(total_memory);
+         io.push(total_io);
       };
    }
 
@@ -207,11 +277,20 @@ async fn scrape(
       )
       .collect();
    futures::future::join_all(killed_futures).await;
+   measurement.target.stop();
+
+   let io_total = collector_map.values().map(NodeMetricCollector::io_total).sum();
 
    ScrapeResult {
       collector_map,
       timestamps,
-      cpu_percentages,
+      values,
+      memory,
+      io,
+      io_total,
+      health: report.health,
+      resyncs: report.resyncs,
+      events: report.events,
    }
 }
 
@@ -229,6 +308,8 @@ impl MetricCollector
       client: KubeClient,
       daemon_set_meta: CAdvisorDaemonSetMetadata,
       daemon_set_state: CAdvisorPods,
+      target: Target,
+      query: Query,
       // cpu_count: u32,
    ) -> Self
    {
@@ -237,6 +318,8 @@ impl MetricCollector
          client,
          daemon_set_meta,
          daemon_set_state,
+         target,
+         query,
          killed,
          // cpu_count,
       ));
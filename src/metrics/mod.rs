@@ -1,6 +1,12 @@
 mod controller;
 mod node;
 mod querier;
+mod selector;
+mod target;
 
-pub use controller::MetricCollector;
+pub use controller::{MetricCollector, ScrapeResult};
+pub use node::{Annotation, AnnotationKind, NodeMetricCollector};
+pub use querier::{Counters, Io, IoCounters, Memory, TopLevelMetric};
+pub use selector::{Aggregate, Aggregation, Labels, MatchOp, Matcher, Query, Selector, SelectorError, Series};
+pub use target::{PodSelector, Selection, Target, TargetPods};
 
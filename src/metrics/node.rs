@@ -1,4 +1,5 @@
-use super::querier::TopLevelMetric;
+use super::querier::{Counters, Io, IoCounters, Memory, TopLevelMetric, total};
+use super::selector::{Aggregate, Labels, MetricKind, Query, Series};
 
 
 #[derive(Debug, Clone)]
@@ -9,61 +10,241 @@ pub struct NodeMetric
 }
 
 
+/// Something out of the ordinary about a sample, noted on the series of its node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotationKind
+{
+   /// The first sample, which only sets where the counters start.
+   Primed,
+   /// A series of a counter went down, e.g. because its container restarted, so it counted
+   /// up from 0 again and its new value is taken as the increase.
+   CounterReset
+   {
+      counter: &'static str,
+      series: Labels,
+      before: f64,
+      after: f64,
+   },
+   /// The sample is older than the previous one, and is ignored.
+   OutOfOrder
+   {
+      previous: i64,
+   },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation
+{
+   /// The timestamp of the sample in milliseconds.
+   pub time: i64,
+   pub kind: AnnotationKind,
+}
+
+impl std::fmt::Display for Annotation
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match &self.kind {
+         AnnotationKind::Primed => write!(f, "primed @ {}", self.time),
+         AnnotationKind::CounterReset { counter, series, before, after } => {
+            let series: Vec<String> = series.iter().map(|(label, value)| format!("{label}={value:?}")).collect();
+            write!(f, "{counter}{{{}}} reset from {before} to {after} @ {}", series.join(", "), self.time)
+         },
+         AnnotationKind::OutOfOrder { previous } => {
+            write!(f, "sample @ {} ignored, older than the previous @ {previous}", self.time)
+         },
+      }
+   }
+}
+
+
+/// The query's value, memory and I/O of the measured containers on one node, one point per
+/// scrape interval.
+///
+/// Counters, including I/O, are rates per second over the interval, and gauges, including
+/// memory, the average of their values at its two ends, all placed at the middle of the
+/// interval so they line up. Counters follow Prometheus' rate then sum: the increase of
+/// every series is taken on its own, a series that drops was reset, and only series in
+/// both samples count, so containers coming and going cannot make the total jump. The
+/// query's increases or values are then aggregated like the query.
 #[derive(Debug, Default)]
 pub struct NodeMetricCollector {
-   prev_cpu: f64,
-   prev_time: i64,
-   cpu_percentages: Vec<f64>,
+   aggregate: Option<Aggregate>,
+   prev: Option<TopLevelMetric>,
+   values: Vec<f64>,
+   memory: Vec<Memory>,
+   io: Vec<Io>,
+   io_total: Io,
    timestamps: Vec<f64>,
+   annotations: Vec<Annotation>,
 }
 
 
 impl NodeMetricCollector {
-   pub fn new() -> Self {
-      Self::default()
+   pub fn new(query: &Query) -> Self {
+      Self {
+         aggregate: query.aggregate.clone(),
+         ..Self::default()
+      }
+   }
+
+   pub fn timestamps(&self) -> &[f64] {
+      &self.timestamps
    }
 
-   pub fn next(&mut self, time: i64, cpu: f64) -> Option<(f64, f64)> {
-      let time_d = time - self.prev_time;
+   /// The query's value per interval: a rate per second for counters, like CPU seconds
+   /// measured in cores, and the value itself for gauges.
+   pub fn values(&self) -> &[f64] {
+      &self.values
+   }
+
+   pub fn memory(&self) -> &[Memory] {
+      &self.memory
+   }
+
+   /// Bytes per second.
+   pub fn io(&self) -> &[Io] {
+      &self.io
+   }
+
+   /// Bytes over the whole run.
+   pub fn io_total(&self) -> Io {
+      self.io_total
+   }
+
+   pub fn annotations(&self) -> &[Annotation] {
+      &self.annotations
+   }
+
+   /// Adds the interval since the previous sample, returning its time and the query's value.
+   ///
+   /// Returns `None` for the first sample and samples older than the previous one, and the
+   /// last point again for a sample at the same time as the previous one.
+   pub fn next(&mut self, metric: &TopLevelMetric) -> Option<(f64, f64)> {
+      let time = metric.timestamp;
+
+      let Some(prev) = self.prev.replace(metric.clone()) else {
+         self.annotate(time, AnnotationKind::Primed);
+         return None;
+      };
+
+      let time_d = time - prev.timestamp;
+
+      if time_d < 0 {
+         self.annotate(time, AnnotationKind::OutOfOrder { previous: prev.timestamp });
+         self.prev = Some(prev);
+         return None;
+      };
 
       if time_d == 0 {
-         let timestamp = self.timestamps.get(self.timestamps.len() - 1);
-         let cpu = self.cpu_percentages.get(self.cpu_percentages.len() - 1);
-         match (timestamp, cpu) {
-            (Some(t), Some(c)) => return Some((*t, *c)),
-            _ => return None,
+         let timestamp = self.timestamps.last();
+         let value = self.values.last();
+         return match (timestamp, value) {
+            (Some(t), Some(c)) => Some((*t, *c)),
+            _ => None,
          };
       };
 
-      let cpu_d = cpu - self.prev_cpu;
-      assert!(time_d.is_positive());
-      assert!(cpu_d.is_sign_positive());
-
-      let percentage = (cpu_d / (time_d as f64 / 1000.0)) * 100.0;
-      let timestamp = (time + self.prev_time) as f64 / 2.0;
+      let series = match metric.kind {
+         MetricKind::Counter => self.increases("query", &prev.series, &metric.series, time),
+         MetricKind::Gauge => averages(&prev.series, &metric.series, time),
+      };
+      let value = match &self.aggregate {
+         Some(aggregate) => total(&aggregate.apply(series)),
+         None => total(&series),
+      };
+      let value = match metric.kind {
+         MetricKind::Counter => value / (time_d as f64 / 1000.0),
+         MetricKind::Gauge => value,
+      };
+      let timestamp = (time + prev.timestamp) as f64 / 2.0;
       self.timestamps.push(timestamp);
-      self.cpu_percentages.push(percentage);
+      self.values.push(value);
 
+      self.memory.push(prev.memory.map(metric.memory, |prev, current| (prev + current) / 2.0));
 
-      self.prev_cpu = cpu;
-      self.prev_time = time;
-      Some((timestamp, percentage))
+      let io_d = self.io_increase(&prev.io, &metric.io, time);
+      self.io_total = self.io_total + io_d;
+      self.io.push(io_d.rate(time_d));
+
+      Some((timestamp, value))
+   }
+
+   /// The increase of every series of `counter` that both samples have, noting resets.
+   /// Series that only one of them has, of containers that started or stopped, have none.
+   fn increases(&mut self, counter: &'static str, prev: &Counters, current: &Counters, time: i64) -> Vec<Series> {
+      let mut increases = vec![];
+
+      for (labels, &after) in current {
+         let Some(&before) = prev.get(labels) else {
+            continue;
+         };
+
+         if after < before {
+            let series = labels.clone();
+            self.annotate(time, AnnotationKind::CounterReset { counter, series, before, after });
+         };
+
+         increases.push(Series {
+            labels: labels.clone(),
+            value: increase(before, after),
+            timestamp: Some(time),
+         });
+      }
+
+      increases
+   }
+
+   fn io_increase(&mut self, prev: &IoCounters, current: &IoCounters, time: i64) -> Io {
+      let mut sum = |counter, prev, current| total(&self.increases(counter, prev, current, time));
+
+      Io {
+         network_rx: sum("network rx", &prev.network_rx, &current.network_rx),
+         network_tx: sum("network tx", &prev.network_tx, &current.network_tx),
+         fs_read: sum("fs read", &prev.fs_read, &current.fs_read),
+         fs_write: sum("fs write", &prev.fs_write, &current.fs_write),
+      }
+   }
+
+   fn annotate(&mut self, time: i64, kind: AnnotationKind) {
+      self.annotations.push(Annotation { time, kind });
    }
 
    pub fn interporlate(&self, time: f64) -> f64 {
+      self.interpolate_with(time, |index| self.values[index])
+   }
+
+   pub fn interpolate_io(&self, time: f64) -> Io {
+      Io {
+         network_rx: self.interpolate_with(time, |index| self.io[index].network_rx),
+         network_tx: self.interpolate_with(time, |index| self.io[index].network_tx),
+         fs_read: self.interpolate_with(time, |index| self.io[index].fs_read),
+         fs_write: self.interpolate_with(time, |index| self.io[index].fs_write),
+      }
+   }
+
+   pub fn interpolate_memory(&self, time: f64) -> Memory {
+      Memory {
+         working_set: self.interpolate_with(time, |index| self.memory[index].working_set),
+         rss: self.interpolate_with(time, |index| self.memory[index].rss),
+         cache: self.interpolate_with(time, |index| self.memory[index].cache),
+      }
+   }
+
+   fn interpolate_with(&self, time: f64, value: impl Fn(usize) -> f64) -> f64 {
       let mut prev = 0;
 
       for (index, current ) in self.timestamps.iter().enumerate() {
          if *current == time {
-            return self.cpu_percentages[index];
+            return value(index);
          };
           
          if *current > time {
             let t1 = self.timestamps[prev];
-            let v1 = self.cpu_percentages[prev];
+            let v1 = value(prev);
 
             let t2 = self.timestamps[index];
-            let v2 = self.cpu_percentages[index];
+            let v2 = value(index);
 
             if t1 == t2 {
                return v2;
@@ -76,7 +257,7 @@ impl NodeMetricCollector {
          prev = index;
       };
 
-      return 0.0;
+      0.0
    }
 }
 
@@ -90,3 +271,176 @@ fn interpolate(t: f64, mut t1: f64, mut v1: f64, t2: f64, v2: f64) -> f64 {
 
    v1 + (v2 - v1) * (t - t1) / (t2 - t1)
 }
+
+/// The average of every series of a gauge at both ends of an interval, of the series both
+/// samples have.
+fn averages(prev: &Counters, current: &Counters, time: i64) -> Vec<Series> {
+   current
+      .iter()
+      .filter_map(|(labels, &after)| {
+         let before = prev.get(labels)?;
+
+         Some(Series {
+            labels: labels.clone(),
+            value: (before + after) / 2.0,
+            timestamp: Some(time),
+         })
+      })
+      .collect()
+}
+
+/// How much a counter went up; a counter lower than before was reset, e.g. by its
+/// container restarting, and counted up from 0 since.
+fn increase(prev: f64, current: f64) -> f64 {
+   if current < prev {
+      return current;
+   };
+
+   current - prev
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   fn series(container: &str) -> Labels
+   {
+      Labels::from([("container".into(), container.into()), ("pod".into(), "web".into())])
+   }
+
+   /// A sample at `time` of CPU seconds per container, with as many bytes read.
+   fn sample(time: i64, cpu: &[(&str, f64)]) -> TopLevelMetric
+   {
+      let cpu: Counters = cpu.iter().map(|&(container, value)| (series(container), value)).collect();
+
+      TopLevelMetric {
+         timestamp: time,
+         memory: Memory::default(),
+         io: IoCounters {
+            fs_read: cpu.clone(),
+            ..IoCounters::default()
+         },
+         series: cpu,
+         kind: MetricKind::Counter,
+      }
+   }
+
+   /// A sample at `time` of a gauge per container.
+   fn gauge(time: i64, values: &[(&str, f64)]) -> TopLevelMetric
+   {
+      TopLevelMetric {
+         kind: MetricKind::Gauge,
+         io: IoCounters::default(),
+         ..sample(time, values)
+      }
+   }
+
+   fn collector() -> NodeMetricCollector
+   {
+      NodeMetricCollector::new(&"sum(container_cpu_usage_seconds_total)".parse().unwrap())
+   }
+
+   fn kinds(collector: &NodeMetricCollector) -> Vec<&AnnotationKind>
+   {
+      collector.annotations().iter().map(|annotation| &annotation.kind).collect()
+   }
+
+   #[test]
+   fn first_sample_primes()
+   {
+      let mut collector = collector();
+
+      assert_eq!(collector.next(&sample(1000, &[("app", 5.0)])), None);
+      assert_eq!(kinds(&collector), [&AnnotationKind::Primed]);
+      assert!(collector.values().is_empty());
+   }
+
+   #[test]
+   fn rates_are_summed_per_series()
+   {
+      let mut collector = collector();
+      collector.next(&sample(0, &[("app", 1.0), ("sidecar", 2.0)]));
+
+      assert_eq!(collector.next(&sample(2000, &[("app", 2.0), ("sidecar", 2.5)])), Some((1000.0, 0.75)));
+      assert_eq!(collector.io()[0].fs_read, 0.75);
+      assert_eq!(collector.io_total().fs_read, 1.5);
+   }
+
+   #[test]
+   fn removed_pod_is_not_a_reset()
+   {
+      let mut collector = collector();
+      collector.next(&sample(0, &[("app", 100.0), ("sidecar", 5000.0)]));
+
+      assert_eq!(collector.next(&sample(1000, &[("app", 101.0)])), Some((500.0, 1.0)));
+      assert_eq!(kinds(&collector), [&AnnotationKind::Primed]);
+   }
+
+   #[test]
+   fn added_pod_only_counts_from_its_first_sample()
+   {
+      let mut collector = collector();
+      collector.next(&sample(0, &[("app", 100.0)]));
+
+      assert_eq!(collector.next(&sample(1000, &[("app", 101.0), ("new", 5000.0)])), Some((500.0, 1.0)));
+      assert_eq!(collector.next(&sample(2000, &[("app", 101.0), ("new", 5001.0)])), Some((1500.0, 1.0)));
+   }
+
+   #[test]
+   fn single_container_restart_is_a_reset()
+   {
+      let mut collector = collector();
+      collector.next(&sample(0, &[("app", 100.0), ("sidecar", 50.0)]));
+
+      // the sidecar restarted and counted 0.5s since, while the total still went up
+      assert_eq!(collector.next(&sample(1000, &[("app", 101.0), ("sidecar", 0.5)])), Some((500.0, 1.5)));
+
+      let resets: Vec<_> = kinds(&collector)
+         .into_iter()
+         .filter_map(|kind| match kind {
+            AnnotationKind::CounterReset { counter, series, before, after } => Some((*counter, series, *before, *after)),
+            _ => None,
+         })
+         .collect();
+
+      assert_eq!(
+         resets,
+         [("query", &self::series("sidecar"), 50.0, 0.5), ("fs read", &self::series("sidecar"), 50.0, 0.5)]
+      );
+   }
+
+   #[test]
+   fn out_of_order_sample_is_ignored()
+   {
+      let mut collector = collector();
+      collector.next(&sample(1000, &[("app", 10.0)]));
+
+      assert_eq!(collector.next(&sample(500, &[("app", 9.0)])), None);
+      assert_eq!(kinds(&collector)[1], &AnnotationKind::OutOfOrder { previous: 1000 });
+
+      // the next sample is compared with the newest one seen
+      assert_eq!(collector.next(&sample(2000, &[("app", 11.0)])), Some((1500.0, 1.0)));
+      assert_eq!(collector.annotations().len(), 2);
+   }
+
+   #[test]
+   fn aggregates_increases_like_the_query()
+   {
+      let mut collector = NodeMetricCollector::new(&"max(container_cpu_usage_seconds_total)".parse().unwrap());
+      collector.next(&sample(0, &[("app", 1.0), ("sidecar", 1.0)]));
+
+      assert_eq!(collector.next(&sample(1000, &[("app", 1.5), ("sidecar", 1.25)])), Some((500.0, 0.5)));
+   }
+
+   #[test]
+   fn gauges_are_averaged_not_rated()
+   {
+      let mut collector = NodeMetricCollector::new(&"sum(container_memory_working_set_bytes)".parse().unwrap());
+      collector.next(&gauge(0, &[("app", 300.0), ("sidecar", 100.0)]));
+
+      // going down is just a lower value
+      assert_eq!(collector.next(&gauge(2000, &[("app", 100.0), ("sidecar", 100.0)])), Some((1000.0, 300.0)));
+      assert_eq!(kinds(&collector), [&AnnotationKind::Primed]);
+   }
+}
@@ -1,4 +1,6 @@
-use prom_text_format_parser::{Scrape, Value, Sample, Metric};
+use std::collections::BTreeMap;
+
+use prom_text_format_parser::Scrape;
 
 use tokio::{
    task::JoinHandle,
@@ -8,6 +10,8 @@ use tokio::{
 use crate::client::{Pod, KubeClient, APIError};
 
 use super::node::NodeMetric;
+use super::selector::{Aggregate, Aggregation, Labels, MetricKind, Query, Selector, Series};
+use super::target::{NAMESPACE_LABELS, POD_LABELS, Selection, TargetPods};
 
 #[derive(Debug, Clone, Copy)]
 pub enum State
@@ -27,84 +31,281 @@ pub struct QueryTask
 
 use tokio::sync::mpsc;
 
-fn find_top_level(metric: Metric) -> Result<Sample, APIError> {
-   metric
-      .samples
-      .into_iter()
-      .find(|sample| {
-         sample
-            .labels
-            .iter()
-            .find(|label| label.key == "id" && label.value == "/")
-            .is_some()
-      })
-      .ok_or(APIError::NodeTopLevelContainerMetricNotFound)
+/// What every querier of a collector shares: the workload, what to measure of it and where
+/// to send the measurements.
+#[derive(Debug, Clone)]
+pub struct Measurement
+{
+   pub client: KubeClient,
+   pub target: TargetPods,
+   pub query: Query,
+   pub metric_sender: mpsc::Sender<NodeMetric>,
 }
 
-fn find_c_advisor(metric: Metric) -> Result<Sample, APIError> {
-   'sample: for sample in metric.samples.into_iter() {
-      for label in sample.labels.iter() {
-         if label.key == "container_label_io_kubernetes_container_name" && label.value !="cadvisor" {
-            continue 'sample;
-         };
+/// The sum of the values of a query's series, 0 when there are none rather than the -0
+/// `Sum` gives.
+pub(super) fn total(series: &[Series]) -> f64
+{
+   series.iter().fold(0.0, |total, series| total + series.value)
+}
+
+/// The values of counters or gauges, keyed by the labels of their series.
+pub type Counters = BTreeMap<Labels, f64>;
+
+fn counters(series: Vec<Series>) -> Counters
+{
+   series.into_iter().map(|series| (series.labels, series.value)).collect()
+}
+
+/// Memory of the measured containers in bytes. Unlike CPU these are gauges.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Memory
+{
+   pub working_set: f64,
+   pub rss: f64,
+   pub cache: f64,
+}
+
+impl Memory
+{
+   /// Sums each memory metric over the containers in `selection`; metrics cadvisor does not
+   /// report count as 0.
+   pub fn from_scrape(scrape: &Scrape, selection: &Selection) -> Self
+   {
+      let sum = |name: &str| -> f64 {
+         total(&Query::sum(Selector::name(name)).evaluate_where(scrape, |sample| selection.matches(sample)))
+      };
+
+      Self {
+         working_set: sum("container_memory_working_set_bytes"),
+         rss: sum("container_memory_rss"),
+         cache: sum("container_memory_cache"),
+      }
+   }
+
+   /// Combines each metric with the same one of `other`.
+   pub fn map(self, other: Self, f: impl Fn(f64, f64) -> f64) -> Self
+   {
+      Self {
+         working_set: f(self.working_set, other.working_set),
+         rss: f(self.rss, other.rss),
+         cache: f(self.cache, other.cache),
+      }
+   }
+}
 
-         if label.key == "container_label_io_kubernetes_pod_name" && !label.value.starts_with("cadvisor") {
-            continue 'sample;
+impl std::ops::Add for Memory
+{
+   type Output = Self;
+
+   fn add(self, other: Self) -> Self
+   {
+      self.map(other, |a, b| a + b)
+   }
+}
+
+impl std::iter::Sum for Memory
+{
+   fn sum<I: Iterator<Item = Self>>(iter: I) -> Self
+   {
+      iter.fold(Self::default(), |total, memory| total + memory)
+   }
+}
+
+impl std::fmt::Display for Memory
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      const MIB: f64 = 1024.0 * 1024.0;
+      write!(
+         f,
+         "working set {:.1}MiB, rss {:.1}MiB, cache {:.1}MiB",
+         self.working_set / MIB,
+         self.rss / MIB,
+         self.cache / MIB
+      )
+   }
+}
+
+/// Network and filesystem I/O of the measured containers in bytes, as rates per second or
+/// totals over a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Io
+{
+   pub network_rx: f64,
+   pub network_tx: f64,
+   pub fs_read: f64,
+   pub fs_write: f64,
+}
+
+/// The I/O counters of the measured containers as scraped, one per series.
+///
+/// Containers of a pod share its network, so network counters are kept per interface of a
+/// pod rather than per container.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoCounters
+{
+   pub network_rx: Counters,
+   pub network_tx: Counters,
+   pub fs_read: Counters,
+   pub fs_write: Counters,
+}
+
+impl IoCounters
+{
+   pub fn from_scrape(scrape: &Scrape, selection: &Selection) -> Self
+   {
+      // every container of a pod, its pause container and the pod's own cgroup may all
+      // report the same interfaces, so each interface of a pod is taken once
+      let by: Vec<Box<str>> = NAMESPACE_LABELS
+         .iter()
+         .chain(POD_LABELS.iter())
+         .chain(["interface"].iter())
+         .map(|&label| label.into())
+         .collect();
+
+      let network = |name: &str| -> Counters {
+         let query = Query {
+            selector: Selector::name(name),
+            aggregate: Some(Aggregate {
+               op: Aggregation::Max,
+               by: by.clone(),
+            }),
          };
 
-         if label.key == "container_label_io_kubernetes_pod_namespace" && label.value != "kube-system" {
-            continue 'sample;
+         counters(query.evaluate_where(scrape, |sample| selection.matches_pod(sample)))
+      };
+
+      let fs = |name: &str| -> Counters {
+         let query = Query {
+            selector: Selector::name(name),
+            aggregate: None,
          };
+
+         counters(query.select_where(scrape, |sample| selection.matches(sample)))
       };
-      
-      return Ok(sample);
-   };
 
-   Err(APIError::NodeTopLevelContainerMetricNotFound)
+      Self {
+         network_rx: network("container_network_receive_bytes_total"),
+         network_tx: network("container_network_transmit_bytes_total"),
+         fs_read: fs("container_fs_reads_bytes_total"),
+         fs_write: fs("container_fs_writes_bytes_total"),
+      }
+   }
+}
+
+impl Io
+{
+   /// The rates per second of increases over `millis`.
+   pub fn rate(self, millis: i64) -> Self
+   {
+      let seconds = millis as f64 / 1000.0;
+      self.map(self, |bytes, _| bytes / seconds)
+   }
+
+   /// Combines each counter with the same one of `other`.
+   pub fn map(self, other: Self, f: impl Fn(f64, f64) -> f64) -> Self
+   {
+      Self {
+         network_rx: f(self.network_rx, other.network_rx),
+         network_tx: f(self.network_tx, other.network_tx),
+         fs_read: f(self.fs_read, other.fs_read),
+         fs_write: f(self.fs_write, other.fs_write),
+      }
+   }
+}
+
+impl std::ops::Add for Io
+{
+   type Output = Self;
+
+   fn add(self, other: Self) -> Self
+   {
+      self.map(other, |a, b| a + b)
+   }
+}
 
+impl std::iter::Sum for Io
+{
+   fn sum<I: Iterator<Item = Self>>(iter: I) -> Self
+   {
+      iter.fold(Self::default(), |total, io| total + io)
+   }
 }
 
+impl std::fmt::Display for Io
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      const KIB: f64 = 1024.0;
+      write!(
+         f,
+         "network rx {:.1}KiB tx {:.1}KiB, fs read {:.1}KiB write {:.1}KiB",
+         self.network_rx / KIB,
+         self.network_tx / KIB,
+         self.fs_read / KIB,
+         self.fs_write / KIB
+      )
+   }
+}
+
+/// One scrape of a node: the value of every series the query selects of the measured
+/// containers, and their memory and I/O.
 #[derive(Debug, Clone)]
 pub struct TopLevelMetric
 {
-   pub value: f64,
+   pub series: Counters,
+   /// Whether `series` are counters or gauges.
+   pub kind: MetricKind,
    pub timestamp: i64,
+   pub memory: Memory,
+   pub io: IoCounters,
 }
 
-impl TryFrom<Scrape> for TopLevelMetric
+impl TopLevelMetric
 {
-   type Error = APIError;
-   fn try_from(scrape: Scrape) -> Result<Self, Self::Error>
+   /// Selects the series of `query` of the containers in `selection`, at the time of the
+   /// latest sample it selects from the whole scrape.
+   ///
+   /// The series are kept apart, so that counters are compared per series before the
+   /// query aggregates their increases. A node running none of the containers has none.
+   /// Memory and I/O are read from the same scrape.
+   pub fn from_scrape(scrape: Scrape, selection: &Selection, query: &Query) -> Result<Self, APIError>
    {
-      let cpu_metric = scrape
-         .metrics
-         .into_iter()
-         .find(|metric| metric.name == "container_cpu_usage_seconds_total")
-         .ok_or(APIError::CPUMetricNotFound)?;
+      let Some(kind) = query.selector.kind(&scrape) else {
+         return Err(APIError::MetricNotFound(query.selector.to_string().into()));
+      };
+
+      let timestamp = query
+         .selector
+         .select(&scrape)
+         .filter_map(|(_, sample)| sample.value.timestamp)
+         .max()
+         .ok_or(APIError::NodeTopLevelContainerMetricNoTimeStamp)?;
 
-      let top_level_metric = find_c_advisor(cpu_metric)?;
+      let series = counters(query.select_where(&scrape, |sample| selection.matches(sample)));
 
-      let value = top_level_metric.value;
-      let Value {
-         value, timestamp, ..
-      } = value;
-      let value = value.as_f64();
-      let timestamp = timestamp.ok_or(APIError::NodeTopLevelContainerMetricNoTimeStamp)?;
+      let memory = Memory::from_scrape(&scrape, selection);
+      let io = IoCounters::from_scrape(&scrape, selection);
 
-      Ok(Self { value, timestamp })
+      Ok(Self {
+         series,
+         kind,
+         timestamp,
+         memory,
+         io,
+      })
    }
 }
 
 
-async fn query_node_c_advisor(
-   client: &KubeClient,
-   pod: &Pod,
-) -> Result<NodeMetric, APIError>
+async fn query_node_c_advisor(pod: &Pod, measurement: &Measurement) -> Result<NodeMetric, APIError>
 {
-   let response = client.proxy.pod(&pod, "metrics").await?;
+   let Measurement { client, target, query, .. } = measurement;
+   let response = client.proxy.pod(pod, "metrics").await?;
    let string = response.text().await?;
    let scrape = Scrape::parse(&string)?;
-   let top_level_metric: TopLevelMetric = scrape.try_into()?;
+   let top_level_metric = TopLevelMetric::from_scrape(scrape, &target.selection(), query)?;
    let node_metric = NodeMetric {
       uid: pod.uid.clone().into(),
       metric: top_level_metric,
@@ -117,13 +318,12 @@ impl QueryTask
 {
    pub fn new(
       pod: &Pod,
-      metric_sender: mpsc::Sender<NodeMetric>,
-      client: &KubeClient,
+      measurement: &Measurement,
    ) -> Self
    {
       println!("querier task created for {} with init state: {}", pod.name, pod.status);
       let pod = pod.clone();
-      let client = client.clone();
+      let measurement = measurement.clone();
       let init_state = if pod.status { State::Running } else { State::Paused };
       let (state_updater, mut state_reader) = watch::channel(init_state);
       let task = async move || {
@@ -167,16 +367,16 @@ impl QueryTask
                }
             };
 
-            let metric = match query_node_c_advisor(&client, &pod).await {
+            let metric = match query_node_c_advisor(&pod, &measurement).await {
                Ok(v) => v,
                Err(e) => {
-                  println!("Error from node querying 3:\n{e:?}");
+                  println!("Error from node querying 3:\n{e}");
                   tokio::time::sleep(std::time::Duration::from_secs(5)).await;
                   continue;
                }
             };
 
-            match metric_sender.send(metric).await {
+            match measurement.metric_sender.send(metric).await {
                Ok(_) => (),
                Err(e) => {
                   println!("Error from node querying 4:\n{e:?}");
@@ -194,31 +394,138 @@ impl QueryTask
 
 
    pub fn pause(&self) {
-      match self.state_updater.send(State::Paused) {
-         Err(e) => println!("Error from node querying 5:\n{e:?}"),
-         _ => (),
+      if let Err(e) = self.state_updater.send(State::Paused) {
+         println!("Error from node querying 5:\n{e:?}");
       };
    }
 
    pub fn resume(&self) {
-      match self.state_updater.send(State::Running) {
-         Err(e) => println!("Error from node querying 6:\n{e:?}"),
-         _ => (),
+      if let Err(e) = self.state_updater.send(State::Running) {
+         println!("Error from node querying 6:\n{e:?}");
       };
    }
 
    pub async fn kill(self) {
-      match self.state_updater.send(State::Killed) {
-         Err(e) => println!("Error from node querying 7:\n{e:?}"),
-         _ => (),
+      if let Err(e) = self.state_updater.send(State::Killed) {
+         println!("Error from node querying 7:\n{e:?}");
       };
 
-      match self.handle.await {
-         Err(e) => println!("Error from node querying 8:\n{e:?}"),
-         _ => (),
+      if let Err(e) = self.handle.await {
+         println!("Error from node querying 8:\n{e:?}");
       };
    }
 
 
 }
 
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   /// Two selected pods with a sidecar, their pause containers and pod cgroups, and a pod
+   /// that is not selected.
+   const SCRAPE: &str = r#"# TYPE container_memory_working_set_bytes gauge
+container_memory_working_set_bytes{namespace="default",pod="web-1",container="app"} 100 1000
+container_memory_working_set_bytes{namespace="default",pod="web-1",container="sidecar"} 50 1000
+container_memory_working_set_bytes{namespace="default",pod="web-1",container="POD"} 10 1000
+container_memory_working_set_bytes{namespace="default",pod="web-1",container=""} 160 1000
+container_memory_working_set_bytes{namespace="default",pod="web-2",container="app"} 200 1000
+container_memory_working_set_bytes{namespace="default",pod="api-1",container="app"} 1000 1000
+# TYPE container_memory_rss gauge
+container_memory_rss{namespace="default",pod="web-1",container="app"} 80 1000
+container_memory_rss{namespace="default",pod="web-1",container=""} 80 1000
+container_memory_rss{namespace="default",pod="web-2",container="app"} 150 1000
+# TYPE container_network_receive_bytes_total counter
+container_network_receive_bytes_total{namespace="default",pod="web-1",container="app",interface="eth0"} 1000 1000
+container_network_receive_bytes_total{namespace="default",pod="web-1",container="sidecar",interface="eth0"} 1000 1000
+container_network_receive_bytes_total{namespace="default",pod="web-1",container="POD",interface="eth0"} 1000 1000
+container_network_receive_bytes_total{namespace="default",pod="web-1",container="",interface="eth0"} 1010 1000
+container_network_receive_bytes_total{namespace="default",pod="web-1",container="",interface="eth1"} 50 1000
+container_network_receive_bytes_total{namespace="default",pod="web-2",container="POD",interface="eth0"} 300 1000
+container_network_receive_bytes_total{namespace="default",pod="api-1",container="POD",interface="eth0"} 9999 1000
+# TYPE container_fs_reads_bytes_total counter
+container_fs_reads_bytes_total{namespace="default",pod="web-1",container="app",device="/dev/vda"} 10 1000
+container_fs_reads_bytes_total{namespace="default",pod="web-1",container="sidecar",device="/dev/vda"} 5 1000
+container_fs_reads_bytes_total{namespace="default",pod="web-1",container="",device="/dev/vda"} 15 1000
+container_fs_reads_bytes_total{namespace="default",pod="web-2",container="app",device="/dev/vda"} 20 1000
+"#;
+
+   fn selection(containers: &[&str]) -> Selection
+   {
+      Selection {
+         namespace: "default".into(),
+         pods: ["web-1".into(), "web-2".into()].into(),
+         containers: containers.iter().map(|&container| container.into()).collect(),
+      }
+   }
+
+   #[test]
+   fn memory_sums_the_selected_containers()
+   {
+      let scrape = Scrape::parse(SCRAPE).unwrap();
+
+      // the pause containers and pod cgroups are not counted, cache is not reported at all
+      let memory = Memory::from_scrape(&scrape, &selection(&[]));
+      assert_eq!(
+         memory,
+         Memory {
+            working_set: 350.0,
+            rss: 230.0,
+            cache: 0.0,
+         }
+      );
+
+      let memory = Memory::from_scrape(&scrape, &selection(&["app"]));
+      assert_eq!(memory.working_set, 300.0);
+   }
+
+   /// The pod, interface or container, and value of each counter, in that order.
+   fn values(counters: &Counters, label: &str) -> Vec<(String, String, f64)>
+   {
+      let mut values: Vec<_> = counters
+         .iter()
+         .map(|(labels, &value)| (labels["pod"].to_string(), labels[label].to_string(), value))
+         .collect();
+      values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+      values
+   }
+
+   #[test]
+   fn network_counters_are_taken_once_per_interface_of_a_pod()
+   {
+      let scrape = Scrape::parse(SCRAPE).unwrap();
+
+      // every container, the pause container and the pod cgroup report the pod's network;
+      // the interface only the pod cgroup or pause container reports is still counted
+      let io = IoCounters::from_scrape(&scrape, &selection(&["app"]));
+      assert_eq!(
+         values(&io.network_rx, "interface"),
+         [
+            ("web-1".into(), "eth0".into(), 1010.0),
+            ("web-1".into(), "eth1".into(), 50.0),
+            ("web-2".into(), "eth0".into(), 300.0),
+         ]
+      );
+      assert_eq!(io.network_tx, Counters::new());
+   }
+
+   #[test]
+   fn fs_counters_are_kept_per_container()
+   {
+      let scrape = Scrape::parse(SCRAPE).unwrap();
+
+      let io = IoCounters::from_scrape(&scrape, &selection(&[]));
+      assert_eq!(
+         values(&io.fs_read, "container"),
+         [
+            ("web-1".into(), "app".into(), 10.0),
+            ("web-1".into(), "sidecar".into(), 5.0),
+            ("web-2".into(), "app".into(), 20.0),
+         ]
+      );
+
+      let io = IoCounters::from_scrape(&scrape, &selection(&["sidecar"]));
+      assert_eq!(values(&io.fs_read, "container"), [("web-1".into(), "sidecar".into(), 5.0)]);
+   }
+}
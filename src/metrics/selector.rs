@@ -0,0 +1,952 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use prom_text_format_parser::{Metric, Sample, Scrape, Type, ValueType};
+use regex::Regex;
+
+/// The label a sample's metric name is matched as, as in Prometheus.
+const NAME_LABEL: &str = "__name__";
+
+/// How a matcher compares a label's value; a label a sample does not have is matched as
+/// the empty string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchOp
+{
+   Equal,
+   NotEqual,
+   /// Matches when the regex matches the whole value.
+   Regex,
+   NotRegex,
+}
+
+impl std::fmt::Display for MatchOp
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Equal => write!(f, "="),
+         Self::NotEqual => write!(f, "!="),
+         Self::Regex => write!(f, "=~"),
+         Self::NotRegex => write!(f, "!~"),
+      }
+   }
+}
+
+/// A label matcher of a selector, built with its regex compiled so it always has one to
+/// match with.
+#[derive(Debug, Clone)]
+pub struct Matcher
+{
+   label: Box<str>,
+   value: Box<str>,
+   pattern: Pattern,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern
+{
+   Equal,
+   NotEqual,
+   Regex(Regex),
+   NotRegex(Regex),
+}
+
+impl Matcher
+{
+   pub fn new(label: &str, op: MatchOp, value: &str) -> Result<Self, regex::Error>
+   {
+      // checked on its own first, so errors point into the regex as it was written
+      let regex = || -> Result<Regex, regex::Error> {
+         Regex::new(value)?;
+         Regex::new(&format!("^(?:{value})$"))
+      };
+
+      let pattern = match op {
+         MatchOp::Equal => Pattern::Equal,
+         MatchOp::NotEqual => Pattern::NotEqual,
+         MatchOp::Regex => Pattern::Regex(regex()?),
+         MatchOp::NotRegex => Pattern::NotRegex(regex()?),
+      };
+
+      Ok(Self {
+         label: label.into(),
+         value: value.into(),
+         pattern,
+      })
+   }
+
+   pub fn label(&self) -> &str
+   {
+      &self.label
+   }
+
+   pub fn op(&self) -> MatchOp
+   {
+      match self.pattern {
+         Pattern::Equal => MatchOp::Equal,
+         Pattern::NotEqual => MatchOp::NotEqual,
+         Pattern::Regex(_) => MatchOp::Regex,
+         Pattern::NotRegex(_) => MatchOp::NotRegex,
+      }
+   }
+
+   /// The value as written, which for regex ops is the regex.
+   pub fn value(&self) -> &str
+   {
+      &self.value
+   }
+
+   pub fn matches(&self, value: &str) -> bool
+   {
+      match &self.pattern {
+         Pattern::Equal => value == self.value.as_ref(),
+         Pattern::NotEqual => value != self.value.as_ref(),
+         Pattern::Regex(regex) => regex.is_match(value),
+         Pattern::NotRegex(regex) => !regex.is_match(value),
+      }
+   }
+}
+
+impl std::fmt::Display for Matcher
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write!(f, "{}{}{:?}", self.label, self.op(), self.value)
+   }
+}
+
+/// Whether a metric only goes up, so its rate is measured, or is a value of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind
+{
+   Counter,
+   Gauge,
+}
+
+impl MetricKind
+{
+   /// The kind of a sample as the scrape declares it. The `_sum`, `_count` and `_bucket`
+   /// samples of summaries and histograms are counters, and untyped metrics are counters
+   /// when their name ends in `_total`, as the naming conventions have it.
+   fn of(metric: &Metric, sample: &Sample) -> Self
+   {
+      match (metric.kind, sample.value.value_type) {
+         (Type::Counter, _) => Self::Counter,
+         (Type::Summary | Type::Histogram, ValueType::Sum | ValueType::Count) => Self::Counter,
+         (Type::Histogram, ValueType::Sample) => Self::Counter,
+         (Type::Untyped, _) if metric.name.ends_with("_total") => Self::Counter,
+         _ => Self::Gauge,
+      }
+   }
+}
+
+/// Selects samples of a scrape by metric name and labels, like a PromQL instant selector:
+/// `container_memory_rss{namespace="default", container!~"POD|"}`.
+///
+/// The metric name is matched as the `__name__` label, so it may also be given as a matcher.
+/// Samples of a summary or histogram are named with their `_sum`, `_count` or `_bucket`
+/// suffix.
+#[derive(Debug, Clone)]
+pub struct Selector
+{
+   pub matchers: Vec<Matcher>,
+}
+
+impl Selector
+{
+   /// Every sample of the metric `name`.
+   pub fn name(name: &str) -> Self
+   {
+      let matcher = Matcher::new(NAME_LABEL, MatchOp::Equal, name).expect("equality matchers have no regex");
+      Self { matchers: vec![matcher] }
+   }
+
+   /// The samples of the scrape that match, with the name they were matched by.
+   pub fn select<'a>(&'a self, scrape: &'a Scrape) -> impl Iterator<Item = (Cow<'a, str>, &'a Sample)> + 'a
+   {
+      scrape
+         .metrics
+         .iter()
+         .flat_map(|metric| metric.samples.iter().map(move |sample| (sample_name(metric, sample), sample)))
+         .filter(|(name, sample)| self.matches(name, sample))
+   }
+
+   /// Counter when every sample the selector selects is one, otherwise gauge; `None` when it
+   /// selects nothing.
+   pub fn kind(&self, scrape: &Scrape) -> Option<MetricKind>
+   {
+      scrape
+         .metrics
+         .iter()
+         .flat_map(|metric| metric.samples.iter().map(move |sample| (metric, sample)))
+         .filter(|(metric, sample)| self.matches(&sample_name(metric, sample), sample))
+         .map(|(metric, sample)| MetricKind::of(metric, sample))
+         .reduce(|kind, other| match (kind, other) {
+            (MetricKind::Counter, MetricKind::Counter) => MetricKind::Counter,
+            _ => MetricKind::Gauge,
+         })
+   }
+
+   pub fn matches(&self, name: &str, sample: &Sample) -> bool
+   {
+      self.matchers.iter().all(|matcher| {
+         let value = match matcher.label() {
+            NAME_LABEL => name,
+            label => label_value(sample, label).unwrap_or_default(),
+         };
+         matcher.matches(value)
+      })
+   }
+}
+
+impl std::fmt::Display for Selector
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      let name = self
+         .matchers
+         .iter()
+         .position(|matcher| matcher.label() == NAME_LABEL && matcher.op() == MatchOp::Equal);
+
+      if let Some(index) = name {
+         write!(f, "{}", self.matchers[index].value())?;
+      };
+
+      let matchers: Vec<String> = self
+         .matchers
+         .iter()
+         .enumerate()
+         .filter(|(index, _)| Some(*index) != name)
+         .map(|(_, matcher)| matcher.to_string())
+         .collect();
+
+      if !matchers.is_empty() || name.is_none() {
+         write!(f, "{{{}}}", matchers.join(", "))?;
+      };
+
+      Ok(())
+   }
+}
+
+fn sample_name<'a>(metric: &'a Metric, sample: &Sample) -> Cow<'a, str>
+{
+   match (sample.value.value_type, metric.kind) {
+      (ValueType::Sum, _) => format!("{}_sum", metric.name).into(),
+      (ValueType::Count, _) => format!("{}_count", metric.name).into(),
+      (ValueType::Sample, Type::Histogram) => format!("{}_bucket", metric.name).into(),
+      (ValueType::Sample, _) => metric.name.as_str().into(),
+   }
+}
+
+fn label_value<'a>(sample: &'a Sample, label: &str) -> Option<&'a str>
+{
+   sample
+      .labels
+      .iter()
+      .find(|x| x.key == label)
+      .map(|x| x.value.as_str())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation
+{
+   Sum,
+   Avg,
+   Max,
+}
+
+impl std::fmt::Display for Aggregation
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Sum => write!(f, "sum"),
+         Self::Avg => write!(f, "avg"),
+         Self::Max => write!(f, "max"),
+      }
+   }
+}
+
+/// An aggregation of the selected samples into one series per distinct value of the `by`
+/// labels, or a single series without any.
+#[derive(Debug, Clone)]
+pub struct Aggregate
+{
+   pub op: Aggregation,
+   pub by: Vec<Box<str>>,
+}
+
+/// A selector, optionally aggregated: `sum by (pod) (container_cpu_usage_seconds_total)`.
+///
+/// Parsed with `FromStr`, which validates label names and regexes up front.
+#[derive(Debug, Clone)]
+pub struct Query
+{
+   pub selector: Selector,
+   pub aggregate: Option<Aggregate>,
+}
+
+pub type Labels = BTreeMap<Box<str>, Box<str>>;
+
+/// One value of a query's result.
+///
+/// Without an aggregation every selected sample is a series of its own, labelled with all
+/// its labels and `__name__`; aggregated series only carry the `by` labels they have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series
+{
+   pub labels: Labels,
+   pub value: f64,
+   /// The latest timestamp of the samples it was computed from.
+   pub timestamp: Option<i64>,
+}
+
+impl Query
+{
+   /// The sum of everything `selector` selects.
+   pub fn sum(selector: Selector) -> Self
+   {
+      Self {
+         selector,
+         aggregate: Some(Aggregate {
+            op: Aggregation::Sum,
+            by: vec![],
+         }),
+      }
+   }
+
+   pub fn evaluate(&self, scrape: &Scrape) -> Vec<Series>
+   {
+      self.evaluate_where(scrape, |_| true)
+   }
+
+   /// Evaluates the query over the selected samples `filter` also accepts, e.g. to narrow
+   /// it down to the containers of a workload.
+   pub fn evaluate_where(&self, scrape: &Scrape, filter: impl Fn(&Sample) -> bool) -> Vec<Series>
+   {
+      let series = self.select_where(scrape, filter);
+
+      match &self.aggregate {
+         None => series,
+         Some(aggregate) => aggregate.apply(series),
+      }
+   }
+
+   /// The samples the selector selects and `filter` also accepts, each a series of its
+   /// own, without aggregating them.
+   pub fn select_where(&self, scrape: &Scrape, filter: impl Fn(&Sample) -> bool) -> Vec<Series>
+   {
+      self
+         .selector
+         .select(scrape)
+         .filter(|(_, sample)| filter(sample))
+         .map(|(name, sample)| {
+            let mut labels: Labels = sample
+               .labels
+               .iter()
+               .map(|label| (label.key.as_str().into(), label.value.as_str().into()))
+               .collect();
+            labels.insert(NAME_LABEL.into(), name.into());
+
+            Series {
+               labels,
+               value: sample.value.value.as_f64(),
+               timestamp: sample.value.timestamp,
+            }
+         })
+         .collect()
+   }
+}
+
+impl Aggregate
+{
+   /// Aggregates `series` into one series per distinct value of the `by` labels.
+   pub fn apply(&self, series: Vec<Series>) -> Vec<Series>
+   {
+      let mut groups: BTreeMap<Labels, (Vec<f64>, Option<i64>)> = BTreeMap::new();
+      for series in series {
+         let labels = self
+            .by
+            .iter()
+            .filter_map(|label| Some((label.clone(), series.labels.get(label)?.clone())))
+            .collect();
+
+         let (values, timestamp) = groups.entry(labels).or_default();
+         values.push(series.value);
+         *timestamp = (*timestamp).max(series.timestamp);
+      }
+
+      groups
+         .into_iter()
+         .map(|(labels, (values, timestamp))| {
+            let value = match self.op {
+               Aggregation::Sum => values.iter().sum(),
+               Aggregation::Avg => values.iter().sum::<f64>() / values.len() as f64,
+               Aggregation::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            };
+
+            Series {
+               labels,
+               value,
+               timestamp,
+            }
+         })
+         .collect()
+   }
+}
+
+impl std::fmt::Display for Query
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match &self.aggregate {
+         None => write!(f, "{}", self.selector),
+         Some(Aggregate { op, by }) if by.is_empty() => write!(f, "{op}({})", self.selector),
+         Some(Aggregate { op, by }) => write!(f, "{op} by ({}) ({})", by.join(", "), self.selector),
+      }
+   }
+}
+
+/// Why a query could not be parsed, with the byte offset in it where the problem is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError
+{
+   pub query: Box<str>,
+   pub position: usize,
+   pub message: Box<str>,
+}
+
+impl std::fmt::Display for SelectorError
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      write!(f, "invalid query \"{}\" at column {}: {}", self.query, self.position + 1, self.message)
+   }
+}
+
+impl std::error::Error for SelectorError {}
+
+impl FromStr for Query
+{
+   type Err = SelectorError;
+
+   fn from_str(query: &str) -> Result<Self, Self::Err>
+   {
+      let tokens = tokenize(query).map_err(|(position, message)| SelectorError {
+         query: query.into(),
+         position,
+         message,
+      })?;
+
+      let mut parser = Parser { tokens, next: 0, end: query.len() };
+      parser.query().map_err(|(position, message)| SelectorError {
+         query: query.into(),
+         position,
+         message,
+      })
+   }
+}
+
+impl FromStr for Selector
+{
+   type Err = SelectorError;
+
+   /// A selector on its own, without an aggregation.
+   fn from_str(selector: &str) -> Result<Self, Self::Err>
+   {
+      let query: Query = selector.parse()?;
+      match query.aggregate {
+         None => Ok(query.selector),
+         Some(_) => Err(SelectorError {
+            query: selector.into(),
+            position: 0,
+            message: "expected a selector, not an aggregation".into(),
+         }),
+      }
+   }
+}
+
+/// A parse failure at a byte offset of the query.
+type ParseError = (usize, Box<str>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token
+{
+   Identifier(Box<str>),
+   String(Box<str>),
+   Op(MatchOp),
+   LeftBrace,
+   RightBrace,
+   LeftParen,
+   RightParen,
+   Comma,
+}
+
+impl std::fmt::Display for Token
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match self {
+         Self::Identifier(name) => write!(f, "`{name}`"),
+         Self::String(value) => write!(f, "{value:?}"),
+         Self::Op(op) => write!(f, "`{op}`"),
+         Self::LeftBrace => write!(f, "`{{`"),
+         Self::RightBrace => write!(f, "`}}`"),
+         Self::LeftParen => write!(f, "`(`"),
+         Self::RightParen => write!(f, "`)`"),
+         Self::Comma => write!(f, "`,`"),
+      }
+   }
+}
+
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, ParseError>
+{
+   let mut tokens = vec![];
+   let mut chars = query.char_indices().peekable();
+
+   while let Some((position, c)) = chars.next() {
+      let token = match c {
+         c if c.is_whitespace() => continue,
+         '{' => Token::LeftBrace,
+         '}' => Token::RightBrace,
+         '(' => Token::LeftParen,
+         ')' => Token::RightParen,
+         ',' => Token::Comma,
+         '=' | '!' => {
+            let op = match (c, chars.peek().map(|&(_, c)| c)) {
+               ('=', Some('~')) => MatchOp::Regex,
+               ('!', Some('~')) => MatchOp::NotRegex,
+               ('!', Some('=')) => MatchOp::NotEqual,
+               ('=', _) => MatchOp::Equal,
+               _ => return Err((position, "expected `!=` or `!~`".into())),
+            };
+            if op != MatchOp::Equal {
+               chars.next();
+            };
+            Token::Op(op)
+         },
+         '"' | '\'' => {
+            let mut value = String::new();
+            loop {
+               match chars.next() {
+                  None => return Err((position, "unterminated string".into())),
+                  Some((_, x)) if x == c => break,
+                  Some((escape, '\\')) => match chars.next() {
+                     Some((_, 'n')) => value.push('\n'),
+                     Some((_, 't')) => value.push('\t'),
+                     Some((_, x @ ('\\' | '"' | '\''))) => value.push(x),
+                     _ => return Err((escape, "invalid escape in string".into())),
+                  },
+                  Some((_, x)) => value.push(x),
+               }
+            }
+            Token::String(value.into())
+         },
+         c if c.is_ascii_alphabetic() || c == '_' || c == ':' => {
+            let mut name = String::from(c);
+            while let Some(&(_, x)) = chars.peek()
+               && (x.is_ascii_alphanumeric() || x == '_' || x == ':')
+            {
+               name.push(x);
+               chars.next();
+            }
+            Token::Identifier(name.into())
+         },
+         c => return Err((position, format!("unexpected character {c:?}").into())),
+      };
+
+      tokens.push((position, token));
+   }
+
+   Ok(tokens)
+}
+
+struct Parser
+{
+   tokens: Vec<(usize, Token)>,
+   next: usize,
+   /// Where the query ends, for errors about missing input.
+   end: usize,
+}
+
+impl Parser
+{
+   fn peek(&self) -> Option<&Token>
+   {
+      self.tokens.get(self.next).map(|(_, token)| token)
+   }
+
+   fn position(&self) -> usize
+   {
+      self.tokens.get(self.next).map_or(self.end, |&(position, _)| position)
+   }
+
+   fn bump(&mut self) -> Option<Token>
+   {
+      let token = self.tokens.get(self.next).map(|(_, token)| token.clone());
+      self.next += 1;
+      token
+   }
+
+   fn unexpected(&self, expected: &str) -> ParseError
+   {
+      let message = match self.peek() {
+         Some(token) => format!("expected {expected}, found {token}"),
+         None => format!("expected {expected}, found the end of the query"),
+      };
+      (self.position(), message.into())
+   }
+
+   fn expect(&mut self, token: Token) -> Result<(), ParseError>
+   {
+      match self.peek() {
+         Some(x) if *x == token => {
+            self.next += 1;
+            Ok(())
+         },
+         _ => Err(self.unexpected(&token.to_string())),
+      }
+   }
+
+   fn query(&mut self) -> Result<Query, ParseError>
+   {
+      let aggregation = match (self.peek(), self.tokens.get(self.next + 1).map(|(_, token)| token)) {
+         (Some(Token::Identifier(name)), Some(Token::LeftParen)) => Some(name.clone()),
+         (Some(Token::Identifier(name)), Some(Token::Identifier(by))) if by.as_ref() == "by" => Some(name.clone()),
+         _ => None,
+      };
+
+      let query = match aggregation {
+         None => Query {
+            selector: self.selector()?,
+            aggregate: None,
+         },
+         Some(name) => {
+            let op = match name.as_ref() {
+               "sum" => Aggregation::Sum,
+               "avg" => Aggregation::Avg,
+               "max" => Aggregation::Max,
+               _ => return Err((self.position(), format!("unknown aggregation `{name}`, expected sum, avg or max").into())),
+            };
+            self.next += 1;
+
+            let before = self.by()?;
+            self.expect(Token::LeftParen)?;
+            let selector = self.selector()?;
+            self.expect(Token::RightParen)?;
+
+            let position = self.position();
+            let by = match (before, self.by()?) {
+               (Some(_), Some(_)) => return Err((position, "`by` given twice".into())),
+               (before, after) => before.or(after).unwrap_or_default(),
+            };
+
+            Query {
+               selector,
+               aggregate: Some(Aggregate { op, by }),
+            }
+         },
+      };
+
+      match self.peek() {
+         None => Ok(query),
+         Some(_) => Err(self.unexpected("the end of the query")),
+      }
+   }
+
+   /// `by (label, ...)`, if it comes next.
+   fn by(&mut self) -> Result<Option<Vec<Box<str>>>, ParseError>
+   {
+      match self.peek() {
+         Some(Token::Identifier(by)) if by.as_ref() == "by" => self.next += 1,
+         _ => return Ok(None),
+      };
+
+      self.expect(Token::LeftParen)?;
+      let mut labels = vec![];
+
+      loop {
+         match self.peek() {
+            Some(Token::RightParen) => break,
+            Some(Token::Identifier(_)) => labels.push(self.label()?),
+            _ => return Err(self.unexpected("a label name or `)`")),
+         };
+
+         match self.peek() {
+            Some(Token::Comma) => self.next += 1,
+            Some(Token::RightParen) => break,
+            _ => return Err(self.unexpected("`,` or `)`")),
+         };
+      }
+
+      self.next += 1;
+      Ok(Some(labels))
+   }
+
+   fn label(&mut self) -> Result<Box<str>, ParseError>
+   {
+      let position = self.position();
+      match self.bump() {
+         Some(Token::Identifier(label)) if label.contains(':') => {
+            Err((position, format!("invalid label name `{label}`").into()))
+         },
+         Some(Token::Identifier(label)) => Ok(label),
+         _ => {
+            self.next -= 1;
+            Err(self.unexpected("a label name"))
+         },
+      }
+   }
+
+   /// `name`, `name{matchers}` or `{matchers}`, with at least one matcher that does not
+   /// match the empty string, so it cannot select every sample.
+   fn selector(&mut self) -> Result<Selector, ParseError>
+   {
+      let start = self.position();
+      let mut matchers = vec![];
+
+      if let Some(Token::Identifier(name)) = self.peek() {
+         let name = name.clone();
+         self.next += 1;
+         matchers.extend(Selector::name(&name).matchers);
+      };
+
+      if self.peek() == Some(&Token::LeftBrace) {
+         self.next += 1;
+
+         loop {
+            if self.peek() == Some(&Token::RightBrace) {
+               break;
+            };
+
+            matchers.push(self.matcher()?);
+
+            match self.peek() {
+               Some(Token::Comma) => self.next += 1,
+               Some(Token::RightBrace) => break,
+               _ => return Err(self.unexpected("`,` or `}`")),
+            };
+         }
+
+         self.next += 1;
+      } else if matchers.is_empty() {
+         return Err(self.unexpected("a metric name or `{`"));
+      };
+
+      if matchers.iter().all(|matcher| matcher.matches("")) {
+         return Err((start, "the selector needs a metric name or a matcher that does not match empty values".into()));
+      };
+
+      Ok(Selector { matchers })
+   }
+
+   fn matcher(&mut self) -> Result<Matcher, ParseError>
+   {
+      let label = self.label()?;
+
+      let op = match self.bump() {
+         Some(Token::Op(op)) => op,
+         _ => {
+            self.next -= 1;
+            return Err(self.unexpected("`=`, `!=`, `=~` or `!~`"));
+         },
+      };
+
+      let position = self.position();
+      let value = match self.bump() {
+         Some(Token::String(value)) => value,
+         _ => {
+            self.next -= 1;
+            return Err(self.unexpected("a quoted label value"));
+         },
+      };
+
+      Matcher::new(&label, op, &value).map_err(|e| (position, format!("invalid regex: {e}").into()))
+   }
+}
+
+#[cfg(test)]
+mod tests
+{
+   use super::*;
+
+   const SCRAPE: &str = r#"# TYPE container_memory_rss gauge
+container_memory_rss{namespace="default",pod="web-1",container="app"} 10 1000
+container_memory_rss{namespace="default",pod="web-1",container="sidecar"} 4 1000
+container_memory_rss{namespace="default",pod="web-2",container="app"} 20 2000
+container_memory_rss{namespace="kube-system",pod="dns",container="app"} 1 1000
+# TYPE up gauge
+up 1
+"#;
+
+   fn evaluate(query: &str) -> Vec<Series>
+   {
+      let scrape = Scrape::parse(SCRAPE).unwrap();
+      query.parse::<Query>().unwrap().evaluate(&scrape)
+   }
+
+   /// The values of a query's series, with the `label` each was grouped by.
+   fn values(query: &str, label: &str) -> Vec<(String, f64)>
+   {
+      evaluate(query)
+         .into_iter()
+         .map(|series| (series.labels.get(label).map(|x| x.to_string()).unwrap_or_default(), series.value))
+         .collect()
+   }
+
+   fn error(query: &str) -> (usize, String)
+   {
+      let error = query.parse::<Query>().unwrap_err();
+      (error.position, error.message.into())
+   }
+
+   fn matcher(op: MatchOp, value: &str) -> Matcher
+   {
+      Matcher::new("container", op, value).unwrap()
+   }
+
+   #[test]
+   fn matcher_ops()
+   {
+      assert!(matcher(MatchOp::Equal, "app").matches("app"));
+      assert!(!matcher(MatchOp::Equal, "app").matches("sidecar"));
+      assert!(matcher(MatchOp::NotEqual, "app").matches("sidecar"));
+      assert!(!matcher(MatchOp::NotEqual, "app").matches("app"));
+      assert!(matcher(MatchOp::Regex, "app|side.*").matches("sidecar"));
+      assert!(!matcher(MatchOp::Regex, "app|side.*").matches("POD"));
+      assert!(matcher(MatchOp::NotRegex, "POD|").matches("app"));
+      assert!(!matcher(MatchOp::NotRegex, "POD|").matches(""));
+   }
+
+   #[test]
+   fn regexes_match_the_whole_value()
+   {
+      assert!(!matcher(MatchOp::Regex, "app").matches("my-app-2"));
+      assert!(!matcher(MatchOp::Regex, "a|b").matches("ab"));
+      assert!(matcher(MatchOp::NotRegex, "app").matches("my-app-2"));
+   }
+
+   #[test]
+   fn selects_by_name_and_labels()
+   {
+      let selected = values(r#"container_memory_rss{namespace="default", container!="sidecar"}"#, "pod");
+      assert_eq!(selected, [("web-1".into(), 10.0), ("web-2".into(), 20.0)]);
+
+      let by_name = evaluate(r#"{__name__="up"}"#);
+      assert_eq!(by_name.len(), 1);
+      assert_eq!(by_name[0].labels[NAME_LABEL].as_ref(), "up");
+   }
+
+   #[test]
+   fn missing_labels_match_as_empty()
+   {
+      assert_eq!(values(r#"up{pod=""}"#, "pod"), [(String::new(), 1.0)]);
+      assert!(evaluate(r#"up{pod!=""}"#).is_empty());
+   }
+
+   #[test]
+   fn aggregations()
+   {
+      assert_eq!(values(r#"sum(container_memory_rss{namespace="default"})"#, ""), [(String::new(), 34.0)]);
+      assert_eq!(values(r#"avg(container_memory_rss{namespace="default"})"#, ""), [(String::new(), 34.0 / 3.0)]);
+      assert_eq!(values(r#"max(container_memory_rss{namespace="default"})"#, ""), [(String::new(), 20.0)]);
+   }
+
+   #[test]
+   fn aggregated_series_keep_the_latest_timestamp()
+   {
+      let series = evaluate("sum(container_memory_rss)");
+      assert_eq!(series.len(), 1);
+      assert_eq!(series[0].timestamp, Some(2000));
+   }
+
+   #[test]
+   fn by_before_or_after_the_selector()
+   {
+      let expected = [("web-1".to_string(), 14.0), ("web-2".into(), 20.0)];
+
+      assert_eq!(values(r#"sum by (pod) (container_memory_rss{namespace="default"})"#, "pod"), expected);
+      assert_eq!(values(r#"sum(container_memory_rss{namespace="default"}) by (pod)"#, "pod"), expected);
+   }
+
+   #[test]
+   fn by_groups_on_every_label()
+   {
+      let series = evaluate("max by (namespace, container) (container_memory_rss)");
+      let groups: Vec<_> = series
+         .iter()
+         .map(|series| (series.labels["namespace"].to_string(), series.labels["container"].to_string(), series.value))
+         .collect();
+
+      assert_eq!(
+         groups,
+         [
+            ("default".into(), "app".into(), 20.0),
+            ("kube-system".into(), "app".into(), 1.0),
+            ("default".into(), "sidecar".into(), 4.0),
+         ]
+      );
+   }
+
+   #[test]
+   fn rejects_selectors_matching_empty_values()
+   {
+      let (position, message) = error(r#"{container=~".*"}"#);
+      assert_eq!(position, 0);
+      assert_eq!(message, "the selector needs a metric name or a matcher that does not match empty values");
+      assert_eq!(error(r#"sum({pod=""})"#).0, 4);
+      assert!(r#"{container=~".+"}"#.parse::<Query>().is_ok());
+   }
+
+   #[test]
+   fn error_positions()
+   {
+      assert_eq!(error("up{pod=web}"), (7, "expected a quoted label value, found `web`".into()));
+      assert_eq!(error(r#"up{pod="web"#), (7, "unterminated string".into()));
+      assert_eq!(error(r#"up{pod=~"(web"}"#).0, 8);
+      assert_eq!(error("up{pod=\"a\";}"), (10, "unexpected character ';'".into()));
+      assert_eq!(error(r#"up{pod="a" "b"}"#), (11, r#"expected `,` or `}`, found "b""#.into()));
+      assert_eq!(error("min(up)"), (0, "unknown aggregation `min`, expected sum, avg or max".into()));
+      assert_eq!(error("sum by (pod) (up) by (pod)"), (18, "`by` given twice".into()));
+      assert_eq!(error("sum(up"), (6, "expected `)`, found the end of the query".into()));
+      assert_eq!(error("up{a:b=\"x\"}"), (3, "invalid label name `a:b`".into()));
+   }
+
+   #[test]
+   fn selector_alone_rejects_aggregations()
+   {
+      assert!("sum(up)".parse::<Selector>().is_err());
+      assert_eq!("up".parse::<Selector>().unwrap().to_string(), "up");
+   }
+
+   #[test]
+   fn displays_as_parsed()
+   {
+      let query: Query = r#"sum by (pod) (container_memory_rss{container!~"POD|"})"#.parse().unwrap();
+      assert_eq!(query.to_string(), r#"sum by (pod) (container_memory_rss{container!~"POD|"})"#);
+   }
+
+   #[test]
+   fn counters_and_gauges_as_declared()
+   {
+      let scrape = Scrape::parse(
+         r#"# TYPE container_cpu_usage_seconds_total counter
+container_cpu_usage_seconds_total{container="app"} 5 1000
+# TYPE container_memory_rss gauge
+container_memory_rss{container="app"} 10 1000
+requests_total 3
+temperature 20
+"#,
+      )
+      .unwrap();
+      let kind = |selector: &str| selector.parse::<Selector>().unwrap().kind(&scrape);
+
+      assert_eq!(kind("container_cpu_usage_seconds_total"), Some(MetricKind::Counter));
+      assert_eq!(kind("container_memory_rss"), Some(MetricKind::Gauge));
+      assert_eq!(kind("requests_total"), Some(MetricKind::Counter));
+      assert_eq!(kind("temperature"), Some(MetricKind::Gauge));
+      assert_eq!(kind(r#"{container="app"}"#), Some(MetricKind::Gauge));
+      assert_eq!(kind("missing"), None);
+   }
+}
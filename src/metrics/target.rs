@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use k8s_openapi::{
+   Metadata,
+   api::{apps::v1::ReplicaSet, core::v1::Pod as JsonPod},
+   apimachinery::pkg::apis::meta::v1::ObjectMeta,
+};
+use prom_text_format_parser::Sample;
+
+use crate::client::{Api, KillHandle, KubeClient, LabelSelector, ListParams, ResourceWatcher, Store, WatcherConfig, Writer, reflect};
+
+/// How long each watch behind `TargetPods` stays open before it is resumed.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Label names of the pod, its namespace and the container in cadvisor's samples; the
+/// standalone DaemonSet copies the runtime's labels, the kubelet's cadvisor renames them.
+pub(super) const NAMESPACE_LABELS: [&str; 2] = ["container_label_io_kubernetes_pod_namespace", "namespace"];
+pub(super) const POD_LABELS: [&str; 2] = ["container_label_io_kubernetes_pod_name", "pod"];
+const CONTAINER_LABELS: [&str; 2] = ["container_label_io_kubernetes_container_name", "container"];
+
+/// Which pods of the namespace make up the workload.
+#[derive(Debug, Clone)]
+pub enum PodSelector
+{
+   Labels(LabelSelector),
+   /// Pods controlled by this owner; a `Deployment` owns its pods through its ReplicaSets.
+   Owner
+   {
+      kind: Box<str>,
+      name: Box<str>,
+   },
+}
+
+/// The workload whose CPU is measured: the selected pods of a namespace, narrowed down to
+/// some of their containers.
+#[derive(Debug, Clone)]
+pub struct Target
+{
+   pub namespace: Box<str>,
+   pub pods: PodSelector,
+   /// Every container of the pods when empty.
+   pub containers: Vec<Box<str>>,
+}
+
+impl Target
+{
+   pub fn labels(namespace: &str, selector: LabelSelector) -> Self
+   {
+      Self {
+         namespace: namespace.into(),
+         pods: PodSelector::Labels(selector),
+         containers: vec![],
+      }
+   }
+
+   pub fn owner(namespace: &str, kind: &str, name: &str) -> Self
+   {
+      Self {
+         namespace: namespace.into(),
+         pods: PodSelector::Owner {
+            kind: kind.into(),
+            name: name.into(),
+         },
+         containers: vec![],
+      }
+   }
+
+   pub fn containers(mut self, containers: &[&str]) -> Self
+   {
+      self.containers = containers.iter().map(|&container| container.into()).collect();
+      self
+   }
+}
+
+impl std::fmt::Display for Target
+{
+   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+   {
+      match &self.pods {
+         PodSelector::Labels(selector) => write!(f, "pods {{{selector}}} in {}", self.namespace)?,
+         PodSelector::Owner { kind, name } => write!(f, "{kind} {}/{name}", self.namespace)?,
+      };
+
+      if !self.containers.is_empty() {
+         write!(f, ", containers {}", self.containers.join(","))?;
+      };
+
+      Ok(())
+   }
+}
+
+/// The pods currently matching a `Target`, kept current by watching them.
+///
+/// Clones share the watches, which run until `stop` is called.
+#[derive(Debug, Clone)]
+pub struct TargetPods
+{
+   target: Target,
+   pods: Store<JsonPod>,
+   replica_sets: Option<Store<ReplicaSet>>,
+   kill_handles: Vec<KillHandle>,
+}
+
+impl TargetPods
+{
+   pub fn watch(client: &KubeClient, target: Target) -> Self
+   {
+      let mut kill_handles = vec![];
+
+      let params = match &target.pods {
+         PodSelector::Labels(selector) => ListParams::default().labels(selector.clone()),
+         PodSelector::Owner { .. } => ListParams::default(),
+      };
+      let pods = ResourceWatcher::new(client.namespaced::<JsonPod>(&target.namespace), params, WatcherConfig::new(WATCH_TIMEOUT));
+      kill_handles.push(pods.kill_handle());
+      let pods = reflect(Writer::pods(), pods);
+
+      let replica_sets = match &target.pods {
+         PodSelector::Owner { kind, .. } if kind.as_ref() == "Deployment" => {
+            let api: Api<ReplicaSet> = client.namespaced(&target.namespace);
+            let replica_sets = ResourceWatcher::new(api, ListParams::default(), WatcherConfig::new(WATCH_TIMEOUT));
+            kill_handles.push(replica_sets.kill_handle());
+            Some(reflect(Writer::new(), replica_sets))
+         },
+         _ => None,
+      };
+
+      Self {
+         target,
+         pods,
+         replica_sets,
+         kill_handles,
+      }
+   }
+
+   pub fn target(&self) -> &Target
+   {
+      &self.target
+   }
+
+   /// Waits for the first list of every watch, so the first selection is complete.
+   pub async fn wait_ready(&self) -> bool
+   {
+      let replica_sets = match &self.replica_sets {
+         Some(replica_sets) => replica_sets.wait_ready().await,
+         None => true,
+      };
+
+      replica_sets && self.pods.wait_ready().await
+   }
+
+   /// The names of the matching pods right now, with the containers to count in them.
+   pub fn selection(&self) -> Selection
+   {
+      let pods = match &self.target.pods {
+         PodSelector::Labels(selector) => self.pods.select(selector),
+         PodSelector::Owner { kind, name } => {
+            let owners: Vec<(Box<str>, Box<str>)> = match &self.replica_sets {
+               Some(replica_sets) => replica_sets
+                  .list()
+                  .iter()
+                  .filter(|replica_set| is_controlled_by(replica_set.metadata(), kind, name))
+                  .filter_map(|replica_set| replica_set.metadata().name.as_deref())
+                  .map(|replica_set| ("ReplicaSet".into(), replica_set.into()))
+                  .collect(),
+               None => vec![(kind.clone(), name.clone())],
+            };
+
+            self.pods
+               .list()
+               .into_iter()
+               .filter(|pod| owners.iter().any(|(kind, name)| is_controlled_by(pod.metadata(), kind, name)))
+               .collect()
+         },
+      };
+
+      let pods = pods
+         .iter()
+         .filter_map(|pod| pod.metadata.name.as_deref())
+         .map(Into::into)
+         .collect();
+
+      Selection {
+         namespace: self.target.namespace.clone(),
+         pods,
+         containers: self.target.containers.clone(),
+      }
+   }
+
+   pub fn stop(&self)
+   {
+      self.kill_handles.iter().for_each(KillHandle::kill);
+   }
+}
+
+fn is_controlled_by(metadata: &ObjectMeta, kind: &str, name: &str) -> bool
+{
+   metadata
+      .owner_references
+      .iter()
+      .flatten()
+      .any(|owner| owner.controller == Some(true) && owner.kind == kind && owner.name == name)
+}
+
+/// A snapshot of `TargetPods` to match cadvisor samples against.
+#[derive(Debug, Clone, Default)]
+pub struct Selection
+{
+   pub namespace: Box<str>,
+   pub pods: HashSet<Box<str>>,
+   pub containers: Vec<Box<str>>,
+}
+
+impl Selection
+{
+   /// Whether the sample belongs to one of the selected pods, whichever of its containers,
+   /// including the pod's own cgroup and pause container.
+   pub fn matches_pod(&self, sample: &Sample) -> bool
+   {
+      label(sample, &NAMESPACE_LABELS) == Some(self.namespace.as_ref())
+         && label(sample, &POD_LABELS).is_some_and(|pod| self.pods.contains(pod))
+   }
+
+   /// Whether the sample belongs to one of the selected containers.
+   ///
+   /// Samples for the pod's own cgroup and its pause container carry no container name, or
+   /// `POD`, and are never selected, so a pod's containers are not counted twice.
+   pub fn matches(&self, sample: &Sample) -> bool
+   {
+      if !self.matches_pod(sample) {
+         return false;
+      };
+
+      match label(sample, &CONTAINER_LABELS) {
+         None | Some("" | "POD") => false,
+         Some(container) => self.containers.is_empty() || self.containers.iter().any(|x| x.as_ref() == container),
+      }
+   }
+}
+
+/// The value of the first of `keys` the sample has.
+fn label<'a>(sample: &'a Sample, keys: &[&str]) -> Option<&'a str>
+{
+   keys.iter().find_map(|key| {
+      sample
+         .labels
+         .iter()
+         .find(|label| label.key == *key)
+         .map(|label| label.value.as_str())
+   })
+}
+
+#[cfg(test)]
+mod tests
+{
+   use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+   use prom_text_format_parser::Scrape;
+
+   use crate::client::{Health, ResourceEvent, WatcherEvent};
+
+   use super::*;
+
+   /// A sample with these labels, written as in the text format.
+   fn sample(labels: &str) -> Sample
+   {
+      let scrape = Scrape::parse(&format!("container_cpu_usage_seconds_total{{{labels}}} 1\n")).unwrap();
+      scrape.metrics[0].samples[0].clone()
+   }
+
+   fn selection(pods: &[&str], containers: &[&str]) -> Selection
+   {
+      Selection {
+         namespace: "default".into(),
+         pods: pods.iter().map(|&pod| pod.into()).collect(),
+         containers: containers.iter().map(|&container| container.into()).collect(),
+      }
+   }
+
+   #[test]
+   fn kubelet_and_standalone_label_names()
+   {
+      let selection = selection(&["web-1"], &[]);
+
+      let kubelet = sample(r#"namespace="default",pod="web-1",container="app""#);
+      assert!(selection.matches(&kubelet));
+
+      let standalone = sample(
+         r#"container_label_io_kubernetes_pod_namespace="default",container_label_io_kubernetes_pod_name="web-1",container_label_io_kubernetes_container_name="app""#,
+      );
+      assert!(selection.matches(&standalone));
+
+      let other_pod = sample(r#"namespace="default",pod="web-2",container="app""#);
+      assert!(!selection.matches_pod(&other_pod));
+
+      let other_namespace = sample(r#"namespace="kube-system",pod="web-1",container="app""#);
+      assert!(!selection.matches_pod(&other_namespace));
+   }
+
+   #[test]
+   fn runtime_labels_take_precedence_whatever_their_order()
+   {
+      let selection = selection(&["web-1"], &[]);
+
+      // a sample relabelled with the scraping pod's own names before the runtime's labels
+      let sample = sample(
+         r#"namespace="monitoring",pod="cadvisor-x",container="cadvisor",container_label_io_kubernetes_pod_namespace="default",container_label_io_kubernetes_pod_name="web-1",container_label_io_kubernetes_container_name="app""#,
+      );
+
+      assert!(selection.matches(&sample));
+   }
+
+   #[test]
+   fn pod_cgroups_and_pause_containers_match_only_the_pod()
+   {
+      let selection = selection(&["web-1"], &[]);
+
+      for labels in [
+         r#"namespace="default",pod="web-1""#,
+         r#"namespace="default",pod="web-1",container="""#,
+         r#"namespace="default",pod="web-1",container="POD""#,
+      ] {
+         let sample = sample(labels);
+         assert!(selection.matches_pod(&sample), "{labels}");
+         assert!(!selection.matches(&sample), "{labels}");
+      }
+   }
+
+   #[test]
+   fn containers_narrow_down_the_selection()
+   {
+      let selection = selection(&["web-1"], &["app"]);
+
+      assert!(selection.matches(&sample(r#"namespace="default",pod="web-1",container="app""#)));
+      assert!(!selection.matches(&sample(r#"namespace="default",pod="web-1",container="sidecar""#)));
+   }
+
+   fn metadata(name: &str, owner: Option<(&str, &str, bool)>) -> ObjectMeta
+   {
+      ObjectMeta {
+         uid: Some(name.into()),
+         name: Some(name.into()),
+         namespace: Some("default".into()),
+         owner_references: owner.map(|(kind, name, controller)| {
+            vec![OwnerReference {
+               kind: kind.into(),
+               name: name.into(),
+               uid: name.into(),
+               controller: Some(controller),
+               ..Default::default()
+            }]
+         }),
+         ..Default::default()
+      }
+   }
+
+   fn store<K: Metadata<Ty = ObjectMeta> + Clone>(objects: Vec<K>) -> Store<K>
+   {
+      let mut writer = Writer::new();
+      for object in objects {
+         writer.apply(&WatcherEvent::Event(ResourceEvent::Added(object)));
+      }
+      writer.apply(&WatcherEvent::Health(Health::Connected));
+      writer.store()
+   }
+
+   fn pod(name: &str, owner: Option<(&str, &str, bool)>) -> JsonPod
+   {
+      JsonPod {
+         metadata: metadata(name, owner),
+         ..Default::default()
+      }
+   }
+
+   fn target_pods(target: Target, pods: Vec<JsonPod>, replica_sets: Option<Vec<ReplicaSet>>) -> TargetPods
+   {
+      TargetPods {
+         target,
+         pods: store(pods),
+         replica_sets: replica_sets.map(store),
+         kill_handles: vec![],
+      }
+   }
+
+   fn selected(target_pods: &TargetPods) -> Vec<String>
+   {
+      let mut pods: Vec<String> = target_pods.selection().pods.into_iter().map(Into::into).collect();
+      pods.sort();
+      pods
+   }
+
+   #[test]
+   fn deployments_select_the_pods_of_their_replica_sets()
+   {
+      let replica_set = |name: &str, deployment: &str| ReplicaSet {
+         metadata: metadata(name, Some(("Deployment", deployment, true))),
+         ..Default::default()
+      };
+
+      let target_pods = target_pods(
+         Target::owner("default", "Deployment", "web"),
+         vec![
+            pod("web-a-1", Some(("ReplicaSet", "web-a", true))),
+            pod("web-a-2", Some(("ReplicaSet", "web-a", true))),
+            // mid-rollout, the old ReplicaSet's pods are still part of the deployment
+            pod("web-b-1", Some(("ReplicaSet", "web-b", true))),
+            pod("api-a-1", Some(("ReplicaSet", "api-a", true))),
+            // only the controller owns a pod
+            pod("adopted", Some(("ReplicaSet", "web-a", false))),
+            pod("standalone", None),
+         ],
+         Some(vec![replica_set("web-a", "web"), replica_set("web-b", "web"), replica_set("api-a", "api")]),
+      );
+
+      assert_eq!(selected(&target_pods), ["web-a-1", "web-a-2", "web-b-1"]);
+   }
+
+   #[test]
+   fn other_owners_select_the_pods_they_control()
+   {
+      let target_pods = target_pods(
+         Target::owner("default", "DaemonSet", "agent"),
+         vec![
+            pod("agent-1", Some(("DaemonSet", "agent", true))),
+            pod("agent-2", Some(("DaemonSet", "agent", true))),
+            pod("other-1", Some(("DaemonSet", "other", true))),
+            pod("web-1", Some(("ReplicaSet", "agent", true))),
+         ],
+         None,
+      );
+
+      assert_eq!(selected(&target_pods), ["agent-1", "agent-2"]);
+   }
+
+   #[test]
+   fn label_selectors_select_by_label()
+   {
+      let labelled = |name: &str, app: &str| {
+         let mut pod = pod(name, None);
+         pod.metadata.labels = Some([("app".to_string(), app.to_string())].into());
+         pod
+      };
+
+      let target_pods = target_pods(
+         Target::labels("default", LabelSelector::new().equal("app", "web")),
+         vec![labelled("web-1", "web"), labelled("api-1", "api")],
+         None,
+      );
+
+      assert_eq!(selected(&target_pods), ["web-1"]);
+   }
+}